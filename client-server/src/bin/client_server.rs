@@ -1,33 +1,97 @@
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex};
 
-use blake2::Blake2b;
 use bytes::{Buf, BufMut};
-use jsonrpc_core::futures::{self, future, TryFutureExt};
-use jsonrpc_core::{BoxFuture, Error, IoHandler, Result};
+use futures::executor::ThreadPool;
+use futures::future::{self, AbortHandle, FutureExt};
+use futures::stream::StreamExt;
+use jsonrpc_core::{serde_json, BoxFuture, Error, ErrorCode, Result};
 use jsonrpc_core_client::transports::local;
+use jsonrpc_core_client::RpcError;
 use jsonrpc_derive::rpc;
-use magma_core::replication::request::dto::{Error as DtoConversionError, Request as DtoRequest};
-use magma_core::replication::request::{Ordering, PathLength, Request};
-use magma_core::replication::response::dto::Response as DtoResponse;
-use magma_core::replication::response::{Response, UnvalidatedResponse};
+use jsonrpc_pubsub::{typed, PubSubHandler, Session, SubscriptionId};
+use magma_core::replication::checkpoint::dto::CheckpointResponse as DtoCheckpointResponse;
+use magma_core::replication::checkpoint::{
+    Checkpoint, CheckpointSigner, CheckpointVerifier, UnvalidatedCheckpointResponse,
+};
+use magma_core::replication::reconciliation::dto::{Error as DtoRangeError, Range as DtoRange};
+use magma_core::replication::reconciliation::{
+    Error as ReconciliationError, HeadSet, Range, Reconciliation,
+};
+use magma_core::replication::request::dto::{
+    CheckpointRequest as DtoCheckpointRequest, Error as DtoConversionError,
+    EventAtRequest as DtoEventAtRequest, Request as DtoRequest,
+};
+use magma_core::replication::request::{
+    Budget, CheckpointRequest, EventAtRequest, Limits, Ordering, PathLength, Request,
+};
+use magma_core::replication::response::dto::{
+    BatchResponse as DtoBatchResponse, Compression, Response as DtoResponse,
+};
+use magma_core::replication::response::UnvalidatedResponse;
+use magma_core::replication::subscription::dto::{
+    HeadUpdate as DtoHeadUpdate, Subscription as DtoSubscription,
+    SubscriptionError as DtoSubscriptionError,
+};
+use magma_core::replication::subscription::{ResponseOptions, Subscription, UnvalidatedHeadUpdate};
+use magma_core::store::dto::Head as DtoHead;
+use magma_core::store::{Head, Store};
 use magma_core::*;
-use snafu::{ensure, Snafu};
+use magma_service::{Connection, Error as ServiceError, MagmaService, StoreService};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
 
 /// Rpc trait
 #[rpc]
 pub trait Rpc {
-    /// Adds two numbers and returns a result
+    type Metadata;
+
+    /// Returns the path between two events of the log
     #[rpc(name = "request")]
-    fn request(&self, request: DtoRequest) -> Result<DtoResponse>;
-}
+    fn request(&self, request: DtoRequest) -> BoxFuture<Result<DtoResponse>>;
+
+    /// Returns the paths of several requests at once, sending the events they share once
+    #[rpc(name = "request_batch")]
+    fn request_batch(&self, requests: Vec<DtoRequest>) -> BoxFuture<Result<DtoBatchResponse>>;
+
+    /// Returns the event at a sequence number, with the path to it from a later event
+    #[rpc(name = "request_event_at")]
+    fn request_event_at(&self, request: DtoEventAtRequest) -> BoxFuture<Result<DtoResponse>>;
+
+    /// Returns the latest checkpoint below an event, with the path from it up to the event
+    #[rpc(name = "request_checkpoint")]
+    fn request_checkpoint(
+        &self,
+        request: DtoCheckpointRequest,
+    ) -> BoxFuture<Result<DtoCheckpointResponse>>;
+
+    /// Returns the head and statistics of every log the server has
+    #[rpc(name = "list_heads")]
+    fn list_heads(&self) -> BoxFuture<Result<Vec<DtoHead>>>;
+
+    /// Answers a message of set reconciliation over the heads of every log the server has
+    #[rpc(name = "reconcile")]
+    fn reconcile(&self, message: Vec<DtoRange>) -> BoxFuture<Result<Vec<DtoRange>>>;
 
-type MyEvent = Event<Blake2b>;
-type MyRequest = Request<Blake2b>;
+    /// Sends the new head of a log whenever its author appends, starting with the next append
+    #[pubsub(subscription = "head", subscribe, name = "subscribe_head")]
+    fn subscribe_head(
+        &self,
+        meta: Self::Metadata,
+        subscriber: typed::Subscriber<DtoHeadUpdate>,
+        subscription: DtoSubscription,
+    );
 
-#[derive(Debug)]
+    /// Stops sending the heads of a log
+    #[pubsub(subscription = "head", unsubscribe, name = "unsubscribe_head")]
+    fn unsubscribe_head(&self, meta: Option<Self::Metadata>, id: SubscriptionId) -> Result<bool>;
+}
+
+#[derive(Debug, Clone)]
 struct U32Semigroup(u32);
-type MyResponse = Response<Blake2b, U32Semigroup>;
-type MyUnvalidatedResponse = UnvalidatedResponse<Blake2b, U32Semigroup>;
 
 #[derive(Snafu, Debug)]
 enum CanonicalEncodingU32Error {
@@ -44,7 +108,7 @@ impl CanonicalEncoding for U32Semigroup {
     type Error = CanonicalEncodingU32Error;
 
     fn encode(&self, mut buffer: &mut [u8]) -> core::result::Result<usize, Self::Error> {
-        ensure!(buffer.len() > 4, BufferTooSmall);
+        ensure!(buffer.len() >= 4, BufferTooSmall);
         buffer.put_u32(self.0);
         Ok(4)
     }
@@ -53,7 +117,7 @@ impl CanonicalEncoding for U32Semigroup {
     where
         Self: Sized,
     {
-        ensure!(buffer.len() > 4, BufferTooSmall);
+        ensure!(buffer.len() >= 4, BufferTooSmall);
         let result = buffer.get_u32();
         Ok((U32Semigroup(result), buffer))
     }
@@ -63,53 +127,697 @@ impl CanonicalEncoding for U32Semigroup {
     }
 }
 
-struct RpcImpl;
+/// Stands in for the author's signing key, signing with a keyed hash.
+struct DemoKey;
+
+impl CheckpointSigner for DemoKey {
+    fn sign(&self, statement: &[u8]) -> Vec<u8> {
+        blake2::Blake2b::digest(&[b"demo key", statement].concat()).to_vec()
+    }
+}
+
+impl CheckpointVerifier for DemoKey {
+    fn verify(&self, statement: &[u8], signature: &[u8]) -> bool {
+        self.sign(statement) == signature
+    }
+}
+
+/// JSON-RPC error codes, in the range for server errors. They're part of the protocol, so they
+/// never change once assigned.
+mod error_code {
+    // The responses without events, with the response as the error data.
+    pub const UNKNOWN_EVENT: i64 = -32000;
+    pub const OLD_NOT_ANCESTOR_OF_NEW: i64 = -32001;
+    pub const PAYLOADS_UNAVAILABLE: i64 = -32002;
+    pub const RATE_LIMITED: i64 = -32003;
+    pub const TOO_LARGE: i64 = -32004;
+
+    // The [super::ServerError]s, with the error as the error data.
+    pub const CANCELLED: i64 = -32005;
+    pub const INVALID_REQUEST: i64 = -32010;
+    pub const INVALID_SUBSCRIPTION: i64 = -32011;
+    pub const INVALID_RANGE: i64 = -32012;
+    pub const UNKNOWN_LOG: i64 = -32020;
+    pub const INVALID_MESSAGE: i64 = -32021;
+}
+
+/// Why the server failed a call, other than with a response without events.
+///
+/// The server sends it as the data of a JSON-RPC error with its [code](ServerError::code), and
+/// clients decode it back with [ServerError::from_rpc_error].
+#[derive(Snafu, Debug, Deserialize, Serialize)]
+enum ServerError {
+    #[snafu(display("The connection closed before the request was answered"))]
+    Cancelled,
+    #[snafu(display("The request is invalid: {}", source))]
+    InvalidRequest { source: DtoConversionError },
+    #[snafu(display("The subscription is invalid: {}", source))]
+    InvalidSubscription { source: DtoSubscriptionError },
+    #[snafu(display("A reconciliation range is invalid: {}", source))]
+    InvalidRange { source: DtoRangeError },
+    #[snafu(display("The server has no log with this root"))]
+    UnknownLog,
+    #[snafu(display("The reconciliation message is invalid: {}", source))]
+    InvalidMessage { source: ReconciliationError },
+}
+
+impl ServerError {
+    fn code(&self) -> i64 {
+        match self {
+            ServerError::Cancelled => error_code::CANCELLED,
+            ServerError::InvalidRequest { .. } => error_code::INVALID_REQUEST,
+            ServerError::InvalidSubscription { .. } => error_code::INVALID_SUBSCRIPTION,
+            ServerError::InvalidRange { .. } => error_code::INVALID_RANGE,
+            ServerError::UnknownLog => error_code::UNKNOWN_LOG,
+            ServerError::InvalidMessage { .. } => error_code::INVALID_MESSAGE,
+        }
+    }
+
+    /// The error the server sent, if it's one of ours and its data matches its code.
+    fn from_rpc_error(error: &Error) -> Option<Self> {
+        let server_error: ServerError = serde_json::from_value(error.data.clone()?).ok()?;
+        if error.code == ErrorCode::ServerError(server_error.code()) {
+            Some(server_error)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<ServiceError> for ServerError {
+    fn from(error: ServiceError) -> Self {
+        match error {
+            ServiceError::UnknownLog => ServerError::UnknownLog,
+            ServiceError::InvalidMessage { source } => ServerError::InvalidMessage { source },
+            ServiceError::Cancelled => ServerError::Cancelled,
+        }
+    }
+}
+
+impl From<ServerError> for Error {
+    fn from(error: ServerError) -> Self {
+        Error {
+            code: ErrorCode::ServerError(error.code()),
+            message: error.to_string(),
+            data: serde_json::to_value(&error).ok(),
+        }
+    }
+}
+
+/// Why a call failed, as the client tells from the error it got.
+#[derive(Snafu, Debug)]
+enum ClientError {
+    #[snafu(display("{}", source))]
+    Server { source: ServerError },
+    #[snafu(display("The call failed on the way: {}", error))]
+    Transport { error: RpcError },
+    #[snafu(display("The server failed with an error the client doesn't know: {}", error))]
+    UnknownCode { error: Error },
+}
+
+impl ClientError {
+    /// Decodes the error of a call, see [ServerError::from_rpc_error].
+    fn from_rpc_error(error: RpcError) -> Self {
+        match error {
+            RpcError::JsonRpcError(error) => match ServerError::from_rpc_error(&error) {
+                Some(source) => ClientError::Server { source },
+                None => ClientError::UnknownCode { error },
+            },
+            error => ClientError::Transport { error },
+        }
+    }
+}
+
+fn service_error(error: ServiceError) -> Error {
+    ServerError::from(error).into()
+}
+
+/// Sends the responses without events as JSON-RPC errors, with the response as the error data.
+fn into_rpc_result(response: DtoResponse) -> Result<DtoResponse> {
+    let (code, message) = match response {
+        DtoResponse::UnknownEvent => (error_code::UNKNOWN_EVENT, "Unknown event"),
+        DtoResponse::OldNotAncestorOfNew { .. } => (
+            error_code::OLD_NOT_ANCESTOR_OF_NEW,
+            "Old is not an ancestor of new",
+        ),
+        DtoResponse::PayloadsUnavailable => {
+            (error_code::PAYLOADS_UNAVAILABLE, "Payloads are unavailable")
+        }
+        DtoResponse::RateLimited => (error_code::RATE_LIMITED, "Rate limited"),
+        DtoResponse::TooLarge => (error_code::TOO_LARGE, "Response too large"),
+        data => return Ok(data),
+    };
+    Err(Error {
+        code: ErrorCode::ServerError(code),
+        message: message.into(),
+        data: serde_json::to_value(response).ok(),
+    })
+}
+
+/// Turns a JSON-RPC error from the server back into the response it stands for, or else into
+/// the [ClientError] it is.
+fn from_rpc_result(
+    result: std::result::Result<DtoResponse, RpcError>,
+) -> std::result::Result<DtoResponse, ClientError> {
+    let error = match result {
+        Ok(response) => return Ok(response),
+        Err(RpcError::JsonRpcError(error)) => error,
+        Err(error) => return Err(ClientError::from_rpc_error(error)),
+    };
+    let known = matches!(
+        error.code.code(),
+        error_code::UNKNOWN_EVENT
+            | error_code::OLD_NOT_ANCESTOR_OF_NEW
+            | error_code::PAYLOADS_UNAVAILABLE
+            | error_code::RATE_LIMITED
+            | error_code::TOO_LARGE
+    );
+    match error.data.clone() {
+        // A response that doesn't decode is an error with data the client doesn't know.
+        Some(data) if known => {
+            serde_json::from_value(data).map_err(|_| ClientError::UnknownCode { error })
+        }
+        _ => Err(ClientError::from_rpc_error(RpcError::JsonRpcError(error))),
+    }
+}
+
+/// Serves a [MagmaService] over JSON-RPC, converting between the DTOs and the service's types.
+///
+/// One adapter serves one client, usually over a [Connection] that bounds what the client can make
+/// the service do and cancels its requests when the adapter is dropped.
+struct JsonRpcAdapter<D, T> {
+    service: T,
+    /// Runs the tasks forwarding the updates of subscriptions.
+    spawner: ThreadPool,
+    /// Stops forwarding the updates of a subscription when the client unsubscribes.
+    subscriptions: Arc<Mutex<HashMap<SubscriptionId, AbortHandle>>>,
+    next_subscription_id: AtomicU64,
+    digest: PhantomData<fn() -> D>,
+}
+
+impl<D, T> JsonRpcAdapter<D, T> {
+    fn new(service: T) -> Self {
+        JsonRpcAdapter {
+            service,
+            spawner: ThreadPool::new().expect("Failed to start the subscription threads"),
+            subscriptions: Default::default(),
+            next_subscription_id: Default::default(),
+            digest: PhantomData,
+        }
+    }
+}
 
-impl Rpc for RpcImpl {
-    fn request(&self, request_dto: DtoRequest) -> Result<DtoResponse> {
-        let request: MyRequest = request_dto
-            .try_into()
-            .map_err(|err: DtoConversionError| Error::invalid_params(err.to_string()))?;
+impl<D, T> Rpc for JsonRpcAdapter<D, T>
+where
+    D: Digest + 'static,
+    T: MagmaService<D, U32Semigroup> + Send + Sync + 'static,
+{
+    type Metadata = Arc<Session>;
 
-        // handle request
+    fn request(&self, request_dto: DtoRequest) -> BoxFuture<Result<DtoResponse>> {
+        let compression = Compression::negotiate(&request_dto.accept_compression);
+        let request: Request<D> = match request_dto.try_into().context(InvalidRequest) {
+            Ok(request) => request,
+            Err(err) => return future::err(err.into()).boxed(),
+        };
 
         // The values in the response could be very large so we need to limit copying and
         // allocating when we don't need to. This is less important for the encoded events
         // themselves, they're not that large.
         // Actually, as long as we just move values that's cheap.
+        self.service
+            .request(request)
+            .map(move |response| {
+                let response = response.map_err(service_error)?;
+                into_rpc_result(DtoResponse::with_compression(response, compression))
+            })
+            .boxed()
+    }
+
+    /// Unlike a single request, the responses that aren't data stay in the batch instead of
+    /// becoming error codes, so one of them doesn't fail the others.
+    fn request_batch(&self, requests: Vec<DtoRequest>) -> BoxFuture<Result<DtoBatchResponse>> {
+        // The pairs are shared, so they're compressed only in a way every request accepts.
+        let compression = requests
+            .first()
+            .and_then(|first| Compression::negotiate(&first.accept_compression))
+            .filter(|compression| {
+                let accepted =
+                    |request: &DtoRequest| request.accept_compression.contains(compression);
+                requests.iter().all(accepted)
+            });
+        let requests: Vec<Request<D>> = match requests
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<std::result::Result<_, _>>()
+            .context(InvalidRequest)
+        {
+            Ok(requests) => requests,
+            Err(err) => return future::err(err.into()).boxed(),
+        };
+        self.service
+            .request_batch(requests)
+            .map(move |responses| {
+                let responses = responses.map_err(service_error)?;
+                Ok(DtoBatchResponse::from_responses(responses, compression))
+            })
+            .boxed()
+    }
 
-        let response: MyResponse = Response::UnknownEvent;
-        Ok(response.into())
+    fn request_event_at(&self, request_dto: DtoEventAtRequest) -> BoxFuture<Result<DtoResponse>> {
+        let compression = Compression::negotiate(&request_dto.accept_compression);
+        let request: EventAtRequest<D> = match request_dto.try_into().context(InvalidRequest) {
+            Ok(request) => request,
+            Err(err) => return future::err(err.into()).boxed(),
+        };
+        self.service
+            .request_event_at(request)
+            .map(move |response| {
+                let response = response.map_err(service_error)?;
+                into_rpc_result(DtoResponse::with_compression(response, compression))
+            })
+            .boxed()
+    }
+
+    fn request_checkpoint(
+        &self,
+        request_dto: DtoCheckpointRequest,
+    ) -> BoxFuture<Result<DtoCheckpointResponse>> {
+        let compression = Compression::negotiate(&request_dto.accept_compression);
+        let request: CheckpointRequest<D> = match request_dto.try_into().context(InvalidRequest) {
+            Ok(request) => request,
+            Err(err) => return future::err(err.into()).boxed(),
+        };
+        self.service
+            .request_checkpoint(request)
+            .map(move |response| {
+                let response = response.map_err(service_error)?;
+                Ok(DtoCheckpointResponse::with_compression(
+                    response,
+                    compression,
+                ))
+            })
+            .boxed()
+    }
+
+    fn list_heads(&self) -> BoxFuture<Result<Vec<DtoHead>>> {
+        self.service
+            .list_heads()
+            .map(|heads| {
+                Ok(heads
+                    .map_err(service_error)?
+                    .iter()
+                    .map(Into::into)
+                    .collect())
+            })
+            .boxed()
+    }
+
+    fn reconcile(&self, message_dto: Vec<DtoRange>) -> BoxFuture<Result<Vec<DtoRange>>> {
+        let message = match message_dto
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<std::result::Result<Vec<Range<D>>, DtoRangeError>>()
+            .context(InvalidRange)
+        {
+            Ok(message) => message,
+            Err(err) => return future::err(err.into()).boxed(),
+        };
+        self.service
+            .reconcile(message)
+            .map(|answer| {
+                answer
+                    .map(|answer| answer.into_iter().map(Into::into).collect())
+                    .map_err(service_error)
+            })
+            .boxed()
+    }
+
+    fn subscribe_head(
+        &self,
+        _meta: Self::Metadata,
+        subscriber: typed::Subscriber<DtoHeadUpdate>,
+        subscription_dto: DtoSubscription,
+    ) {
+        let subscription: Subscription<D> =
+            match subscription_dto.try_into().context(InvalidSubscription) {
+                Ok(subscription) => subscription,
+                Err(err) => {
+                    let _ = subscriber.reject(err.into());
+                    return;
+                }
+            };
+
+        let id = SubscriptionId::Number(
+            self.next_subscription_id
+                .fetch_add(1, atomic::Ordering::SeqCst),
+        );
+        // Subscribing before returning, so the subscription gets every head after this request.
+        let subscribed = self.service.subscribe_head(subscription);
+        let forwarded_id = id.clone();
+        let (forward, abort) = future::abortable(async move {
+            let updates = match subscribed.await {
+                Ok(updates) => updates,
+                Err(err) => {
+                    let _ = subscriber.reject(service_error(err));
+                    return;
+                }
+            };
+            if let Ok(sink) = subscriber.assign_id(forwarded_id) {
+                let _ = updates
+                    .map(|update| Ok(Ok(update.into())))
+                    .forward(sink)
+                    .await;
+            }
+        });
+        self.subscriptions.lock().unwrap().insert(id.clone(), abort);
+        self.spawner.spawn_ok(forward.map(|_| ()));
+    }
+
+    fn unsubscribe_head(&self, _meta: Option<Self::Metadata>, id: SubscriptionId) -> Result<bool> {
+        let subscription = self.subscriptions.lock().unwrap().remove(&id);
+        Ok(subscription.map(|abort| abort.abort()).is_some())
     }
 }
 
 fn main() {
-    let mut io = IoHandler::new();
-    io.extend_with(RpcImpl.to_delegate());
+    match std::env::args().nth(1).as_deref().unwrap_or("blake2b") {
+        "blake2b" => run::<blake2::Blake2b>(),
+        "blake2s" => run::<blake2::Blake2s>(),
+        "blake3" => run::<blake3::Hasher>(),
+        "sha256" => run::<sha2::Sha256>(),
+        other => eprintln!(
+            "Unknown digest {}, expected one of blake2b, blake2s, blake3 or sha256",
+            other
+        ),
+    }
+}
+
+fn run<D: Digest + Clone + Send + Sync + std::fmt::Debug + 'static>() {
+    let mut store = Store::<D, U32Semigroup>::with_accumulated_digests();
+    let first = store.create(U32Semigroup(1)).unwrap().digest.clone();
+    for value in 2..=15 {
+        store.append(&first, U32Semigroup(value)).unwrap();
+    }
+    // A peer that has the first log up to here, and none of the logs added later.
+    let peer = store.clone();
+    for value in 16..=20 {
+        store.append(&first, U32Semigroup(value)).unwrap();
+    }
+    // Another author's log in the same store.
+    let other = store.create(U32Semigroup(100)).unwrap().digest.clone();
+    for value in 101..=105 {
+        store.append(&other, U32Semigroup(value)).unwrap();
+    }
 
-    let (client, server) = local::connect::<gen_client::Client, _, _>(io);
+    let log = store.log(&first).unwrap();
+    let new = log.head().unwrap().digest.clone();
+    let tenth = log.get(10).unwrap().digest.clone();
+    let other_head = store.log(&other).unwrap().head().unwrap().digest.clone();
+    let checkpoint = Checkpoint::sign(
+        log.get(10).unwrap().digest.clone(),
+        log.combined_payload(0, 10).unwrap(),
+        &DemoKey,
+    );
+    store.add_checkpoint(checkpoint).unwrap();
 
-    let new = Blake2b::digest(b"123");
+    // However much a client asks for, a response reads at most this much of the store.
+    let budget = Budget {
+        max_events_walked: Some(1000),
+        max_bytes_read: Some(1 << 20),
+    };
+    let service = StoreService::with_budget(store, budget);
+    let author = service.clone();
+    // The only client, whose requests are cancelled when it disconnects and the adapter is dropped.
+    let connection = Connection::new(service, 4);
+    let mut io = PubSubHandler::default();
+    io.extend_with(JsonRpcAdapter::<D, _>::new(connection).to_delegate());
+
+    let (client, server) = local::connect_with_pubsub::<gen_client::Client, _>(io);
 
-    let request = MyRequest {
+    let request = Request::<D> {
         ordering: Ordering::Ascending,
         path_length: PathLength::ShortestPath,
         old: None,
         include_values: true,
+        new: new.clone(),
+        limits: Limits::default(),
+    };
+    // The whole first log, the part of it above the checkpoint and the other log, in one round
+    // trip that sends the events of the first log once.
+    let batch_requests = vec![
+        Request::<D> {
+            path_length: PathLength::LongestPath,
+            ..request.clone()
+        },
+        Request::<D> {
+            path_length: PathLength::LongestPath,
+            old: Some(tenth),
+            ..request.clone()
+        },
+        Request::<D> {
+            new: other_head,
+            ..request.clone()
+        },
+    ];
+    // Asks for a path up from the head, which the server answers with an error code.
+    let backwards_request = Request::<D> {
+        old: Some(new.clone()),
+        new: first,
+        ..request.clone()
+    };
+    let checkpoint_request = CheckpointRequest::<D> {
+        new: new.clone(),
+        path_length: PathLength::ShortestPath,
+    };
+    let event_at_request = EventAtRequest::<D> {
         new,
+        sequence_number: NonZeroU64::new(7).unwrap(),
+        include_value: true,
     };
 
-    let fut = client
-        .request(DtoRequest::from_request(&request))
-        .map_ok(|res| {
-            // TODO: hide this stuff in internals
-            println!("{:?}", res);
-            let res: MyUnvalidatedResponse = res.try_into().unwrap();
-            println!("{:?}", res);
-            let err = res.try_into_valid_response(request);
-            println!("{:?}", err);
+    // A request for a digest of the wrong length, which the server rejects with a typed error.
+    let malformed = client
+        .request(DtoRequest {
+            new: vec![0; 3],
+            ..DtoRequest::from_request(&request)
+        })
+        .map(|res| match from_rpc_result(res) {
+            Err(ClientError::Server { source }) => println!("{:?}", source),
+            Err(error) => println!("request failed: {}", error),
+            Ok(response) => println!("Expected an error, got {:?}", response),
+        });
+    let path = client
+        .request(DtoRequest {
+            accept_compression: vec![Compression::Lz4],
+            ..DtoRequest::from_request(&request)
+        })
+        .map(|res| match from_rpc_result(res) {
+            Ok(res) => {
+                // TODO: hide this stuff in internals
+                let res: UnvalidatedResponse<D, U32Semigroup> = res.try_into().unwrap();
+                println!("{:?}", res.try_into_valid_response(request));
+            }
+            Err(error) => println!("request failed: {}", error),
+        });
+    let backwards = client
+        .request(DtoRequest::from_request(&backwards_request))
+        .map(|res| match from_rpc_result(res) {
+            Ok(res) => {
+                let res: UnvalidatedResponse<D, U32Semigroup> = res.try_into().unwrap();
+                println!("{:?}", res.try_into_valid_response(backwards_request));
+            }
+            Err(error) => println!("request failed: {}", error),
+        });
+    let batch = client
+        .request_batch(
+            batch_requests
+                .iter()
+                .map(|request| DtoRequest {
+                    accept_compression: vec![Compression::Lz4],
+                    ..DtoRequest::from_request(request)
+                })
+                .collect(),
+        )
+        .map(|res| {
+            let res = match res {
+                Ok(res) => res,
+                Err(error) => {
+                    return println!("batch failed: {}", ClientError::from_rpc_error(error));
+                }
+            };
+            let sent = res.pairs.len();
+            let responses = res.into_responses::<D, U32Semigroup>().unwrap();
+            let mut on_paths = 0;
+            let mut valid = 0;
+            for (response, request) in responses.into_iter().zip(batch_requests) {
+                if let Ok(response) = response.try_into_valid_response(request) {
+                    on_paths += response.events.len();
+                    valid += 1;
+                }
+            }
+            println!(
+                "batch: {} valid, {} pairs sent for {}",
+                valid, sent, on_paths
+            );
+        });
+    let event_at = client
+        .request_event_at(DtoEventAtRequest::from_request(&event_at_request))
+        .map(|res| match from_rpc_result(res) {
+            Ok(res) => {
+                let res: UnvalidatedResponse<D, U32Semigroup> = res.try_into().unwrap();
+                println!("{:?}", res.try_into_valid_event_at(event_at_request));
+            }
+            Err(error) => println!("request failed: {}", error),
         });
-    futures::executor::block_on(async move { futures::join!(fut, server) })
-        .0
+    let checkpoint = client
+        .request_checkpoint(DtoCheckpointRequest::from_request(&checkpoint_request))
+        .map(|res| match res.map_err(ClientError::from_rpc_error) {
+            Ok(res) => {
+                let res: UnvalidatedCheckpointResponse<D, U32Semigroup> = res.try_into().unwrap();
+                let valid = res.try_into_valid_response(checkpoint_request, &DemoKey);
+                println!("{:?}", valid.map(|valid| valid.value.clone()));
+            }
+            Err(error) => println!("checkpoint failed: {}", error),
+        });
+    let heads = client
+        .list_heads()
+        .map(|res| match res.map_err(ClientError::from_rpc_error) {
+            Ok(heads) => {
+                for head in heads {
+                    let head: Head<D> = head.try_into().unwrap();
+                    println!("{:?}", head);
+                }
+            }
+            Err(error) => println!("list_heads failed: {}", error),
+        });
+    // Finds the logs the peer is behind on in as few round trips as the differences allow.
+    let reconcile = {
+        let client = client.clone();
+        async move {
+            let heads: HeadSet<D> = peer.heads().collect();
+            let mut reconciliation = Reconciliation::new(&heads);
+            let mut message = reconciliation.initiate();
+            while !message.is_empty() {
+                let answer = client
+                    .reconcile(message.into_iter().map(Into::into).collect())
+                    .await
+                    .unwrap();
+                let answer = answer
+                    .into_iter()
+                    .map(TryInto::try_into)
+                    .collect::<std::result::Result<Vec<Range<D>>, DtoRangeError>>()
+                    .unwrap();
+                message = reconciliation.respond(&answer).unwrap();
+            }
+            for wanted in reconciliation.wanted() {
+                let known = heads.get(&wanted.root).map(|head| head.sequence_number);
+                println!("peer is behind: {:?} of {}", known, wanted.sequence_number);
+            }
+        }
+    };
+    // Follows the other log, with the path from the previous head in every update.
+    let mut following = Subscription::<D> {
+        root: other.clone(),
+        known: None,
+        responses: Some(ResponseOptions {
+            ordering: Ordering::Ascending,
+            path_length: PathLength::ShortestPath,
+            include_values: true,
+            limits: Limits::default(),
+        }),
+    };
+    let updates = client
+        .subscribe_head(DtoSubscription::from_subscription(&following))
         .unwrap();
+    // The server has handled the subscription by the time it answers a later request.
+    let appends = client.list_heads().map(move |_| {
+        for value in 106..=108 {
+            author.append(&other, U32Semigroup(value)).unwrap();
+        }
+    });
+    let mut value = None;
+    let follow = updates.take(3).for_each(move |update| {
+        let update: UnvalidatedHeadUpdate<D, U32Semigroup> = update.unwrap().try_into().unwrap();
+        let valid = update.try_into_valid_update(&mut following).unwrap();
+        value = Some(valid.response.as_ref().unwrap().fold(value.take()).unwrap());
+        println!("head {}: {:?}", valid.sequence_number, value);
+        future::ready(())
+    });
+    drop(client);
+
+    // The subscriber's sink keeps the session, and so the server, alive, so the demo ends once
+    // the clients are done instead of waiting for the server.
+    let clients = async move {
+        futures::join!(
+            path, backwards, malformed, batch, event_at, checkpoint, heads, reconcile, appends,
+            follow
+        )
+    };
+    let _ = futures::executor::block_on(future::select(Box::pin(clients), server));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_errors_decode_from_their_rpc_errors() {
+        let errors = vec![
+            ServerError::Cancelled,
+            ServerError::InvalidRequest {
+                source: DtoConversionError::OldWasIncorrectLength,
+            },
+            ServerError::InvalidSubscription {
+                source: DtoSubscriptionError::KnownWasIncorrectLength,
+            },
+            ServerError::InvalidRange {
+                source: DtoRangeError::BoundWasIncorrectLength,
+            },
+            ServerError::UnknownLog,
+            ServerError::InvalidMessage {
+                source: ReconciliationError::InvalidRange,
+            },
+        ];
+
+        for error in errors {
+            let expected = format!("{:?}", error);
+            let code = error.code();
+            let mut rpc_error = Error::from(error);
+            let decoded = ServerError::from_rpc_error(&rpc_error);
+            assert_eq!(format!("{:?}", decoded.unwrap()), expected);
+
+            rpc_error.code = ErrorCode::ServerError(code - 1);
+            assert!(ServerError::from_rpc_error(&rpc_error).is_none());
+        }
+    }
+
+    #[test]
+    fn client_errors_tell_server_errors_from_unknown_codes() {
+        let server_error = Error::from(ServerError::UnknownLog);
+        let res = from_rpc_result(Err(RpcError::JsonRpcError(server_error)));
+        assert!(matches!(
+            res,
+            Err(ClientError::Server {
+                source: ServerError::UnknownLog
+            })
+        ));
+
+        let res = from_rpc_result(Err(RpcError::JsonRpcError(Error::new(
+            ErrorCode::ServerError(-32099),
+        ))));
+        assert!(matches!(res, Err(ClientError::UnknownCode { .. })));
+
+        let mut invalid_response = into_rpc_result(DtoResponse::RateLimited).unwrap_err();
+        invalid_response.data = Some(serde_json::Value::Null);
+        let res = from_rpc_result(Err(RpcError::JsonRpcError(invalid_response)));
+        assert!(matches!(res, Err(ClientError::UnknownCode { .. })));
+
+        let res = from_rpc_result(Err(RpcError::Timeout));
+        assert!(matches!(res, Err(ClientError::Transport { .. })));
+
+        let response = into_rpc_result(DtoResponse::RateLimited).unwrap_err();
+        let res = from_rpc_result(Err(RpcError::JsonRpcError(response)));
+        assert!(matches!(res, Ok(DtoResponse::RateLimited)));
+    }
 }
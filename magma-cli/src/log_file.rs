@@ -0,0 +1,168 @@
+//! Logs stored in a single file.
+//!
+//! A log file is the log's events in order, each followed by its payload. Both are prefixed with
+//! their length as a varu64.
+use magma_core::log::{Error as LogError, Log};
+use magma_core::{CanonicalEncoding, Digest, Semigroup};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to read {}: {}", path.display(), source))]
+    ReadLog {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Failed to write {}: {}", path.display(), source))]
+    WriteLog {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Record {} of the log is truncated", record))]
+    TruncatedRecord { record: u64 },
+    #[snafu(display("Event {} is invalid: {}", record, source))]
+    InvalidEvent { record: u64, source: LogError },
+}
+
+/// Appends bytes to each other, encoded as the bytes themselves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bytes(pub Vec<u8>);
+
+#[derive(Snafu, Debug)]
+pub enum BytesError {
+    BufferTooSmall,
+}
+
+impl Semigroup for Bytes {
+    fn combine(&self, other: &Self) -> Self {
+        Bytes([self.0.as_slice(), other.0.as_slice()].concat())
+    }
+}
+
+impl CanonicalEncoding for Bytes {
+    type Error = BytesError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        ensure!(buffer.len() >= self.0.len(), BufferTooSmall);
+        buffer[..self.0.len()].copy_from_slice(&self.0);
+        Ok(self.0.len())
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), Self::Error> {
+        Ok((Bytes(buffer.to_vec()), &[]))
+    }
+
+    fn encoding_length(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// An encoded event and its payload, as stored in a log file.
+#[derive(Debug, PartialEq)]
+pub struct Record<'a> {
+    pub event: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        for bytes in &[self.event, self.payload] {
+            let mut length = [0; 9];
+            let length_size = varu64::encode(bytes.len() as u64, &mut length);
+            out.extend_from_slice(&length[..length_size]);
+            out.extend_from_slice(bytes);
+        }
+    }
+
+    /// Splits the contents of a log file into its records.
+    pub fn decode_all(mut bytes: &'a [u8]) -> Result<Vec<Self>, Error> {
+        let mut records = Vec::new();
+        while !bytes.is_empty() {
+            let record = records.len() as u64 + 1;
+            let (event, rest) = take_prefixed(bytes).context(TruncatedRecord { record })?;
+            let (payload, rest) = take_prefixed(rest).context(TruncatedRecord { record })?;
+            records.push(Record { event, payload });
+            bytes = rest;
+        }
+        Ok(records)
+    }
+}
+
+fn take_prefixed(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (length, rest) = varu64::decode(bytes).ok()?;
+    let length = usize::try_from(length).ok()?;
+    if rest.len() < length {
+        return None;
+    }
+    Some(rest.split_at(length))
+}
+
+pub fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).context(ReadLog { path })
+}
+
+/// Reads the log at `path`, checking every event along the way.
+pub fn load<D: Digest>(path: &Path) -> Result<Log<D, Bytes>, Error> {
+    let bytes = read(path)?;
+    let mut log = Log::new();
+    for (i, record) in Record::decode_all(&bytes)?.into_iter().enumerate() {
+        log.push(record.event, Bytes(record.payload.to_vec()))
+            .context(InvalidEvent {
+                record: i as u64 + 1,
+            })?;
+    }
+    Ok(log)
+}
+
+/// Creates an empty log file, failing if `path` already exists.
+pub fn create(path: &Path) -> Result<(), Error> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .context(WriteLog { path })?;
+    Ok(())
+}
+
+pub fn append(path: &Path, record: &Record) -> Result<(), Error> {
+    let mut bytes = Vec::new();
+    record.encode(&mut bytes);
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(&bytes))
+        .context(WriteLog { path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let records = [
+            Record {
+                event: b"event",
+                payload: b"",
+            },
+            Record {
+                event: &[0; 300],
+                payload: b"payload",
+            },
+        ];
+        let mut bytes = Vec::new();
+        for record in &records {
+            record.encode(&mut bytes);
+        }
+
+        assert_eq!(Record::decode_all(&bytes).unwrap(), records);
+        assert!(matches!(
+            Record::decode_all(&bytes[..bytes.len() - 1]),
+            Err(Error::TruncatedRecord { record: 2 })
+        ));
+    }
+}
@@ -0,0 +1,262 @@
+//! `magma`, a tool for building and inspecting Magma logs stored in files.
+//!
+//! Payloads are arbitrary bytes, combined by appending them to each other.
+use clap::{Parser, Subcommand, ValueEnum};
+use magma_core::graph::{Error as GraphError, Graph};
+use magma_core::replication::path::skip_link_target;
+use magma_core::replication::request::{Limits, Ordering, PathLength, Request};
+use magma_core::{Digest, Event};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::fs;
+use std::path::PathBuf;
+
+mod log_file;
+
+use log_file::{Bytes, Record};
+
+#[derive(Parser, Debug)]
+#[command(name = "magma", about = "Build and inspect Magma logs")]
+struct Cli {
+    /// The digest the log's events are hashed with.
+    #[arg(long, value_enum, default_value_t = DigestName::Blake2b)]
+    digest: DigestName,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DigestName {
+    Blake2b,
+    Blake2s,
+    Blake3,
+    Sha256,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create an empty log.
+    Create { log: PathBuf },
+    /// Append an event whose delta is the contents of a file.
+    Append { log: PathBuf, delta: PathBuf },
+    /// Print the fields of an event.
+    Print { log: PathBuf, sequence_number: u64 },
+    /// Check every event and payload of a log.
+    Verify { log: PathBuf },
+    /// Print the events on the path from `new` down to `old`, or to the root.
+    Path {
+        log: PathBuf,
+        new: u64,
+        old: Option<u64>,
+        /// Print every event between `new` and `old` rather than the shortest path.
+        #[arg(long)]
+        longest: bool,
+    },
+    /// Print the evolution graph of a log as Graphviz DOT.
+    Graph {
+        log: PathBuf,
+        /// Highlight the path from this event.
+        #[arg(long)]
+        new: Option<u64>,
+        /// Highlight the path down to this event rather than to the root.
+        #[arg(long, requires = "new")]
+        old: Option<u64>,
+        /// Highlight every event between `new` and `old` rather than the shortest path.
+        #[arg(long, requires = "new")]
+        longest: bool,
+        /// Print a JSON adjacency list instead.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Snafu, Debug)]
+enum Error {
+    #[snafu(display("{}", source))]
+    LogFile { source: log_file::Error },
+    #[snafu(display("Failed to read {}: {}", path.display(), source))]
+    ReadDelta {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("The log has no event {}", sequence_number))]
+    NoSuchEvent { sequence_number: u64 },
+    #[snafu(display("Failed to decode event {}: {:?}", sequence_number, source))]
+    DecodeEvent {
+        sequence_number: u64,
+        source: magma_core::event::decode::error::Error,
+    },
+    #[snafu(display("There is no path from event {} to event {}", new, old))]
+    NoPath { new: u64, old: u64 },
+    #[snafu(display("Failed to highlight the path: {}", source))]
+    HighlightPath { source: GraphError },
+}
+
+impl From<log_file::Error> for Error {
+    fn from(source: log_file::Error) -> Self {
+        Error::LogFile { source }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let res = match cli.digest {
+        DigestName::Blake2b => run::<blake2::Blake2b>(cli.command),
+        DigestName::Blake2s => run::<blake2::Blake2s>(cli.command),
+        DigestName::Blake3 => run::<blake3::Hasher>(cli.command),
+        DigestName::Sha256 => run::<sha2::Sha256>(cli.command),
+    };
+    if let Err(err) = res {
+        eprintln!("magma: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run<D: Digest>(command: Command) -> Result<(), Error> {
+    match command {
+        Command::Create { log } => log_file::create(&log)?,
+        Command::Append { log, delta } => {
+            let payload = fs::read(&delta).context(ReadDelta { path: delta })?;
+            let mut events = log_file::load::<D>(&log)?;
+            let entry = events.append(Bytes(payload.clone()));
+            log_file::append(
+                &log,
+                &Record {
+                    event: &entry.encoded,
+                    payload: &payload,
+                },
+            )?;
+            println!("{} {}", entry.sequence_number(), hex::encode(&entry.digest));
+        }
+        Command::Print {
+            log,
+            sequence_number,
+        } => {
+            // Decode the single record so broken logs can still be inspected.
+            let bytes = log_file::read(&log)?;
+            let records = Record::decode_all(&bytes)?;
+            let record = sequence_number
+                .checked_sub(1)
+                .and_then(|index| records.get(index as usize))
+                .context(NoSuchEvent { sequence_number })?;
+            let event =
+                Event::<D>::decode(record.event).context(DecodeEvent { sequence_number })?;
+            print_event(&event, &D::digest(record.event));
+        }
+        Command::Verify { log } => {
+            let events = log_file::load::<D>(&log)?;
+            match events.head() {
+                Some(head) => println!(
+                    "{} events, head {}",
+                    events.len(),
+                    hex::encode(&head.digest)
+                ),
+                None => println!("0 events"),
+            }
+        }
+        Command::Path {
+            log,
+            new,
+            old,
+            longest,
+        } => {
+            let events = log_file::load::<D>(&log)?;
+            let path = events
+                .path(new, old, path_length(longest))
+                .context(NoPath {
+                    new,
+                    old: old.unwrap_or(0),
+                })?;
+            for entry in path {
+                println!("{} {}", entry.sequence_number(), hex::encode(&entry.digest));
+            }
+        }
+        Command::Graph {
+            log,
+            new,
+            old,
+            longest,
+            json,
+        } => {
+            let events = log_file::load::<D>(&log)?;
+            let digest = |sequence_number| {
+                events
+                    .get(sequence_number)
+                    .map(|entry| entry.digest.clone())
+                    .context(NoSuchEvent { sequence_number })
+            };
+            let mut graph = Graph::new(events.entries().map(|entry| &entry.event));
+            if let Some(new) = new {
+                let request = Request::<D> {
+                    new: digest(new)?,
+                    old: old.map(digest).transpose()?,
+                    ordering: Ordering::Descending,
+                    path_length: path_length(longest),
+                    include_values: false,
+                    limits: Limits::default(),
+                };
+                graph.highlight_path(&request).context(HighlightPath)?;
+            }
+
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&graph).expect("graphs always serialize")
+                );
+            } else {
+                print!("{}", graph.to_dot());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn path_length(longest: bool) -> PathLength {
+    if longest {
+        PathLength::LongestPath
+    } else {
+        PathLength::ShortestPath
+    }
+}
+
+fn print_event<D: Digest>(event: &Event<D>, digest: &[u8]) {
+    let sequence_number = event.sequence_number().get();
+    println!("sequence number:  {}", sequence_number);
+    println!("digest:           {}", hex::encode(digest));
+    println!(
+        "delta:            {} ({} bytes)",
+        hex::encode(event.delta_digest()),
+        event.size()
+    );
+
+    if let Event::Child {
+        predecessor_event_link,
+        skip_event_link,
+        skip_delta_digest,
+        skip_delta_size,
+        ..
+    } = event
+    {
+        println!(
+            "predecessor link: {} (event {})",
+            hex::encode(predecessor_event_link),
+            sequence_number - 1
+        );
+        if skip_event_link == predecessor_event_link {
+            println!("skip link:        same as the predecessor link");
+        } else {
+            println!(
+                "skip link:        {} (event {})",
+                hex::encode(skip_event_link),
+                skip_link_target(sequence_number)
+            );
+            println!(
+                "skip delta:       {} ({} bytes)",
+                hex::encode(skip_delta_digest),
+                skip_delta_size
+            );
+        }
+        if let Some((digest, size)) = event.accumulated() {
+            println!("accumulated:      {} ({} bytes)", hex::encode(digest), size);
+        }
+    }
+}
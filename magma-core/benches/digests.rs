@@ -0,0 +1,81 @@
+//! Compares encoding, decoding and validating events across the digests `magma-core` is tested
+//! with. Run with `cargo bench -p magma-core`.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use magma_core::replication::request::{Limits, Ordering, PathLength, Request};
+use magma_core::replication::response::Validator;
+use magma_core::*;
+
+const LOG_LENGTH: u64 = 64;
+
+/// Encodes a log without skip links, one single byte payload per event.
+fn encoded_log<D: Digest>() -> Vec<Vec<u8>> {
+    let mut log: Vec<Vec<u8>> = Vec::new();
+    for sequence_number in 1..=LOG_LENGTH {
+        let payload = [sequence_number as u8];
+        let event = match log.last() {
+            None => Event::<D>::Root {
+                delta_digest: D::digest(&payload),
+                delta_size: 1,
+            },
+            Some(previous) => Event::Child {
+                sequence_number: NonZeroU64::new(sequence_number).unwrap(),
+                predecessor_event_link: D::digest(previous),
+                delta_digest: D::digest(&payload),
+                delta_size: 1,
+                skip_event_link: D::digest(previous),
+                skip_delta_digest: D::digest(&payload),
+                skip_delta_size: 1,
+                accumulated: None,
+            },
+        };
+        let mut buffer = vec![0; event.encoding_length()];
+        event.encode(&mut buffer).unwrap();
+        log.push(buffer);
+    }
+    log
+}
+
+fn bench_digest<D: Digest>(c: &mut Criterion, name: &str) {
+    let log = encoded_log::<D>();
+    let last = log.last().unwrap();
+    let event = Event::<D>::decode(last).unwrap();
+    let mut buffer = vec![0; event.encoding_length()];
+
+    let mut group = c.benchmark_group(name);
+    group.bench_function("encode", |b| {
+        b.iter(|| event.encode(black_box(&mut buffer)).unwrap())
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| Event::<D>::decode(black_box(last)).unwrap())
+    });
+    group.bench_function("validate", |b| {
+        let request = Request::<D> {
+            new: D::digest(last),
+            old: None,
+            ordering: Ordering::Ascending,
+            path_length: PathLength::LongestPath,
+            include_values: true,
+            limits: Limits::default(),
+        };
+        b.iter(|| {
+            let mut validator = Validator::new(&request);
+            for (i, event) in log.iter().enumerate() {
+                validator
+                    .push(black_box(event), Some(&[i as u8 + 1]))
+                    .unwrap();
+            }
+            validator.finish().unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn digests(c: &mut Criterion) {
+    bench_digest::<blake2::Blake2b>(c, "blake2b");
+    bench_digest::<blake2::Blake2s>(c, "blake2s");
+    bench_digest::<blake3::Hasher>(c, "blake3");
+    bench_digest::<sha2::Sha256>(c, "sha256");
+}
+
+criterion_group!(benches, digests);
+criterion_main!(benches);
@@ -1,20 +1,23 @@
 #![no_main]
 
 use libfuzzer_sys::fuzz_target;
-use magma_core::*; 
-use blake2::Blake2b;
+use magma_core::*;
 
-type MyEvent = Event<Blake2b>;
-
-fuzz_target!(|data: &[u8]| {
-    match MyEvent::decode(data) {
+fn round_trip<D: Digest>(data: &[u8]) {
+    match Event::<D>::decode(data) {
         Err(_) => {}
         Ok(event) => {
-            let mut out = Vec::new();
-            out.resize(event.encoding_length(), 0);
+            let mut out = vec![0; event.encoding_length()];
             let sz = event.encode(&mut out[..]).unwrap();
 
             assert_eq!(data[..sz], out[..sz]);
         }
     }
+}
+
+fuzz_target!(|data: &[u8]| {
+    round_trip::<blake2::Blake2b>(data);
+    round_trip::<blake2::Blake2s>(data);
+    round_trip::<blake3::Hasher>(data);
+    round_trip::<sha2::Sha256>(data);
 });
@@ -1,28 +1,40 @@
 #![no_main]
 
-use blake2::Blake2b;
 use libfuzzer_sys::fuzz_target;
 use magma_core::replication::request::dto::Request as RequestDto;
-use magma_core::replication::request::{Ordering, PathLength, Request};
+use magma_core::replication::request::{Limits, Ordering, PathLength, Request};
+use magma_core::Digest;
 use std::convert::TryInto;
 
-type MyRequest = Request<Blake2b>;
-
 #[derive(arbitrary::Arbitrary, Debug)]
 struct ArbRequest {
     pub new: Vec<u8>,
     pub old: Option<Vec<u8>>,
     pub include_values: bool,
+    pub max_events: Option<u64>,
+    pub max_payload_bytes: Option<u64>,
 }
 
 fuzz_target!(|arb_request: ArbRequest| {
-    let request_dto = RequestDto {
-        new: arb_request.new,
-        old: arb_request.old,
+    let request_dto = || RequestDto {
+        new: arb_request.new.clone(),
+        old: arb_request.old.clone(),
         include_values: arb_request.include_values,
         ordering: Ordering::Ascending,
         path_length: PathLength::ShortestPath,
+        limits: Limits {
+            max_events: arb_request.max_events,
+            max_payload_bytes: arb_request.max_payload_bytes,
+        },
+        accept_compression: Vec::new(),
     };
 
-    let _request: Result<MyRequest, _> = request_dto.try_into();
+    try_into_request::<blake2::Blake2b>(request_dto());
+    try_into_request::<blake2::Blake2s>(request_dto());
+    try_into_request::<blake3::Hasher>(request_dto());
+    try_into_request::<sha2::Sha256>(request_dto());
 });
+
+fn try_into_request<D: Digest>(request_dto: RequestDto) {
+    let _request: Result<Request<D>, _> = request_dto.try_into();
+}
@@ -1,42 +1,130 @@
 #![no_main]
 use bytes::{Buf, BufMut};
-use blake2::Blake2b;
 use libfuzzer_sys::fuzz_target;
-use magma_core::replication::response::dto::{EventPayloadPair, Response as ResponseDto};
-use magma_core::replication::response::{Response, UnvalidatedResponse};
+use magma_core::replication::response::dto::{
+    self, Compression, EventPayloadPair, Payload, Response as ResponseDto,
+};
+use magma_core::replication::response::UnvalidatedResponse;
 use magma_core::*;
 use std::convert::TryInto;
-use snafu::{Snafu, ensure, ResultExt};
+use snafu::{Snafu, ensure};
 
 
-#[derive(arbitrary::Arbitrary, Debug)]
-struct ArbEventPayloadPair {
-    pub event: Vec<u8>,
-    pub payload: Option<Vec<u8>>,
+#[derive(arbitrary::Arbitrary, Clone, Debug)]
+enum ArbEventPayloadPair {
+    Raw {
+        event: Vec<u8>,
+        payload: Option<ArbPayload>,
+    },
+    /// A root event that declares `delta_size`, so decoding gets past the event to check the
+    /// size of the payload.
+    Sized {
+        delta_size: u64,
+        payload: ArbPayload,
+    },
 }
-#[derive(arbitrary::Arbitrary, Debug)]
+#[derive(arbitrary::Arbitrary, Clone, Debug)]
+enum ArbPayload {
+    Value(Vec<u8>),
+    Lz4(Vec<u8>),
+    Reference(Vec<u8>),
+}
+#[derive(arbitrary::Arbitrary, Clone, Debug)]
 enum ArbResponse {
     UnknownEvent,
+    OldNotAncestorOfNew {
+        old: Vec<u8>,
+        path: Vec<Vec<u8>>,
+    },
+    PayloadsUnavailable,
+    RateLimited,
+    TooLarge,
     Data(Vec<ArbEventPayloadPair>),
+    Partial(Vec<ArbEventPayloadPair>),
+}
+
+fuzz_target!(|input: (ArbResponse, Option<u64>)| {
+    let (arb_response, max_payload_size) = input;
+    try_into_response::<blake2::Blake2b>(arb_response.clone(), max_payload_size);
+    try_into_response::<blake2::Blake2s>(arb_response.clone(), max_payload_size);
+    try_into_response::<blake3::Hasher>(arb_response.clone(), max_payload_size);
+    try_into_response::<sha2::Sha256>(arb_response, max_payload_size);
+});
+
+fn payload(payload: ArbPayload) -> Payload {
+    match payload {
+        ArbPayload::Value(value) => Payload::Value(value),
+        ArbPayload::Lz4(value) => Payload::Compressed {
+            compression: Compression::Lz4,
+            value,
+        },
+        ArbPayload::Reference(digest) => Payload::Reference(digest),
+    }
+}
+
+fn sized_pair<D: Digest>(delta_size: u64, arb_payload: ArbPayload) -> EventPayloadPair {
+    let delta_digest = match &arb_payload {
+        ArbPayload::Value(value) | ArbPayload::Lz4(value) => D::digest(value),
+        ArbPayload::Reference(digest) => D::digest(digest),
+    };
+    let event = Event::<D>::Root {
+        delta_digest,
+        delta_size,
+    };
+    let mut encoded = vec![0; event.encoding_length()];
+    event.encode(&mut encoded).unwrap();
+    EventPayloadPair {
+        event: encoded,
+        payload: Some(payload(arb_payload)),
+    }
 }
 
-fuzz_target!(|arb_response: ArbResponse| {
+fn try_into_response<D: Digest>(arb_response: ArbResponse, max_payload_size: Option<u64>) {
+    // The largest size a sized pair declares, which a decoded response must be within, and
+    // whether a sized pair sends a value of another size
+    let mut largest_size = None;
+    let mut mismatched = false;
+    let mut pairs = |data: Vec<ArbEventPayloadPair>| {
+        data.into_iter()
+            .map(|pair| match pair {
+                ArbEventPayloadPair::Raw { event, payload: p } => EventPayloadPair {
+                    event,
+                    payload: p.map(payload),
+                },
+                ArbEventPayloadPair::Sized { delta_size, payload } => {
+                    largest_size = largest_size.max(Some(delta_size));
+                    if let ArbPayload::Value(value) = &payload {
+                        mismatched |= value.len() as u64 != delta_size;
+                    }
+                    sized_pair::<D>(delta_size, payload)
+                }
+            })
+            .collect()
+    };
     let response_dto = match arb_response {
         ArbResponse::UnknownEvent => ResponseDto::UnknownEvent,
-        ArbResponse::Data(data) => {
-            let res = data.into_iter().map(|pair| EventPayloadPair {
-                event: pair.event,
-                payload: pair.payload,
-            }).collect();
-            ResponseDto::Data(res)
+        ArbResponse::OldNotAncestorOfNew { old, path } => {
+            ResponseDto::OldNotAncestorOfNew { old, path }
         }
+        ArbResponse::PayloadsUnavailable => ResponseDto::PayloadsUnavailable,
+        ArbResponse::RateLimited => ResponseDto::RateLimited,
+        ArbResponse::TooLarge => ResponseDto::TooLarge,
+        ArbResponse::Data(data) => ResponseDto::Data(pairs(data)),
+        ArbResponse::Partial(data) => ResponseDto::Partial(pairs(data)),
     };
 
-    let _: Result<MyUnvalidatedResponse, _> = response_dto.try_into();
-});
+    let res: Result<UnvalidatedResponse<D, U32Semigroup>, _> = match max_payload_size {
+        Some(max_payload_size) => response_dto.decode_within(max_payload_size),
+        None => response_dto.try_into(),
+    };
+    if res.is_ok() {
+        let max_payload_size = max_payload_size.unwrap_or(dto::DEFAULT_MAX_PAYLOAD_SIZE);
+        assert!(largest_size.map_or(true, |size| size <= max_payload_size));
+        assert!(!mismatched);
+    }
+}
 #[derive(Debug)]
 struct U32Semigroup(u32);
-type MyUnvalidatedResponse = UnvalidatedResponse<Blake2b, U32Semigroup>;
 
 #[derive(Snafu, Debug)]
 enum CanonicalEncodingU32Error {
@@ -53,7 +141,7 @@ impl CanonicalEncoding for U32Semigroup {
     type Error = CanonicalEncodingU32Error;
 
     fn encode(&self, mut buffer: &mut [u8]) -> core::result::Result<usize, Self::Error> {
-        ensure!(buffer.len() > 4, BufferTooSmall);
+        ensure!(buffer.len() >= 4, BufferTooSmall);
         buffer.put_u32(self.0);
         Ok(4)
     }
@@ -62,7 +150,7 @@ impl CanonicalEncoding for U32Semigroup {
     where
         Self: Sized,
     {
-        ensure!(buffer.len() > 4, BufferTooSmall);
+        ensure!(buffer.len() >= 4, BufferTooSmall);
         let result = buffer.get_u32();
         Ok((U32Semigroup(result), buffer))
     }
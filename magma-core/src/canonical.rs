@@ -0,0 +1,163 @@
+use frunk::Semigroup;
+use postcard::ser_flavors::{Flavor, Size};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use snafu::{ensure, Snafu};
+
+use crate::CanonicalEncoding;
+
+/// Wraps any serde type so it can be used as a payload.
+///
+/// Values are encoded with [postcard], which always produces the same bytes for the same value.
+/// Decoding serializes the value again and rejects the input unless it matches byte for byte, so
+/// every value has exactly one accepted encoding and its digest is stable across implementations.
+///
+/// Map fields must use an ordered collection like `BTreeMap`. A `HashMap` has no stable iteration
+/// order, so it has no stable encoding either.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub struct Canonical<T>(pub T);
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to encode the value: {}", reason))]
+    Encode { reason: postcard::Error },
+
+    #[snafu(display("Failed to decode the value: {}", reason))]
+    Decode { reason: postcard::Error },
+
+    #[snafu(display("The value was decoded from an encoding that is not canonical"))]
+    NonCanonicalEncoding,
+}
+
+impl<T> Semigroup for Canonical<T>
+where
+    T: Semigroup,
+{
+    fn combine(&self, other: &Self) -> Self {
+        Canonical(self.0.combine(&other.0))
+    }
+}
+
+impl<T> CanonicalEncoding for Canonical<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    type Error = Error;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let written =
+            postcard::to_slice(&self.0, buffer).map_err(|reason| Error::Encode { reason })?;
+        Ok(written.len())
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), Self::Error>
+    where
+        Self: Sized,
+    {
+        let (value, rest) =
+            postcard::take_from_bytes::<T>(buffer).map_err(|reason| Error::Decode { reason })?;
+        let encoded = &buffer[..buffer.len() - rest.len()];
+
+        let is_canonical = postcard::serialize_with_flavor(&value, Matches::new(encoded))
+            .map_err(|reason| Error::Encode { reason })?;
+        ensure!(is_canonical, NonCanonicalEncoding);
+
+        Ok((Canonical(value), rest))
+    }
+
+    fn encoding_length(&self) -> usize {
+        // A value that can't be serialized reports a length of zero, `encode` returns the error.
+        postcard::serialize_with_flavor(&self.0, Size::default()).unwrap_or(0)
+    }
+}
+
+/// A postcard flavor that compares the serialized bytes against an expected encoding instead of
+/// storing them, so decoding can check canonicity without allocating.
+struct Matches<'a> {
+    expected: &'a [u8],
+    position: usize,
+    matches: bool,
+}
+
+impl<'a> Matches<'a> {
+    fn new(expected: &'a [u8]) -> Self {
+        Matches {
+            expected,
+            position: 0,
+            matches: true,
+        }
+    }
+}
+
+impl Flavor for Matches<'_> {
+    type Output = bool;
+
+    fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+        self.matches &= self.expected.get(self.position) == Some(&data);
+        self.position += 1;
+        Ok(())
+    }
+
+    fn finalize(self) -> postcard::Result<bool> {
+        Ok(self.matches && self.position == self.expected.len())
+    }
+}
+
+// The tests use `Vec` and `BTreeMap` payloads, which serde only supports with `alloc`.
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    type Payload = Canonical<(u64, Vec<u8>, BTreeMap<u8, u32>)>;
+
+    fn encode<T: CanonicalEncoding>(payload: &T) -> Vec<u8> {
+        let mut buffer = vec![0; payload.encoding_length()];
+        let length = payload.encode(&mut buffer).unwrap();
+        assert_eq!(length, buffer.len());
+        buffer
+    }
+
+    proptest! {
+        #[test]
+        fn encode_decode_canonical(value in any::<(u64, Vec<u8>, BTreeMap<u8, u32>)>(), trailing in any::<Vec<u8>>()){
+            let payload = Canonical(value);
+            let mut buffer = encode(&payload);
+            buffer.extend_from_slice(&trailing);
+
+            let (decoded, rest) = Payload::decode(&buffer).unwrap();
+
+            assert_eq!(decoded, payload);
+            assert_eq!(rest, trailing.as_slice());
+        }
+
+        #[test]
+        fn decoding_never_panics(bytes in any::<Vec<u8>>()){
+            // Whatever decodes is the canonical encoding of its value.
+            if let Ok((decoded, rest)) = Payload::decode(&bytes) {
+                let encoded = encode(&decoded);
+                assert_eq!(&bytes[..encoded.len()], encoded.as_slice());
+                assert_eq!(rest, &bytes[encoded.len()..]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_overlong_varint() {
+        // 1 encoded in two bytes instead of one
+        let res = Canonical::<u64>::decode(&[0x81, 0x00]);
+        assert!(matches!(res, Err(Error::NonCanonicalEncoding)));
+    }
+
+    #[test]
+    fn rejects_unsorted_map() {
+        let mut map = BTreeMap::new();
+        map.insert(1u8, 10u8);
+        map.insert(2u8, 20u8);
+        let payload = Canonical(map);
+        assert_eq!(encode(&payload), vec![2, 1, 10, 2, 20]);
+
+        let res = Canonical::<BTreeMap<u8, u8>>::decode(&[2, 2, 20, 1, 10]);
+        assert!(matches!(res, Err(Error::NonCanonicalEncoding)));
+    }
+}
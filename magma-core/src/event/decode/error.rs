@@ -10,5 +10,9 @@ pub enum Error {
     DecodeSequenceNumberFromVaru64 { source: DecodeError },
     DecodeDeltaSizeFromVaru64 { source: DecodeError },
     DecodeSkipDeltaSizeFromVaru64 { source: DecodeError },
+    DecodeAccumulatedSizeFromVaru64 { source: DecodeError },
+    UnknownFlags { flags: u8 },
+    TrailingBytes,
     DecodedSequenceNumberForChildWasNotLargerThanOne,
+    DecodeMultihash { source: crate::multihash::Error },
 }
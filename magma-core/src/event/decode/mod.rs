@@ -1,8 +1,11 @@
-use digest::{generic_array::GenericArray, Digest, Output};
-use snafu::{ensure, OptionExt};
+use digest::{Digest, Output};
+use snafu::{ensure, OptionExt, ResultExt};
 use varu64::{decode as varu64_decode, decode_non_zero_u64};
 
 pub mod error;
+use crate::event::dto::{AccumulatedRef, EventRef};
+use crate::event::{Accumulated, ACCUMULATED_FLAG};
+use crate::multihash::{DigestFormat, MultihashDigest};
 use crate::Event;
 use error::*;
 
@@ -11,6 +14,74 @@ where
     D: Digest,
 {
     pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let event = EventRef::decode::<D>(bytes)?;
+        Ok(Self::from_event_ref(&event))
+    }
+
+    pub fn decode_with_format(bytes: &[u8], format: DigestFormat) -> Result<Self, Error> {
+        let event = EventRef::decode_with_format::<D>(bytes, format)?;
+        Ok(Self::from_event_ref(&event))
+    }
+
+    /// Copies the digests out of an [EventRef] that was decoded for this digest.
+    pub(crate) fn from_event_ref(event: &EventRef) -> Self {
+        let digest = |bytes: &[u8]| <&Output<D>>::from(bytes).clone();
+        match *event {
+            EventRef::Root {
+                delta_digest,
+                delta_size,
+            } => Self::Root {
+                delta_digest: digest(delta_digest),
+                delta_size,
+            },
+            EventRef::Child {
+                sequence_number,
+                predecessor_event_link,
+                delta_digest,
+                delta_size,
+                skip_event_link,
+                skip_delta_digest,
+                skip_delta_size,
+                accumulated,
+            } => Self::Child {
+                sequence_number,
+                predecessor_event_link: digest(predecessor_event_link),
+                delta_digest: digest(delta_digest),
+                delta_size,
+                skip_event_link: digest(skip_event_link),
+                skip_delta_digest: digest(skip_delta_digest),
+                skip_delta_size,
+                accumulated: accumulated.map(|accumulated| Accumulated {
+                    digest: digest(accumulated.digest),
+                    size: accumulated.size,
+                }),
+            },
+        }
+    }
+}
+
+impl<D> Event<D>
+where
+    D: MultihashDigest,
+{
+    /// Decodes an event written with [Event::encode_multihash].
+    pub fn decode_multihash(bytes: &[u8]) -> Result<Self, Error> {
+        Self::decode_with_format(bytes, DigestFormat::multihash::<D>())
+    }
+}
+
+impl<'a> EventRef<'a> {
+    /// Decodes an event without copying, the digests borrow from `bytes`.
+    pub fn decode<D: Digest>(bytes: &'a [u8]) -> Result<Self, Error> {
+        Self::decode_with_format::<D>(bytes, DigestFormat::Raw)
+    }
+
+    /// Decodes an event whose digests are written in `format`. With [DigestFormat::Multihash]
+    /// every digest must carry the same algorithm code.
+    pub fn decode_with_format<D: Digest>(
+        bytes: &'a [u8],
+        format: DigestFormat,
+    ) -> Result<Self, Error> {
         ensure!(!bytes.is_empty(), DecodeInputIsLengthZero);
         let digest_size = D::output_size();
 
@@ -19,7 +90,7 @@ where
             // The first byte is just whether or not it's a Root.
             let bytes = &bytes[1..];
 
-            let (delta_digest, bytes) = Self::decode_digest(bytes, digest_size)?;
+            let (delta_digest, bytes) = decode_digest(bytes, format, digest_size)?;
 
             let (size, _) = varu64_decode(bytes).map_err(|(varu_error, _)| {
                 Error::DecodeRootSizeFromVaru64 { source: varu_error }
@@ -39,8 +110,8 @@ where
                 DecodedSequenceNumberForChildWasNotLargerThanOne
             );
 
-            let (predecessor_event_link, bytes) = Self::decode_digest(bytes, digest_size)?;
-            let (delta_digest, bytes) = Self::decode_digest(bytes, digest_size)?;
+            let (predecessor_event_link, bytes) = decode_digest(bytes, format, digest_size)?;
+            let (delta_digest, bytes) = decode_digest(bytes, format, digest_size)?;
 
             let (delta_size, bytes) = varu64_decode(bytes)
                 .map_err(|(err, _)| Error::DecodeDeltaSizeFromVaru64 { source: err })?;
@@ -48,23 +119,34 @@ where
             // If there are still bytes left then there must be skip link etc.
             // Otherwise we just set skip == delta.
             // TODO I think the Event type should have an option of skips tbh.
-            let (skip_event_link, skip_delta_digest, skip_delta_size) = match bytes.len() {
-                0 => Ok((
-                    predecessor_event_link.clone(),
-                    delta_digest.clone(),
-                    delta_size,
-                )),
+            let (skip_event_link, skip_delta_digest, skip_delta_size, bytes) = match bytes.len() {
+                0 => Ok((predecessor_event_link, delta_digest, delta_size, bytes)),
                 _ => {
-                    let (skip_event_link, bytes) = Self::decode_digest(bytes, digest_size)?;
-                    let (skip_delta_digest, bytes) = Self::decode_digest(bytes, digest_size)?;
-                    let (skip_delta_size, _) = varu64_decode(bytes)
+                    let (skip_event_link, bytes) = decode_digest(bytes, format, digest_size)?;
+                    let (skip_delta_digest, bytes) = decode_digest(bytes, format, digest_size)?;
+                    let (skip_delta_size, bytes) = varu64_decode(bytes)
                         .map_err(|(err, _)| Error::DecodeSkipDeltaSizeFromVaru64 { source: err })?;
 
-                    Ok((skip_event_link, skip_delta_digest, skip_delta_size))
+                    Ok((skip_event_link, skip_delta_digest, skip_delta_size, bytes))
                 }
             }?;
 
-            Ok(Event::Child {
+            // Any bytes after the skip link start with the flags of the optional fields.
+            let accumulated = match bytes.split_first() {
+                None => None,
+                Some((&flags, bytes)) => {
+                    ensure!(flags == ACCUMULATED_FLAG, UnknownFlags { flags });
+                    let (digest, bytes) = decode_digest(bytes, format, digest_size)?;
+                    let (size, bytes) = varu64_decode(bytes).map_err(|(err, _)| {
+                        Error::DecodeAccumulatedSizeFromVaru64 { source: err }
+                    })?;
+                    // Otherwise events with other digests would decode to the same event.
+                    ensure!(bytes.is_empty(), TrailingBytes);
+                    Some(AccumulatedRef { digest, size })
+                }
+            };
+
+            Ok(Self::Child {
                 sequence_number,
                 predecessor_event_link,
                 delta_digest,
@@ -72,18 +154,19 @@ where
                 skip_event_link,
                 skip_delta_digest,
                 skip_delta_size,
+                accumulated,
             })
         }
     }
-    fn decode_digest(
-        bytes: &[u8],
-        digest_size: usize,
-    ) -> Result<(GenericArray<u8, <D as Digest>::OutputSize>, &[u8]), Error> {
-        let delta_digest = bytes
-            .get(..digest_size)
-            .map(Output::<D>::clone_from_slice)
-            .context(OutBufferTooSmall)?;
-        let bytes = &bytes[digest_size..];
-        Ok((delta_digest, bytes))
-    }
+}
+
+fn decode_digest(
+    bytes: &[u8],
+    format: DigestFormat,
+    digest_size: usize,
+) -> Result<(&[u8], &[u8]), Error> {
+    format
+        .decode_digest(bytes, digest_size)
+        .context(DecodeMultihash)?
+        .context(OutBufferTooSmall)
 }
@@ -3,10 +3,11 @@ use alloc::vec::Vec;
 
 use core::convert::TryFrom;
 use core::num::NonZeroU64;
-use digest::{generic_array::GenericArray, Digest};
+use digest::{Digest, Output};
 use serde::{Deserialize, Serialize};
 use snafu::{ensure, Snafu};
 
+use crate::event::Accumulated as ValidAccumulated;
 use crate::Event as ValidEvent;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -25,11 +26,33 @@ pub enum EventRef<'a> {
         skip_event_link: &'a [u8], // the skip event, None if this is the first event
         skip_delta_digest: &'a [u8], // change compared to the skip event
         skip_delta_size: u64,      // size in bytes of this.skip_delta
+
+        #[serde(borrow, default)]
+        accumulated: Option<AccumulatedRef<'a>>,
     },
 }
 
+/// The digest and size of the accumulated value at an event, see [crate::event::Accumulated].
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct AccumulatedRef<'a> {
+    pub digest: &'a [u8],
+    pub size: u64,
+}
+
+impl<'a> EventRef<'a> {
+    /// The root event has sequence number 1.
+    pub fn sequence_number(&self) -> NonZeroU64 {
+        match self {
+            Self::Root { .. } => NonZeroU64::new(1).unwrap(),
+            Self::Child {
+                sequence_number, ..
+            } => *sequence_number,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
-#[cfg(any(feature = "alloc", feature = "std"))]
+#[cfg(feature = "alloc")]
 pub enum Event {
     Root {
         delta_digest: Vec<u8>,
@@ -45,9 +68,19 @@ pub enum Event {
         skip_event_link: Vec<u8>, // the skip event, None if this is the first event
         skip_delta_digest: Vec<u8>, // change compared to the skip event
         skip_delta_size: u64,     // size in bytes of this.skip_delta
+
+        #[serde(default)]
+        accumulated: Option<Accumulated>,
     },
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg(feature = "alloc")]
+pub struct Accumulated {
+    pub digest: Vec<u8>,
+    pub size: u64,
+}
+
 #[derive(Snafu, Debug)]
 pub enum Error {
     #[snafu(display(
@@ -66,8 +99,53 @@ pub enum Error {
     },
 }
 
-#[cfg(any(feature = "alloc", feature = "std"))]
-impl<'a, D: Digest> TryFrom<Event> for ValidEvent<D> {
+impl<'a, D: Digest> TryFrom<EventRef<'a>> for ValidEvent<D> {
+    type Error = Error;
+
+    fn try_from(value: EventRef<'a>) -> Result<Self, Self::Error> {
+        match value {
+            EventRef::Root {
+                delta_digest,
+                delta_size,
+            } => Ok(ValidEvent::Root {
+                delta_digest: try_convert_slice_to_digest::<D>(delta_digest)?,
+                delta_size,
+            }),
+            EventRef::Child {
+                sequence_number,
+                predecessor_event_link,
+                delta_digest,
+                delta_size,
+                skip_event_link,
+                skip_delta_digest,
+                skip_delta_size,
+                accumulated,
+            } => {
+                ensure!(sequence_number.get() >= 2u64, InvalidSequenceNumber);
+
+                Ok(ValidEvent::Child {
+                    sequence_number,
+                    predecessor_event_link: try_convert_slice_to_digest::<D>(
+                        predecessor_event_link,
+                    )?,
+                    delta_digest: try_convert_slice_to_digest::<D>(delta_digest)?,
+                    delta_size,
+                    skip_event_link: try_convert_slice_to_digest::<D>(skip_event_link)?,
+                    skip_delta_digest: try_convert_slice_to_digest::<D>(skip_delta_digest)?,
+                    skip_delta_size,
+                    accumulated: accumulated
+                        .map(|accumulated| {
+                            try_convert_accumulated(accumulated.digest, accumulated.size)
+                        })
+                        .transpose()?,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<D: Digest> TryFrom<Event> for ValidEvent<D> {
     type Error = Error;
 
     fn try_from(value: Event) -> Result<Self, Self::Error> {
@@ -92,17 +170,43 @@ impl<'a, D: Digest> TryFrom<Event> for ValidEvent<D> {
                 skip_event_link,
                 skip_delta_digest,
                 skip_delta_size,
+                accumulated,
             } => {
                 ensure!(sequence_number.get() >= 2u64, InvalidSequenceNumber);
-                unimplemented!();
+
+                let evt = ValidEvent::Child {
+                    sequence_number,
+                    predecessor_event_link: try_convert_slice_to_digest::<D>(
+                        &predecessor_event_link,
+                    )?,
+                    delta_digest: try_convert_slice_to_digest::<D>(&delta_digest)?,
+                    delta_size,
+                    skip_event_link: try_convert_slice_to_digest::<D>(&skip_event_link)?,
+                    skip_delta_digest: try_convert_slice_to_digest::<D>(&skip_delta_digest)?,
+                    skip_delta_size,
+                    accumulated: accumulated
+                        .map(|accumulated| {
+                            try_convert_accumulated(&accumulated.digest, accumulated.size)
+                        })
+                        .transpose()?,
+                };
+                Ok(evt)
             }
         }
     }
 }
 
-fn try_convert_slice_to_digest<D: Digest>(
-    delta_digest: &[u8],
-) -> Result<GenericArray<u8, <D as Digest>::OutputSize>, Error> {
+fn try_convert_accumulated<D: Digest>(
+    digest: &[u8],
+    size: u64,
+) -> Result<ValidAccumulated<D>, Error> {
+    Ok(ValidAccumulated {
+        digest: try_convert_slice_to_digest::<D>(digest)?,
+        size,
+    })
+}
+
+fn try_convert_slice_to_digest<D: Digest>(delta_digest: &[u8]) -> Result<Output<D>, Error> {
     let actual_length = delta_digest.len();
     let expected_length = D::output_size();
     ensure!(
@@ -112,6 +216,6 @@ fn try_convert_slice_to_digest<D: Digest>(
             actual_length
         }
     );
-    let digest = GenericArray::clone_from_slice(delta_digest);
+    let digest = <&Output<D>>::from(delta_digest).clone();
     Ok(digest)
 }
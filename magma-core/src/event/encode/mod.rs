@@ -3,6 +3,8 @@ use snafu::ensure;
 use varu64::{encode as varu64_encode, encode_non_zero_u64};
 
 pub mod error;
+use crate::event::ACCUMULATED_FLAG;
+use crate::multihash::{DigestFormat, MultihashDigest};
 use crate::Event;
 use error::*;
 
@@ -11,7 +13,18 @@ where
     D: Digest,
 {
     pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
-        ensure!(out.len() >= self.encoding_length(), OutBufferTooSmall);
+        self.encode_with_format(out, DigestFormat::Raw)
+    }
+
+    pub fn encoding_length(&self) -> usize {
+        self.encoding_length_with_format(DigestFormat::Raw)
+    }
+
+    pub fn encode_with_format(&self, out: &mut [u8], format: DigestFormat) -> Result<usize, Error> {
+        ensure!(
+            out.len() >= self.encoding_length_with_format(format),
+            OutBufferTooSmall
+        );
 
         match self {
             Self::Root {
@@ -25,10 +38,7 @@ where
                 next_byte_num += 1;
 
                 // Followed by the delta digest
-                let digest_bytes = delta_digest.as_slice();
-                out[next_byte_num..digest_bytes.len() + next_byte_num]
-                    .copy_from_slice(digest_bytes);
-                next_byte_num += digest_bytes.len();
+                next_byte_num += format.encode_digest(delta_digest, &mut out[next_byte_num..]);
 
                 // Followed by the delta size
                 next_byte_num += varu64_encode(*delta_size, &mut out[next_byte_num..]);
@@ -42,6 +52,7 @@ where
                 skip_event_link,
                 skip_delta_digest,
                 skip_delta_size,
+                accumulated,
             } => {
                 let mut next_byte_num = 0;
 
@@ -49,62 +60,93 @@ where
                 next_byte_num += encode_non_zero_u64(*sequence_number, &mut out[next_byte_num..]);
 
                 // Followed by predecessor_event_link
-                out[next_byte_num..predecessor_event_link.len() + next_byte_num]
-                    .copy_from_slice(predecessor_event_link);
-                next_byte_num += predecessor_event_link.len();
+                next_byte_num +=
+                    format.encode_digest(predecessor_event_link, &mut out[next_byte_num..]);
 
                 // Followed by the delta digest
-                let digest_bytes = delta_digest.as_slice();
-                out[next_byte_num..digest_bytes.len() + next_byte_num]
-                    .copy_from_slice(digest_bytes);
-                next_byte_num += digest_bytes.len();
+                next_byte_num += format.encode_digest(delta_digest, &mut out[next_byte_num..]);
 
                 // Followed by the delta size
                 next_byte_num += varu64_encode(*delta_size, &mut out[next_byte_num..]);
 
-                if skip_event_link != predecessor_event_link {
+                // The skip link is written out when the accumulated value follows, so the decoder
+                // can tell the two apart.
+                if skip_event_link != predecessor_event_link || accumulated.is_some() {
                     // Followed by skip_event_link
-                    out[next_byte_num..skip_event_link.len() + next_byte_num]
-                        .copy_from_slice(skip_event_link);
-                    next_byte_num += skip_event_link.len();
+                    next_byte_num +=
+                        format.encode_digest(skip_event_link, &mut out[next_byte_num..]);
 
                     // Followed by the skip_delta digest
-                    let digest_bytes = skip_delta_digest.as_slice();
-                    out[next_byte_num..digest_bytes.len() + next_byte_num]
-                        .copy_from_slice(digest_bytes);
-                    next_byte_num += digest_bytes.len();
+                    next_byte_num +=
+                        format.encode_digest(skip_delta_digest, &mut out[next_byte_num..]);
 
                     // Followed by the skip_delta size
                     next_byte_num += varu64_encode(*skip_delta_size, &mut out[next_byte_num..]);
                 }
 
+                if let Some(accumulated) = accumulated {
+                    // Followed by the flags
+                    out[next_byte_num] = ACCUMULATED_FLAG;
+                    next_byte_num += 1;
+
+                    // Followed by the accumulated value digest
+                    next_byte_num +=
+                        format.encode_digest(&accumulated.digest, &mut out[next_byte_num..]);
+
+                    // Followed by the accumulated value size
+                    next_byte_num += varu64_encode(accumulated.size, &mut out[next_byte_num..]);
+                }
+
                 Ok(next_byte_num)
             }
         }
     }
-    pub fn encoding_length(&self) -> usize {
+
+    pub fn encoding_length_with_format(&self, format: DigestFormat) -> usize {
+        let digest_length = format.encoded_digest_length(D::output_size());
         match self {
             Self::Root {
-                delta_digest: digest,
-                delta_size: size,
-            } => 1 + digest.len() + varu64::encoding_length(*size),
+                delta_size: size, ..
+            } => 1 + digest_length + varu64::encoding_length(*size),
             Self::Child {
-                delta_digest,
                 delta_size,
                 sequence_number,
                 predecessor_event_link,
                 skip_event_link,
                 skip_delta_size,
-                skip_delta_digest,
+                accumulated,
+                ..
             } => {
-                delta_digest.len()
+                let skip_length =
+                    if skip_event_link != predecessor_event_link || accumulated.is_some() {
+                        2 * digest_length + varu64::encoding_length(*skip_delta_size)
+                    } else {
+                        0
+                    };
+                let accumulated_length = accumulated.as_ref().map_or(0, |accumulated| {
+                    1 + digest_length + varu64::encoding_length(accumulated.size)
+                });
+
+                varu64::encoding_length_non_zero_u64(*sequence_number)
+                    + 2 * digest_length
                     + varu64::encoding_length(*delta_size)
-                    + varu64::encoding_length_non_zero_u64(*sequence_number)
-                    + predecessor_event_link.len()
-                    + skip_event_link.len()
-                    + skip_delta_digest.len()
-                    + varu64::encoding_length(*skip_delta_size)
+                    + skip_length
+                    + accumulated_length
             }
         }
     }
 }
+
+impl<D> Event<D>
+where
+    D: MultihashDigest,
+{
+    /// Encodes the event with every digest written as a multihash.
+    pub fn encode_multihash(&self, out: &mut [u8]) -> Result<usize, Error> {
+        self.encode_with_format(out, DigestFormat::multihash::<D>())
+    }
+
+    pub fn encoding_length_multihash(&self) -> usize {
+        self.encoding_length_with_format(DigestFormat::multihash::<D>())
+    }
+}
@@ -3,10 +3,15 @@ pub mod dto;
 pub mod encode;
 
 pub use core::num::NonZeroU64;
+#[allow(deprecated)]
 pub use digest::{generic_array::GenericArray, Digest, Output};
 pub use frunk::Semigroup;
 
-#[derive(Clone, Debug)]
+/// Set in the flags byte that follows the skip link when a child event commits to its
+/// accumulated value.
+pub(crate) const ACCUMULATED_FLAG: u8 = 0x01;
+
+#[derive(Debug)]
 pub enum Event<D: Digest>
 where
     D: Digest,
@@ -26,9 +31,73 @@ where
         skip_event_link: Output<D>,   // the skip event
         skip_delta_digest: Output<D>, // change compared to the skip event
         skip_delta_size: u64,         // size in bytes of this.skip_delta
+
+        /// The accumulated value at this event, if the author commits to it.
+        accumulated: Option<Accumulated<D>>,
     },
 }
 
+/// The digest and size of the encoded accumulated value at an event, so a client can check a
+/// value it folded without replaying the log from a value it already trusts.
+#[derive(Debug)]
+pub struct Accumulated<D: Digest> {
+    pub digest: Output<D>,
+    pub size: u64,
+}
+
+// Derived `Clone` and `PartialEq` would needlessly require `D: Clone` and `D: PartialEq`.
+impl<D: Digest> Clone for Accumulated<D> {
+    fn clone(&self) -> Self {
+        Accumulated {
+            digest: self.digest.clone(),
+            size: self.size,
+        }
+    }
+}
+
+impl<D: Digest> PartialEq for Accumulated<D> {
+    fn eq(&self, other: &Self) -> bool {
+        self.digest == other.digest && self.size == other.size
+    }
+}
+
+// Derived `Clone` would needlessly require `D: Clone`.
+impl<D> Clone for Event<D>
+where
+    D: Digest,
+{
+    fn clone(&self) -> Self {
+        match self {
+            Self::Root {
+                delta_digest,
+                delta_size,
+            } => Self::Root {
+                delta_digest: delta_digest.clone(),
+                delta_size: *delta_size,
+            },
+            Self::Child {
+                sequence_number,
+                predecessor_event_link,
+                delta_digest,
+                delta_size,
+                skip_event_link,
+                skip_delta_digest,
+                skip_delta_size,
+                accumulated,
+            } => Self::Child {
+                sequence_number: *sequence_number,
+                predecessor_event_link: predecessor_event_link.clone(),
+                delta_digest: delta_digest.clone(),
+                delta_size: *delta_size,
+                skip_event_link: skip_event_link.clone(),
+                skip_delta_digest: skip_delta_digest.clone(),
+                skip_delta_size: *skip_delta_size,
+                accumulated: accumulated.clone(),
+            },
+        }
+    }
+}
+
 impl<D> PartialEq for Event<D>
 where
     D: Digest,
@@ -54,6 +123,7 @@ where
                     skip_event_link: l_skip_event_link,
                     skip_delta_digest: l_skip_delta_digest,
                     skip_delta_size: l_skip_delta_size,
+                    accumulated: l_accumulated,
                 },
                 Self::Child {
                     sequence_number: r_sequence_number,
@@ -63,6 +133,7 @@ where
                     skip_event_link: r_skip_event_link,
                     skip_delta_digest: r_skip_delta_digest,
                     skip_delta_size: r_skip_delta_size,
+                    accumulated: r_accumulated,
                 },
             ) => {
                 l_sequence_number == r_sequence_number
@@ -72,6 +143,7 @@ where
                     && l_skip_event_link == r_skip_event_link
                     && l_skip_delta_digest == r_skip_delta_digest
                     && l_skip_delta_size == r_skip_delta_size
+                    && l_accumulated == r_accumulated
             }
             _ => false,
         }
@@ -104,158 +176,257 @@ where
             } => *size,
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::*;
-    use blake2::Blake2b;
-    use proptest::prelude::*;
-
-    type MyEvent = Event<Blake2b>;
-
-    prop_compose! {
-        fn root_event_strategy()(payload in any::<Vec<u8>>()) -> MyEvent{
-            let delta_digest = Blake2b::digest(&payload);
-            Event::Root{
+    /// The digest and size of the payload between the event its skip link points to and this
+    /// event. The skip delta of the root is its delta.
+    pub fn skip_delta(&self) -> (&Output<D>, u64) {
+        match self {
+            Self::Root {
                 delta_digest,
-                delta_size: payload.len() as u64
-            }
+                delta_size,
+            } => (delta_digest, *delta_size),
+            Self::Child {
+                skip_delta_digest,
+                skip_delta_size,
+                ..
+            } => (skip_delta_digest, *skip_delta_size),
         }
     }
-    prop_compose! {
-        fn encoded_root_event_strategy()(root_event in root_event_strategy()) -> Vec<u8> {
-            let mut buffer = Vec::new();
-            buffer.resize(root_event.encoding_length(), 0);
-
-            root_event.encode(&mut buffer).unwrap();
-
-            buffer
+    /// The root event has sequence number 1.
+    pub fn sequence_number(&self) -> NonZeroU64 {
+        match self {
+            Self::Root { .. } => NonZeroU64::new(1).unwrap(),
+            Self::Child {
+                sequence_number, ..
+            } => *sequence_number,
         }
     }
-    prop_compose! {
-        fn digested_root_event_strategy()(root_event in encoded_root_event_strategy()) -> Output<Blake2b> {
-            Blake2b::digest(&root_event)
+    /// The digest and size of the accumulated value at this event, if the event commits to it.
+    /// The accumulated value of the root is its delta.
+    pub fn accumulated(&self) -> Option<(&Output<D>, u64)> {
+        match self {
+            Self::Root {
+                delta_digest,
+                delta_size,
+            } => Some((delta_digest, *delta_size)),
+            Self::Child { accumulated, .. } => accumulated
+                .as_ref()
+                .map(|accumulated| (&accumulated.digest, accumulated.size)),
         }
     }
+}
 
-    prop_compose! {
-        fn digested_root_event_strategy_one_byte_different()(root_event in digested_root_event_strategy()) -> Output<Blake2b> {
-            let mut event = root_event.clone();
-            event[0] ^= 1;
-            event
-        }
-    }
+#[cfg(test)]
+mod tests {
+    /// Runs the suite once per digest, so nothing assumes a particular `D::output_size()`.
+    macro_rules! digest_suite {
+        ($($name:ident => $digest:ty),* $(,)?) => {$(
+            mod $name {
+                use crate::*;
+                use proptest::prelude::*;
+
+                type MyDigest = $digest;
+                type MyEvent = Event<MyDigest>;
+
+                prop_compose! {
+                    fn root_event_strategy()(payload in any::<Vec<u8>>()) -> MyEvent{
+                        let delta_digest = MyDigest::digest(&payload);
+                        Event::Root{
+                            delta_digest,
+                            delta_size: payload.len() as u64
+                        }
+                    }
+                }
+                prop_compose! {
+                    fn encoded_root_event_strategy()(root_event in root_event_strategy()) -> Vec<u8> {
+                        let mut buffer = vec![0; root_event.encoding_length()];
+
+                        root_event.encode(&mut buffer).unwrap();
+
+                        buffer
+                    }
+                }
+                prop_compose! {
+                    fn digested_root_event_strategy()(root_event in encoded_root_event_strategy()) -> Output<MyDigest> {
+                        MyDigest::digest(&root_event)
+                    }
+                }
+
+                prop_compose! {
+                    fn digested_root_event_strategy_one_byte_different()(root_event in digested_root_event_strategy()) -> Output<MyDigest> {
+                        let mut event = root_event;
+                        event[0] ^= 1;
+                        event
+                    }
+                }
+
+                prop_compose! {
+                    fn valid_sequence_number()(n in any::<u64>())-> NonZeroU64{
+                        NonZeroU64::new(n).unwrap_or(NonZeroU64::new(2).unwrap())
+                    }
+                }
+                prop_compose! {
+                    fn child_with_skip_same_as_predecessor_event_strategy()(payload in any::<Vec<u8>>(), sequence_number in valid_sequence_number(), digested_root_event in digested_root_event_strategy()) -> MyEvent{
+                        let delta_digest = MyDigest::digest(&payload);
+                        Event::Child{
+                            sequence_number,
+                            delta_digest,
+                            delta_size: payload.len() as u64,
+                            predecessor_event_link: digested_root_event,
+                            skip_event_link: digested_root_event,
+                            skip_delta_digest: delta_digest,
+                            skip_delta_size: payload.len() as u64,
+                            accumulated: None,
+                        }
+                    }
+                }
+                prop_compose! {
+                    fn child_event_strategy()(payload in any::<Vec<u8>>(), payload_two in any::<Vec<u8>>(), sequence_number in valid_sequence_number(), predecessor_event_link in digested_root_event_strategy(), skip_event_link in digested_root_event_strategy_one_byte_different()) -> MyEvent{
+
+                        let delta_digest = MyDigest::digest(&payload);
+                        let skip_delta_digest = MyDigest::digest(&payload_two);
+
+                        Event::Child{
+                            sequence_number,
+                            delta_digest,
+                            delta_size: payload.len() as u64,
+                            predecessor_event_link,
+                            skip_event_link,
+                            skip_delta_digest,
+                            skip_delta_size: payload_two.len() as u64,
+                            accumulated: None,
+                        }
+                    }
+                }
+
+                prop_compose! {
+                    fn child_with_accumulated_event_strategy()(event in prop_oneof![child_with_skip_same_as_predecessor_event_strategy(), child_event_strategy()], value in any::<Vec<u8>>()) -> MyEvent{
+                        match event {
+                            Event::Child { sequence_number, predecessor_event_link, delta_digest, delta_size, skip_event_link, skip_delta_digest, skip_delta_size, .. } => Event::Child {
+                                sequence_number,
+                                predecessor_event_link,
+                                delta_digest,
+                                delta_size,
+                                skip_event_link,
+                                skip_delta_digest,
+                                skip_delta_size,
+                                accumulated: Some(Accumulated {
+                                    digest: MyDigest::digest(&value),
+                                    size: value.len() as u64,
+                                }),
+                            },
+                            Event::Root { .. } => unreachable!(),
+                        }
+                    }
+                }
+
+                fn random_event_stratedy() -> BoxedStrategy<MyEvent> {
+                    prop_oneof![
+                        root_event_strategy(),
+                        child_with_skip_same_as_predecessor_event_strategy(),
+                        child_event_strategy(),
+                        child_with_accumulated_event_strategy()
+                    ]
+                    .boxed()
+                }
+
+                proptest! {
+                    #[test]
+                    fn first_byte_of_an_encoded_root_event_is_zero(root_event in root_event_strategy() ){
+                        let mut buffer = vec![0; root_event.encoding_length()];
+
+                        root_event.encode(&mut buffer).unwrap();
+
+                        assert_eq!(buffer[0], 0)
+                    }
+
+                    #[test]
+                    fn next_bytes_of_an_encoded_root_event_contain_digest(root_event in root_event_strategy()){
+                        let mut buffer = vec![0; root_event.encoding_length()];
+                        root_event.encode(&mut buffer).unwrap();
+
+                        let digest = root_event.delta_digest();
+                        assert_eq!(&buffer[1..digest.len() + 1], &digest[..])
+                    }
+
+                    #[test]
+                    fn last_bytes_of_an_encoded_root_event_contain_size_as_varu64(root_event in root_event_strategy()){
+                        let digest = root_event.delta_digest();
+                        let mut buffer = vec![0; root_event.encoding_length()];
+                        root_event.encode(&mut buffer).unwrap();
+
+                        let (n,_ ) = varu64::decode( &buffer[digest.len() + 1 .. ]).unwrap();
+
+                        assert_eq!(n, root_event.size())
+                    }
+
+                    #[test]
+                    fn encode_decode_event(event in random_event_stratedy()){
+                        let mut buffer = vec![0; event.encoding_length()];
+
+                        let encoded_size = event.encode(&mut buffer).unwrap();
+
+                        let decoded = MyEvent::decode(&buffer[..encoded_size]).unwrap();
+
+                        assert_eq!(event, decoded);
+                    }
+
+                    #[test]
+                    fn decoding_rejects_unknown_flags(event in child_event_strategy(), flags in 2..=u8::MAX, rest in any::<Vec<u8>>()){
+                        let mut buffer = vec![0; event.encoding_length()];
+                        event.encode(&mut buffer).unwrap();
+                        buffer.push(flags);
+                        buffer.extend(rest);
+
+                        let res = MyEvent::decode(&buffer);
+
+                        assert!(matches!(res, Err(crate::event::decode::error::Error::UnknownFlags { flags: f }) if f == flags));
+                    }
+
+                    #[test]
+                    fn decoding_rejects_trailing_bytes(event in child_with_accumulated_event_strategy(), rest in proptest::collection::vec(any::<u8>(), 1..16)){
+                        let mut buffer = vec![0; event.encoding_length()];
+                        event.encode(&mut buffer).unwrap();
+                        buffer.extend(rest);
+
+                        let res = MyEvent::decode(&buffer);
+
+                        assert!(matches!(res, Err(crate::event::decode::error::Error::TrailingBytes)));
+                    }
+
+                    #[test]
+                    fn encoding_length_is_exact(event in random_event_stratedy()){
+                        let mut buffer = vec![0; event.encoding_length() + 10];
+
+                        let encoded_size = event.encode(&mut buffer).unwrap();
+
+                        assert_eq!(encoded_size, event.encoding_length());
+                    }
+
+                    #[test]
+                    fn encoding_never_panics_from_incorrect_out_buffer_size(event in random_event_stratedy(), mut out in any::<Vec<u8>>()){
+                        let res = event.encode(&mut out);
+                        assert!(res.is_ok() || res.is_err());
+                    }
+
+                    #[test]
+                    fn decoding_never_panics_from_incorrect_out_buffer_size(event in random_event_stratedy(), truncation_amount in 1..1000usize){
+                        let mut buffer = vec![0; event.encoding_length()];
+                        let encoded_size = event.encode(&mut buffer).unwrap();
 
-    prop_compose! {
-        fn valid_sequence_number()(n in any::<u64>())-> NonZeroU64{
-            NonZeroU64::new(n).unwrap_or(NonZeroU64::new(2).unwrap())
-        }
-    }
-    prop_compose! {
-        fn child_with_skip_same_as_predecessor_event_strategy()(payload in any::<Vec<u8>>(), sequence_number in valid_sequence_number(), digested_root_event in digested_root_event_strategy()) -> MyEvent{
-            let delta_digest = Blake2b::digest(&payload);
-            Event::Child{
-                sequence_number,
-                delta_digest,
-                delta_size: payload.len() as u64,
-                predecessor_event_link: digested_root_event,
-                skip_event_link: digested_root_event,
-                skip_delta_digest: delta_digest,
-                skip_delta_size: payload.len() as u64
-            }
-        }
-    }
-    prop_compose! {
-        fn child_event_strategy()(payload in any::<Vec<u8>>(), payload_two in any::<Vec<u8>>(), sequence_number in valid_sequence_number(), predecessor_event_link in digested_root_event_strategy(), skip_event_link in digested_root_event_strategy_one_byte_different()) -> MyEvent{
+                        let res = MyEvent::decode(&buffer[.. std::cmp::min(encoded_size, truncation_amount)]);
 
-            let delta_digest = Blake2b::digest(&payload);
-            let skip_delta_digest = Blake2b::digest(&payload_two);
+                        assert!(res.is_ok() || res.is_err());
+                    }
 
-            Event::Child{
-                sequence_number,
-                delta_digest,
-                delta_size: payload.len() as u64,
-                predecessor_event_link,
-                skip_event_link,
-                skip_delta_digest,
-                skip_delta_size: payload_two.len() as u64
+                }
             }
-        }
-    }
-
-    fn random_event_stratedy() -> BoxedStrategy<MyEvent> {
-        prop_oneof![
-            root_event_strategy(),
-            child_with_skip_same_as_predecessor_event_strategy(),
-            child_event_strategy()
-        ]
-        .boxed()
+        )*};
     }
 
-    proptest! {
-        #[test]
-        fn first_byte_of_an_encoded_root_event_is_zero(root_event in root_event_strategy() ){
-            let mut buffer = Vec::new();
-            buffer.resize(root_event.encoding_length(), 0);
-
-            root_event.encode(&mut buffer).unwrap();
-
-            assert_eq!(buffer[0], 0)
-        }
-
-        #[test]
-        fn next_bytes_of_an_encoded_root_event_contain_digest(root_event in root_event_strategy()){
-            let mut buffer = Vec::new();
-            buffer.resize(root_event.encoding_length(), 0);
-            root_event.encode(&mut buffer).unwrap();
-
-            let digest = root_event.delta_digest();
-            assert_eq!(&buffer[1..digest.len() + 1], digest.as_slice())
-        }
-
-        #[test]
-        fn last_bytes_of_an_encoded_root_event_contain_size_as_varu64(root_event in root_event_strategy()){
-            let digest = root_event.delta_digest();
-            let mut buffer = Vec::new();
-            buffer.resize(root_event.encoding_length(), 0);
-            root_event.encode(&mut buffer).unwrap();
-
-            let mut size_buffer = Vec::new();
-            size_buffer.resize(varu64::encoding_length(root_event.size()), 0);
-            let (n,_ ) = varu64::decode( &buffer[digest.len() + 1 .. ]).unwrap();
-
-            assert_eq!(n, root_event.size())
-        }
-
-        #[test]
-        fn encode_decode_event(event in random_event_stratedy()){
-            let mut buffer = Vec::new();
-            buffer.resize(event.encoding_length(), 0);
-
-            let encoded_size = event.encode(&mut buffer).unwrap();
-
-            let decoded = MyEvent::decode(&buffer[..encoded_size]).unwrap();
-
-            assert_eq!(event, decoded);
-        }
-
-        #[test]
-        fn encoding_never_panics_from_incorrect_out_buffer_size(event in random_event_stratedy(), mut out in any::<Vec<u8>>()){
-            let res = event.encode(&mut out);
-            assert!(res.is_ok() || res.is_err());
-        }
-
-        #[test]
-        fn decoding_never_panics_from_incorrect_out_buffer_size(event in random_event_stratedy(), truncation_amount in 1..1000usize){
-            let mut buffer = Vec::new();
-            buffer.resize(event.encoding_length(), 0);
-            let encoded_size = event.encode(&mut buffer).unwrap();
-
-            let res = MyEvent::decode(&buffer[.. std::cmp::min(encoded_size, truncation_amount)]);
-
-            assert!(res.is_ok() || res.is_err());
-        }
-
+    digest_suite! {
+        blake2b => blake2::Blake2b,
+        blake2s => blake2::Blake2s,
+        blake3 => blake3::Hasher,
+        sha256 => sha2::Sha256,
     }
 }
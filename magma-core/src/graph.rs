@@ -0,0 +1,375 @@
+//! The evolution graph of a set of events, for looking at the skip link structure of a log.
+//!
+//! A [Graph] serializes to a JSON adjacency list with serde, and [Graph::to_dot] renders it for
+//! Graphviz.
+use alloc::{format, string::String, vec, vec::Vec};
+use core::fmt::Write;
+use digest::{Digest, Output};
+use serde::Serialize;
+use snafu::{OptionExt, Snafu};
+
+use crate::replication::path::Path;
+use crate::replication::request::Request;
+use crate::Event;
+
+#[derive(Snafu, Debug)]
+#[snafu(visibility = "pub(crate)")]
+pub enum Error {
+    #[snafu(display("The graph does not contain the event the request asks for"))]
+    UnknownNew,
+    #[snafu(display("The graph does not contain the event the client already knows"))]
+    UnknownOld,
+    #[snafu(display("The old event is not below the new event"))]
+    NoPath,
+    #[snafu(display("The path needs event {}, which is not in the graph", sequence_number))]
+    MissingEvent { sequence_number: u64 },
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkKind {
+    Predecessor,
+    Skip,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub link: LinkKind,
+    /// The hex encoded digest of the linked event.
+    pub to: String,
+    pub highlighted: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The hex encoded digest of the event.
+    pub digest: String,
+    /// `None` for events that are linked to but not in the graph.
+    pub sequence_number: Option<u64>,
+    pub edges: Vec<Edge>,
+    pub highlighted: bool,
+}
+
+/// Nodes in order of ascending sequence number, followed by the linked events that are missing.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+}
+
+impl Graph {
+    pub fn new<'a, D: Digest + 'a>(events: impl IntoIterator<Item = &'a Event<D>>) -> Self {
+        let mut events: Vec<_> = events.into_iter().collect();
+        events.sort_by_key(|event| event.sequence_number());
+
+        let mut nodes: Vec<Node> = events
+            .into_iter()
+            .map(|event| {
+                let mut encoded = vec![0; event.encoding_length()];
+                event
+                    .encode(&mut encoded)
+                    .expect("Encoding event failed unexpectedly");
+
+                Node {
+                    digest: hex(&D::digest(&encoded)),
+                    sequence_number: Some(event.sequence_number().get()),
+                    edges: edges::<D>(event),
+                    highlighted: false,
+                }
+            })
+            .collect();
+
+        let mut missing: Vec<String> = nodes
+            .iter()
+            .flat_map(|node| node.edges.iter().map(|edge| &edge.to))
+            .filter(|to| !nodes.iter().any(|node| &&node.digest == to))
+            .cloned()
+            .collect();
+        missing.sort();
+        missing.dedup();
+        nodes.extend(missing.into_iter().map(|digest| Node {
+            digest,
+            sequence_number: None,
+            edges: Vec::new(),
+            highlighted: false,
+        }));
+
+        Graph { nodes }
+    }
+
+    /// Marks the events and links on the path the server would send in response to `request`.
+    pub fn highlight_path<D: Digest>(&mut self, request: &Request<D>) -> Result<(), Error> {
+        let new = self.index_of(&hex(&request.new)).context(UnknownNew)?;
+        let old = match &request.old {
+            Some(old) => Some(self.index_of(&hex(old)).context(UnknownOld)?),
+            None => None,
+        };
+        let new_sequence_number = self.nodes[new].sequence_number.context(UnknownNew)?;
+        let old_sequence_number = match old {
+            Some(old) => Some(self.nodes[old].sequence_number.context(UnknownOld)?),
+            None => None,
+        };
+
+        let path = Path::new(
+            new_sequence_number,
+            old_sequence_number,
+            request.path_length,
+        )
+        .context(NoPath)?;
+
+        let mut path = path.peekable();
+        let mut current = new;
+        while let Some(current_sequence_number) = path.next() {
+            self.nodes[current].highlighted = true;
+            let next = match (path.peek(), old_sequence_number) {
+                (Some(&next), _) => next,
+                // The lowest event links to `old`, unless the path ends at the root.
+                (None, Some(old)) => old,
+                (None, None) => break,
+            };
+            let link = if next + 1 == current_sequence_number {
+                LinkKind::Predecessor
+            } else {
+                LinkKind::Skip
+            };
+
+            let edge = self.nodes[current]
+                .edges
+                .iter_mut()
+                .find(|edge| edge.link == link)
+                .context(MissingEvent {
+                    sequence_number: next,
+                })?;
+            edge.highlighted = true;
+            let to = edge.to.clone();
+
+            current = self.index_of(&to).context(MissingEvent {
+                sequence_number: next,
+            })?;
+            if self.nodes[current].sequence_number.is_none() {
+                return MissingEvent {
+                    sequence_number: next,
+                }
+                .fail();
+            }
+        }
+        if let Some(old) = old {
+            self.nodes[old].highlighted = true;
+        }
+        Ok(())
+    }
+
+    /// Renders the graph in the Graphviz DOT language.
+    ///
+    /// Skip links are dashed, events missing from the graph dotted and the highlighted path red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph evolution {\n    rankdir=RL;\n    node [shape=box];\n");
+        for node in &self.nodes {
+            let label = match node.sequence_number {
+                Some(sequence_number) => format!("{}\\n{}", sequence_number, short(&node.digest)),
+                None => format!("?\\n{}", short(&node.digest)),
+            };
+            let mut attributes = format!("label=\"{}\"", label);
+            if node.sequence_number.is_none() {
+                attributes.push_str(" style=dotted");
+            }
+            if node.highlighted {
+                attributes.push_str(" color=red");
+            }
+            writeln!(dot, "    \"{}\" [{}];", node.digest, attributes).unwrap();
+        }
+        for node in &self.nodes {
+            for edge in &node.edges {
+                let mut attributes = String::from(match edge.link {
+                    LinkKind::Predecessor => "style=solid",
+                    LinkKind::Skip => "style=dashed",
+                });
+                if edge.highlighted {
+                    attributes.push_str(" color=red penwidth=2");
+                }
+                writeln!(
+                    dot,
+                    "    \"{}\" -> \"{}\" [{}];",
+                    node.digest, edge.to, attributes
+                )
+                .unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn index_of(&self, digest: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.digest == digest)
+    }
+}
+
+fn edges<D: Digest>(event: &Event<D>) -> Vec<Edge> {
+    let edge = |link, to: &Output<D>| Edge {
+        link,
+        to: hex(to),
+        highlighted: false,
+    };
+    match event {
+        Event::Root { .. } => Vec::new(),
+        Event::Child {
+            predecessor_event_link,
+            skip_event_link,
+            ..
+        } if skip_event_link == predecessor_event_link => {
+            vec![edge(LinkKind::Predecessor, predecessor_event_link)]
+        }
+        Event::Child {
+            predecessor_event_link,
+            skip_event_link,
+            ..
+        } => vec![
+            edge(LinkKind::Predecessor, predecessor_event_link),
+            edge(LinkKind::Skip, skip_event_link),
+        ],
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+/// The start of a hex digest, enough to tell events apart at a glance.
+fn short(digest: &str) -> &str {
+    &digest[..digest.len().min(8)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::tests::log;
+    use crate::replication::request::{Limits, Ordering, PathLength};
+    use blake2::Blake2b;
+
+    fn events(length: u8) -> Vec<Event<Blake2b>> {
+        let payloads: Vec<Vec<u8>> = (0..length).map(|i| vec![i]).collect();
+        log(&payloads)
+            .entries()
+            .map(|entry| entry.event.clone())
+            .collect()
+    }
+
+    fn request(events: &[Event<Blake2b>], new: usize, old: Option<usize>) -> Request<Blake2b> {
+        let digest = |event: &Event<Blake2b>| {
+            let mut encoded = vec![0; event.encoding_length()];
+            event.encode(&mut encoded).unwrap();
+            Blake2b::digest(&encoded)
+        };
+        Request {
+            new: digest(&events[new - 1]),
+            old: old.map(|old| digest(&events[old - 1])),
+            ordering: Ordering::Descending,
+            path_length: PathLength::ShortestPath,
+            include_values: false,
+            limits: Limits::default(),
+        }
+    }
+
+    fn highlighted(graph: &Graph) -> Vec<u64> {
+        graph
+            .nodes
+            .iter()
+            .filter(|node| node.highlighted)
+            .filter_map(|node| node.sequence_number)
+            .collect()
+    }
+
+    #[test]
+    fn skip_edges_only_where_skip_differs_from_predecessor() {
+        let graph = Graph::new(&events(4));
+
+        let links: Vec<Vec<LinkKind>> = graph
+            .nodes
+            .iter()
+            .map(|node| node.edges.iter().map(|edge| edge.link).collect())
+            .collect();
+        assert_eq!(
+            links,
+            [
+                vec![],
+                vec![LinkKind::Predecessor],
+                vec![LinkKind::Predecessor],
+                vec![LinkKind::Predecessor, LinkKind::Skip]
+            ]
+        );
+        assert_eq!(graph.nodes[3].edges[1].to, graph.nodes[0].digest);
+    }
+
+    #[test]
+    fn highlights_the_shortest_path() {
+        let events = events(13);
+        let mut graph = Graph::new(&events);
+
+        graph.highlight_path(&request(&events, 13, None)).unwrap();
+        assert_eq!(highlighted(&graph), [1, 4, 13]);
+
+        let highlighted_edges = graph
+            .nodes
+            .iter()
+            .flat_map(|node| &node.edges)
+            .filter(|edge| edge.highlighted)
+            .count();
+        assert_eq!(highlighted_edges, 2);
+    }
+
+    #[test]
+    fn highlights_the_path_down_to_old() {
+        let events = events(13);
+        let mut graph = Graph::new(&events);
+
+        graph
+            .highlight_path(&request(&events, 13, Some(5)))
+            .unwrap();
+        assert_eq!(highlighted(&graph), [5, 6, 7, 8, 12, 13]);
+    }
+
+    #[test]
+    fn linked_events_outside_the_set_are_missing_nodes() {
+        let events = events(4);
+        let mut graph = Graph::new(&events[2..]);
+
+        assert_eq!(graph.nodes.len(), 4);
+        assert_eq!(graph.nodes[2].sequence_number, None);
+        assert!(matches!(
+            graph.highlight_path(&request(&events, 4, None)),
+            Err(Error::MissingEvent { sequence_number: 1 })
+        ));
+    }
+
+    #[test]
+    fn serializes_to_an_adjacency_list() {
+        let graph = Graph::new(&events(2));
+        let json = serde_json::to_value(&graph).unwrap();
+
+        assert_eq!(json["nodes"][1]["sequence_number"], 2);
+        assert_eq!(json["nodes"][1]["edges"][0]["link"], "predecessor");
+        assert_eq!(
+            json["nodes"][1]["edges"][0]["to"],
+            json["nodes"][0]["digest"]
+        );
+    }
+
+    #[test]
+    fn renders_dot() {
+        let events = events(4);
+        let mut graph = Graph::new(&events);
+        graph.highlight_path(&request(&events, 4, None)).unwrap();
+        let dot = graph.to_dot();
+
+        assert!(dot.starts_with("digraph evolution {"));
+        let skip = format!(
+            "\"{}\" -> \"{}\" [style=dashed color=red penwidth=2];",
+            graph.nodes[3].digest, graph.nodes[0].digest
+        );
+        assert!(dot.contains(&skip));
+        assert_eq!(dot.matches(" -> ").count(), 4);
+    }
+}
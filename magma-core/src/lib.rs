@@ -1,16 +1,31 @@
-#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
 #[cfg(feature = "alloc")]
 extern crate alloc;
 
 pub use core::num::NonZeroU64;
+#[allow(deprecated)]
 pub use digest::{generic_array::GenericArray, Digest, Output};
-pub use event::Event;
+pub use event::{Accumulated, Event};
 pub use frunk::Semigroup;
 use snafu::AsErrorSource;
 
+#[cfg(feature = "canonical")]
+pub mod canonical;
 pub mod event;
+#[cfg(feature = "alloc")]
+pub mod graph;
+#[cfg(feature = "alloc")]
+pub mod log;
+pub mod multihash;
+#[cfg(feature = "alloc")]
+pub mod peer;
 pub mod replication;
+#[cfg(feature = "alloc")]
+pub mod store;
+
+#[cfg(feature = "canonical")]
+pub use canonical::Canonical;
 
 pub trait CanonicalEncoding {
     type Error: AsErrorSource + core::fmt::Debug;
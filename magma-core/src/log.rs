@@ -0,0 +1,1163 @@
+//! An in-memory log, for writing new events and checking logs received from elsewhere.
+use alloc::{vec, vec::Vec};
+use digest::{Digest, Output};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use crate::event::decode::error::Error as DecodeError;
+use crate::replication::checkpoint::{self, Checkpoint, CheckpointResponse};
+use crate::replication::path::{skip_link_target, Path};
+use crate::replication::request::{
+    Budget, CheckpointRequest, EventAtRequest, Ordering, PathLength, Request,
+};
+use crate::replication::response::{EventPayloadPair, Response};
+use crate::{Accumulated, CanonicalEncoding, Event, NonZeroU64, Semigroup};
+
+mod retention;
+pub use retention::{Pruned, RetentionPolicy};
+
+#[derive(Snafu, Debug)]
+#[snafu(visibility = "pub(crate)")]
+pub enum Error {
+    #[snafu(display("Failed to decode event: {}", source))]
+    DecodeEvent { source: DecodeError },
+    #[snafu(display("Expected event {} but got event {}", expected, actual))]
+    UnexpectedSequenceNumber { expected: u64, actual: u64 },
+    #[snafu(display("Event {} did not link to the previous event", sequence_number))]
+    PredecessorLinkDidNotMatch { sequence_number: u64 },
+    #[snafu(display(
+        "Event {} did not skip link to event {}",
+        sequence_number,
+        skip_link_target
+    ))]
+    SkipLinkDidNotMatch {
+        sequence_number: u64,
+        skip_link_target: u64,
+    },
+    #[snafu(display("The delta of event {} did not match its payload", sequence_number))]
+    DeltaDidNotMatchPayload { sequence_number: u64 },
+    #[snafu(display(
+        "The skip delta of event {} did not match the payloads since its skip link",
+        sequence_number
+    ))]
+    SkipDeltaDidNotMatchPayloads { sequence_number: u64 },
+    #[snafu(display(
+        "The accumulated value of event {} did not match the payloads up to it",
+        sequence_number
+    ))]
+    AccumulatedDidNotMatchPayloads { sequence_number: u64 },
+    #[snafu(display("The checkpoint is for an event that is not in the log"))]
+    UnknownCheckpointEvent,
+    #[snafu(display("The checkpoint value did not match the payloads of the log"))]
+    CheckpointValueDidNotMatchPayloads,
+}
+
+/// An event of a [Log], with its encoding and the payload it was written with.
+#[readonly::make]
+#[derive(Debug, Clone)]
+pub struct Entry<D: Digest, S> {
+    pub event: Event<D>,
+    pub encoded: Vec<u8>,
+    pub digest: Output<D>,
+    /// `None` once pruned, see [Log::prune].
+    pub payload: Option<S>,
+    /// The payloads the skip delta combines, kept once some of them are pruned.
+    pub skip_payload: Option<S>,
+}
+
+impl<D: Digest, S> Entry<D, S> {
+    pub fn sequence_number(&self) -> u64 {
+        self.event.sequence_number().get()
+    }
+}
+
+/// What a [Log] holds, e.g. for a server to report.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LogStats {
+    pub events: u64,
+    /// Events whose payload was not pruned.
+    pub payloads: u64,
+    /// The encoded size of the payloads that were not pruned.
+    pub payload_bytes: u64,
+    pub checkpoints: u64,
+}
+
+/// The events of a single log, where event `n` is stored at index `n - 1`.
+///
+/// Every event skip links to [skip_link_target] of its sequence number, and its skip delta is the
+/// combination of the payloads of the events after that target.
+#[derive(Debug, Clone)]
+pub struct Log<D: Digest, S> {
+    entries: Vec<Entry<D, S>>,
+    /// Events up to this one may have had their payloads pruned.
+    pruned_up_to: u64,
+    /// In order of ascending sequence number.
+    checkpoints: Vec<(u64, Checkpoint<D, S>)>,
+    /// Whether child events commit to their accumulated value, see
+    /// [Log::with_accumulated_digests].
+    commits_accumulated: bool,
+    /// The accumulated value at the head, only kept when events commit to it.
+    accumulated: Option<S>,
+}
+
+impl<D: Digest, S> Default for Log<D, S> {
+    fn default() -> Self {
+        Log {
+            entries: Vec::new(),
+            pruned_up_to: 0,
+            checkpoints: Vec::new(),
+            commits_accumulated: false,
+            accumulated: None,
+        }
+    }
+}
+
+impl<D, S> Log<D, S>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A log whose child events commit to the digest and size of their accumulated value.
+    ///
+    /// Events pushed to it must commit to theirs as well, so a log that copies another has to be
+    /// created the same way.
+    pub fn with_accumulated_digests() -> Self {
+        Log {
+            commits_accumulated: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, sequence_number: u64) -> Option<&Entry<D, S>> {
+        let index = sequence_number.checked_sub(1)?;
+        self.entries.get(index as usize)
+    }
+
+    pub fn head(&self) -> Option<&Entry<D, S>> {
+        self.entries.last()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry<D, S>> {
+        self.entries.iter()
+    }
+
+    pub fn stats(&self) -> LogStats {
+        let payloads = self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.payload.as_ref());
+        LogStats {
+            events: self.len(),
+            payloads: payloads.clone().count() as u64,
+            payload_bytes: payloads
+                .map(|payload| payload.encoding_length() as u64)
+                .sum(),
+            checkpoints: self.checkpoints.len() as u64,
+        }
+    }
+
+    /// Finds the event with `digest`.
+    pub fn find(&self, digest: &Output<D>) -> Option<&Entry<D, S>> {
+        self.entries.iter().find(|entry| &entry.digest == digest)
+    }
+
+    /// Writes a new event for `payload` to the end of the log.
+    pub fn append(&mut self, payload: S) -> &Entry<D, S> {
+        let (event, skip_payload, accumulated) = self.next_event(&payload);
+        let mut encoded = vec![0; event.encoding_length()];
+        event
+            .encode(&mut encoded)
+            .expect("Encoding event failed unexpectedly");
+        self.push_entry(event, encoded, payload, skip_payload, accumulated)
+    }
+
+    /// Checks that `encoded` is the next event of the log for `payload` and adds it.
+    pub fn push(&mut self, encoded: &[u8], payload: S) -> Result<&Entry<D, S>, Error> {
+        let event = Event::<D>::decode(encoded).context(DecodeEvent)?;
+        let (expected, skip_payload, accumulated) = self.next_event(&payload);
+
+        let sequence_number = expected.sequence_number().get();
+        ensure!(
+            event.sequence_number() == expected.sequence_number(),
+            UnexpectedSequenceNumber {
+                expected: sequence_number,
+                actual: event.sequence_number().get()
+            }
+        );
+
+        ensure!(
+            (event.delta_digest(), event.size()) == (expected.delta_digest(), expected.size()),
+            DeltaDidNotMatchPayload { sequence_number }
+        );
+
+        if let (
+            Event::Child {
+                predecessor_event_link,
+                skip_event_link,
+                skip_delta_digest,
+                skip_delta_size,
+                accumulated,
+                ..
+            },
+            Event::Child {
+                predecessor_event_link: expected_predecessor_event_link,
+                skip_event_link: expected_skip_event_link,
+                skip_delta_digest: expected_skip_delta_digest,
+                skip_delta_size: expected_skip_delta_size,
+                accumulated: expected_accumulated,
+                ..
+            },
+        ) = (&event, &expected)
+        {
+            ensure!(
+                predecessor_event_link == expected_predecessor_event_link,
+                PredecessorLinkDidNotMatch { sequence_number }
+            );
+            ensure!(
+                skip_event_link == expected_skip_event_link,
+                SkipLinkDidNotMatch {
+                    sequence_number,
+                    skip_link_target: skip_link_target(sequence_number)
+                }
+            );
+            ensure!(
+                (skip_delta_digest, skip_delta_size)
+                    == (expected_skip_delta_digest, expected_skip_delta_size),
+                SkipDeltaDidNotMatchPayloads { sequence_number }
+            );
+            ensure!(
+                accumulated == expected_accumulated,
+                AccumulatedDidNotMatchPayloads { sequence_number }
+            );
+        }
+
+        Ok(self.push_entry(event, encoded.to_vec(), payload, skip_payload, accumulated))
+    }
+
+    /// The entries on the path from `new` down to but excluding `old`, in descending order.
+    ///
+    /// Returns `None` if `new` is not in the log or `old` is not lower than `new`.
+    pub fn path(
+        &self,
+        new: u64,
+        old: Option<u64>,
+        path_length: PathLength,
+    ) -> Option<impl Iterator<Item = &Entry<D, S>>> {
+        if new > self.len() {
+            return None;
+        }
+        let path = Path::new(new, old, path_length)?;
+        Some(path.map(move |sequence_number| &self.entries[sequence_number as usize - 1]))
+    }
+
+    /// Answers a [Request] for the path between two events of this log.
+    pub fn respond(&self, request: &Request<D>) -> Response<D, S> {
+        self.respond_within(request, &Budget::default())
+    }
+
+    /// Answers a [Request] like [Log::respond], reading no more of the log than `budget` allows.
+    ///
+    /// Finds `new` and `old` by walking the log, which a [crate::store::Store] looks up in its
+    /// index instead.
+    pub fn respond_within(&self, request: &Request<D>, budget: &Budget) -> Response<D, S> {
+        let new = match self.find(&request.new) {
+            Some(new) => new.sequence_number(),
+            None => return Response::UnknownEvent,
+        };
+        let old = match &request.old {
+            Some(old) => match self.find(old) {
+                Some(old) => Some(old.sequence_number()),
+                None => return Response::UnknownEvent,
+            },
+            None => None,
+        };
+        self.respond_from(new, old, request, budget)
+    }
+
+    /// Answers a [Request] like [Log::respond_within], with `new` and `old` already found at
+    /// these sequence numbers.
+    pub(crate) fn respond_from(
+        &self,
+        new: u64,
+        old: Option<u64>,
+        request: &Request<D>,
+        budget: &Budget,
+    ) -> Response<D, S> {
+        if let Some(old) = old {
+            // A log has no forks, so only an event that is above `new` isn't its ancestor.
+            if old > new {
+                return self.not_ancestor(new, &self.entries[old as usize - 1].event);
+            }
+            // The client already has `new`.
+            if old == new {
+                return Response::Data(Vec::new());
+            }
+        }
+        let mut hops = hops(new, old, request.path_length, request.ordering).peekable();
+
+        // In order of descending depth the last event is repeated in the next response.
+        let minimum = match request.ordering {
+            Ordering::Ascending => 1,
+            Ordering::Descending => 2,
+        };
+        let max_events = match (request.limits.max_events, budget.max_events_walked) {
+            (Some(limit), Some(budget)) => limit.min(budget),
+            (limit, budget) => limit.or(budget).unwrap_or(u64::MAX),
+        };
+        let mut pairs = Vec::new();
+        let mut payload_bytes = 0u64;
+        let mut bytes_read = 0u64;
+        while let Some(&(lower, upper)) = hops.peek() {
+            let sent = pairs.len() as u64;
+            if sent >= max_events && sent >= minimum {
+                break;
+            }
+            let entry = &self.entries[upper as usize - 1];
+            // The payload is everything between this event and the next one down the path.
+            let payload = if request.include_values {
+                match self.hop_payload(lower, upper) {
+                    Some(payload) => Some(payload),
+                    None => return Response::PayloadsUnavailable,
+                }
+            } else {
+                None
+            };
+            let payload_length = payload
+                .as_ref()
+                .map_or(0, |payload| payload.encoding_length() as u64);
+            payload_bytes += payload_length;
+            bytes_read += entry.encoded.len() as u64 + payload_length;
+            let over = matches!(request.limits.max_payload_bytes, Some(max) if payload_bytes > max)
+                || matches!(budget.max_bytes_read, Some(max) if bytes_read > max);
+            if over && sent >= minimum {
+                break;
+            }
+            pairs.push(EventPayloadPair {
+                event: entry.event.clone(),
+                payload,
+            });
+            hops.next();
+        }
+
+        if hops.peek().is_none() {
+            return Response::Data(pairs);
+        }
+        if let Ordering::Descending = request.ordering {
+            // The next response starts with this event again, with its payload checked then.
+            if let Some(last) = pairs.last_mut() {
+                last.payload = None;
+            }
+        }
+        Response::Partial(pairs)
+    }
+
+    /// Proves that `old`, of this log or another one, is not an ancestor of the event at `new`:
+    /// with just `new` if `old` is not below it, or else with the shortest path from `new` down to
+    /// the event at the sequence number of `old`.
+    pub(crate) fn not_ancestor(&self, new: u64, old: &Event<D>) -> Response<D, S> {
+        let at = old.sequence_number().get();
+        let path = if at >= new {
+            vec![self.entries[new as usize - 1].event.clone()]
+        } else {
+            self.path(new, Some(at), PathLength::ShortestPath)
+                .into_iter()
+                .flatten()
+                .chain(Some(&self.entries[at as usize - 1]))
+                .map(|entry| entry.event.clone())
+                .collect()
+        };
+        Response::OldNotAncestorOfNew {
+            old: old.clone(),
+            path,
+        }
+    }
+
+    /// Answers an [EventAtRequest] with the shortest path from `new` down to the requested event.
+    pub fn respond_event_at(&self, request: &EventAtRequest<D>) -> Response<D, S> {
+        match self.find(&request.new) {
+            Some(new) => self.respond_event_at_from(new.sequence_number(), request),
+            None => Response::UnknownEvent,
+        }
+    }
+
+    /// Answers an [EventAtRequest] like [Log::respond_event_at], with `new` already found at this
+    /// sequence number.
+    pub(crate) fn respond_event_at_from(
+        &self,
+        new: u64,
+        request: &EventAtRequest<D>,
+    ) -> Response<D, S> {
+        let sequence_number = request.sequence_number.get();
+        if sequence_number > new {
+            return Response::UnknownEvent;
+        }
+
+        let path = self
+            .path(new, Some(sequence_number), PathLength::ShortestPath)
+            .into_iter()
+            .flatten()
+            .map(|entry| EventPayloadPair {
+                event: entry.event.clone(),
+                payload: None,
+            });
+        let target = &self.entries[sequence_number as usize - 1];
+        if request.include_value && target.payload.is_none() {
+            return Response::PayloadsUnavailable;
+        }
+        let target = EventPayloadPair {
+            event: target.event.clone(),
+            payload: if request.include_value {
+                target.payload.clone()
+            } else {
+                None
+            },
+        };
+        Response::Data(path.chain(Some(target)).collect())
+    }
+
+    /// Publishes a checkpoint, replacing any earlier one for the same event.
+    ///
+    /// The value is checked against the payloads if none of them were pruned, the signature is
+    /// left to the clients.
+    pub fn add_checkpoint(&mut self, checkpoint: Checkpoint<D, S>) -> Result<(), Error> {
+        let sequence_number = self
+            .find(&checkpoint.event)
+            .map(Entry::sequence_number)
+            .context(UnknownCheckpointEvent)?;
+        if let Some(value) = self.combined_payload(0, sequence_number) {
+            ensure!(
+                digest_payload::<D, S>(&value) == digest_payload::<D, S>(&checkpoint.value),
+                CheckpointValueDidNotMatchPayloads
+            );
+        }
+        let index = self
+            .checkpoints
+            .partition_point(|(existing, _)| *existing < sequence_number);
+        if matches!(self.checkpoints.get(index), Some((existing, _)) if *existing == sequence_number)
+        {
+            self.checkpoints[index].1 = checkpoint;
+        } else {
+            self.checkpoints
+                .insert(index, (sequence_number, checkpoint));
+        }
+        Ok(())
+    }
+
+    /// Answers a [CheckpointRequest] with the latest checkpoint below `new` and the path from it.
+    pub fn respond_checkpoint(&self, request: &CheckpointRequest<D>) -> CheckpointResponse<D, S> {
+        self.respond_checkpoint_within(request, &Budget::default())
+    }
+
+    /// Answers a [CheckpointRequest] like [Log::respond_checkpoint], reading no more of the log
+    /// than `budget` allows. The path can't be sent in parts, so one that needs more is
+    /// [Response::TooLarge].
+    pub fn respond_checkpoint_within(
+        &self,
+        request: &CheckpointRequest<D>,
+        budget: &Budget,
+    ) -> CheckpointResponse<D, S> {
+        match self.find(&request.new) {
+            Some(new) => self.respond_checkpoint_from(new.sequence_number(), request, budget),
+            None => CheckpointResponse {
+                checkpoint: None,
+                path: Response::UnknownEvent,
+            },
+        }
+    }
+
+    /// Answers a [CheckpointRequest] like [Log::respond_checkpoint_within], with `new` already
+    /// found at this sequence number.
+    pub(crate) fn respond_checkpoint_from(
+        &self,
+        new: u64,
+        request: &CheckpointRequest<D>,
+        budget: &Budget,
+    ) -> CheckpointResponse<D, S> {
+        let found = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(sequence_number, _)| *sequence_number < new);
+        let path = self.respond_from(
+            new,
+            found.map(|(sequence_number, _)| *sequence_number),
+            &checkpoint::path_request(request, found.map(|(_, checkpoint)| &checkpoint.event)),
+            budget,
+        );
+        let checkpoint = found.map(|(_, checkpoint)| checkpoint.clone());
+        let path = match path {
+            Response::Partial(_) => Response::TooLarge,
+            path => path,
+        };
+        CheckpointResponse { checkpoint, path }
+    }
+
+    /// Combines the payloads of the events after `after` up to and including `up_to`.
+    ///
+    /// Returns `None` if any of them was pruned.
+    pub fn combined_payload(&self, after: u64, up_to: u64) -> Option<S> {
+        let mut payloads = self
+            .entries
+            .get(after as usize..up_to as usize)?
+            .iter()
+            .map(|entry| entry.payload.as_ref());
+        let first = payloads.next()??.clone();
+        payloads.try_fold(first, |combined, payload| Some(combined.combine(payload?)))
+    }
+
+    /// The payload sent with event `upper` when the path continues at `lower`, which `upper`
+    /// links to.
+    fn hop_payload(&self, lower: u64, upper: u64) -> Option<S> {
+        let entry = self.get(upper)?;
+        if lower + 1 == upper {
+            entry.payload.clone()
+        } else if lower == skip_link_target(upper) {
+            match &entry.skip_payload {
+                Some(skip_payload) => Some(skip_payload.clone()),
+                None => self.combined_payload(lower, upper),
+            }
+        } else {
+            self.combined_payload(lower, upper)
+        }
+    }
+
+    /// Combines the payloads after `skip_target` up to and including `up_to`, following skip
+    /// links down from `up_to`, which always lead exactly to `skip_target` for the skip target of
+    /// the event after `up_to`.
+    fn payload_since_skip_target(&self, skip_target: u64, up_to: u64) -> Option<S> {
+        let mut combined: Option<S> = None;
+        let mut upper = up_to;
+        while upper > skip_target {
+            let lower = skip_link_target(upper);
+            let payload = self.hop_payload(lower, upper)?;
+            combined = Some(match combined {
+                Some(combined) => payload.combine(&combined),
+                None => payload,
+            });
+            upper = lower;
+        }
+        combined
+    }
+
+    /// The event that would come next in the log for `payload`, the payload of its skip delta if
+    /// the skip link is not the predecessor link, and its accumulated value if the log keeps it.
+    fn next_event(&self, payload: &S) -> (Event<D>, Option<S>, Option<S>) {
+        let (delta_digest, delta_size) = digest_payload::<D, S>(payload);
+
+        let head = match self.head() {
+            None => {
+                let root = Event::Root {
+                    delta_digest,
+                    delta_size,
+                };
+                let accumulated = Some(payload.clone()).filter(|_| self.commits_accumulated);
+                return (root, None, accumulated);
+            }
+            Some(head) => head,
+        };
+
+        let sequence_number = self.len() + 1;
+        let skip_target = skip_link_target(sequence_number);
+        let (skip_event_link, skip_delta_digest, skip_delta_size, skip_payload) =
+            if skip_target == sequence_number - 1 {
+                (head.digest.clone(), delta_digest.clone(), delta_size, None)
+            } else {
+                let skip_payload = self
+                    .payload_since_skip_target(skip_target, self.len())
+                    .expect("Pruning keeps the payloads along skip links")
+                    .combine(payload);
+                let (skip_delta_digest, skip_delta_size) = digest_payload::<D, S>(&skip_payload);
+                (
+                    self.entries[skip_target as usize - 1].digest.clone(),
+                    skip_delta_digest,
+                    skip_delta_size,
+                    Some(skip_payload),
+                )
+            };
+
+        let accumulated = self
+            .accumulated
+            .as_ref()
+            .map(|value| value.combine(payload));
+        let event = Event::Child {
+            sequence_number: NonZeroU64::new(sequence_number).expect("sequence number is >= 2"),
+            predecessor_event_link: head.digest.clone(),
+            delta_digest,
+            delta_size,
+            skip_event_link,
+            skip_delta_digest,
+            skip_delta_size,
+            accumulated: accumulated.as_ref().map(|value| {
+                let (digest, size) = digest_payload::<D, S>(value);
+                Accumulated { digest, size }
+            }),
+        };
+        (event, skip_payload, accumulated)
+    }
+
+    fn push_entry(
+        &mut self,
+        event: Event<D>,
+        encoded: Vec<u8>,
+        payload: S,
+        skip_payload: Option<S>,
+        accumulated: Option<S>,
+    ) -> &Entry<D, S> {
+        let digest = D::digest(&encoded);
+        // Keep the skip payload only when it can't be combined from the payloads.
+        let skip_target = skip_link_target(event.sequence_number().get());
+        let skip_payload = skip_payload.filter(|_| skip_target < self.pruned_up_to);
+        self.accumulated = accumulated;
+        self.entries.push(Entry {
+            event,
+            encoded,
+            digest,
+            payload: Some(payload),
+            skip_payload,
+        });
+        self.entries.last().expect("just pushed an entry")
+    }
+}
+
+/// The hops along the path between `new` and `old` in the order they're sent, each as the
+/// sequence numbers of the event below and the event, so the payload of the hop is what's between
+/// them.
+///
+/// In order of ascending depth the path is walked from the bottom, which is easy as it's either
+/// every event or of logarithmic length, so no events are walked that aren't sent.
+fn hops(
+    new: u64,
+    old: Option<u64>,
+    path_length: PathLength,
+    ordering: Ordering,
+) -> impl Iterator<Item = (u64, u64)> {
+    let bottom = old.unwrap_or(0);
+    let mut path = Path::new(new, old, path_length)
+        .expect("Both events are in the log and old is below new")
+        .peekable();
+    let mut ascending: Vec<u64> = match (ordering, path_length) {
+        (Ordering::Ascending, PathLength::ShortestPath) => path.by_ref().collect(),
+        _ => Vec::new(),
+    };
+    let mut lower = bottom;
+    core::iter::from_fn(move || {
+        let upper = match (ordering, path_length) {
+            (Ordering::Descending, _) => {
+                let upper = path.next()?;
+                return Some((path.peek().copied().unwrap_or(bottom), upper));
+            }
+            (Ordering::Ascending, PathLength::ShortestPath) => ascending.pop()?,
+            (Ordering::Ascending, PathLength::LongestPath) if lower < new => lower + 1,
+            (Ordering::Ascending, PathLength::LongestPath) => return None,
+        };
+        let hop = (lower, upper);
+        lower = upper;
+        Some(hop)
+    })
+}
+
+/// Hashes the encoded value, as the digest and size of a delta or accumulated value of an event.
+fn digest_payload<D: Digest, S: CanonicalEncoding>(payload: &S) -> (Output<D>, u64) {
+    let mut encoded = vec![0; payload.encoding_length()];
+    payload
+        .encode(&mut encoded)
+        .expect("Encoding Semigroup value failed unexpectedly. Is payload.encoding_length buggy?");
+    (D::digest(&encoded), encoded.len() as u64)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::replication::request::Limits;
+    use crate::replication::response::{ResponseValidationError, UnvalidatedResponse};
+    use blake2::Blake2b;
+    use proptest::prelude::*;
+
+    /// Concatenates byte strings, encoded as the bytes themselves.
+    #[derive(Debug, Clone, PartialEq)]
+    pub(crate) struct Bytes(pub(crate) Vec<u8>);
+
+    #[derive(Snafu, Debug)]
+    pub(crate) enum BytesError {
+        BufferTooSmall,
+    }
+
+    impl Semigroup for Bytes {
+        fn combine(&self, other: &Self) -> Self {
+            Bytes([self.0.as_slice(), other.0.as_slice()].concat())
+        }
+    }
+
+    impl CanonicalEncoding for Bytes {
+        type Error = BytesError;
+
+        fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            ensure!(buffer.len() >= self.0.len(), BufferTooSmall);
+            buffer[..self.0.len()].copy_from_slice(&self.0);
+            Ok(self.0.len())
+        }
+
+        fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), Self::Error> {
+            Ok((Bytes(buffer.to_vec()), &[]))
+        }
+
+        fn encoding_length(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    pub(crate) type MyLog = Log<Blake2b, Bytes>;
+
+    pub(crate) fn log(payloads: &[Vec<u8>]) -> MyLog {
+        let mut log = MyLog::new();
+        for payload in payloads {
+            log.append(Bytes(payload.clone()));
+        }
+        log
+    }
+
+    prop_compose! {
+        fn log_strategy()(payloads in prop::collection::vec(any::<Vec<u8>>(), 1..40)) -> MyLog {
+            log(&payloads)
+        }
+    }
+
+    fn log_with_accumulated_digests(payloads: &[Vec<u8>]) -> MyLog {
+        let mut log = MyLog::with_accumulated_digests();
+        for payload in payloads {
+            log.append(Bytes(payload.clone()));
+        }
+        log
+    }
+
+    #[test]
+    fn skip_delta_combines_payloads_since_the_skip_link() {
+        let log = log(&[b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+        let fourth = &log.get(4).unwrap().event;
+
+        match fourth {
+            Event::Child {
+                skip_event_link,
+                skip_delta_digest,
+                skip_delta_size,
+                ..
+            } => {
+                assert_eq!(skip_event_link, &log.get(1).unwrap().digest);
+                assert_eq!(skip_delta_digest, &Blake2b::digest(b"bcd"));
+                assert_eq!(*skip_delta_size, 3);
+            }
+            Event::Root { .. } => panic!("expected a child event"),
+        }
+    }
+
+    #[test]
+    fn events_commit_to_the_accumulated_value() {
+        let written = log_with_accumulated_digests(&[
+            b"a".to_vec(),
+            b"b".to_vec(),
+            b"c".to_vec(),
+            b"d".to_vec(),
+        ]);
+        let accumulated = written.head().unwrap().event.accumulated().unwrap();
+        assert_eq!(accumulated, (&Blake2b::digest(b"abcd"), 4));
+
+        let mut log = MyLog::with_accumulated_digests();
+        for entry in written.entries() {
+            log.push(&entry.encoded, entry.payload.clone().unwrap())
+                .unwrap();
+        }
+
+        let mut log = MyLog::new();
+        log.push(&written.get(1).unwrap().encoded, Bytes(b"a".to_vec()))
+            .unwrap();
+        let res = log.push(&written.get(2).unwrap().encoded, Bytes(b"b".to_vec()));
+        assert!(matches!(
+            res,
+            Err(Error::AccumulatedDidNotMatchPayloads { sequence_number: 2 })
+        ));
+    }
+
+    #[test]
+    fn push_rejects_the_wrong_payload() {
+        let written = log(&[b"a".to_vec(), b"b".to_vec()]);
+        let mut log = MyLog::new();
+        log.push(&written.get(1).unwrap().encoded, Bytes(b"a".to_vec()))
+            .unwrap();
+
+        let res = log.push(&written.get(2).unwrap().encoded, Bytes(b"c".to_vec()));
+        assert!(matches!(
+            res,
+            Err(Error::DeltaDidNotMatchPayload { sequence_number: 2 })
+        ));
+    }
+
+    #[test]
+    fn push_rejects_events_out_of_order() {
+        let written = log(&[b"a".to_vec(), b"b".to_vec()]);
+        let mut log = MyLog::new();
+
+        let res = log.push(&written.get(2).unwrap().encoded, Bytes(b"b".to_vec()));
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedSequenceNumber {
+                expected: 1,
+                actual: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn event_at_rejects_a_path_to_another_event() {
+        let log = log(&[b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+        let mut request = EventAtRequest::<Blake2b> {
+            new: log.get(4).unwrap().digest,
+            sequence_number: NonZeroU64::new(1).unwrap(),
+            include_value: false,
+        };
+        let response: UnvalidatedResponse<_, _> = log.respond_event_at(&request).into();
+
+        request.sequence_number = NonZeroU64::new(2).unwrap();
+        assert!(matches!(
+            response.try_into_valid_event_at(request),
+            Err(ResponseValidationError::EventWasNotAtSequenceNumber)
+        ));
+    }
+
+    #[test]
+    fn partial_descending_response_must_leave_out_the_last_payload() {
+        let log = log(&[vec![1], vec![2], vec![3]]);
+        let request = || Request::<Blake2b> {
+            new: log.get(3).unwrap().digest,
+            old: None,
+            ordering: Ordering::Descending,
+            path_length: PathLength::LongestPath,
+            include_values: true,
+            limits: Limits {
+                max_events: Some(2),
+                max_payload_bytes: None,
+            },
+        };
+        let pairs = |response| match response {
+            Response::Partial(pairs) => pairs,
+            _ => panic!("expected a partial response"),
+        };
+
+        let mut sent = pairs(log.respond(&request()));
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1].payload.is_none());
+
+        sent[1].payload = Some(Bytes(vec![2]));
+        assert!(matches!(
+            UnvalidatedResponse::Partial(sent).try_into_valid_response(request()),
+            Err(ResponseValidationError::UnverifiablePayload)
+        ));
+    }
+
+    #[test]
+    fn budgets_cut_long_paths_short() {
+        let log = log(&vec![vec![0; 10]; 1000]);
+        let request = |ordering| Request::<Blake2b> {
+            new: log.head().unwrap().digest,
+            old: None,
+            ordering,
+            path_length: PathLength::LongestPath,
+            include_values: true,
+            limits: Limits::default(),
+        };
+        let sent = |request: &Request<Blake2b>, budget: Budget| match log
+            .respond_within(request, &budget)
+        {
+            Response::Partial(pairs) => pairs,
+            _ => panic!("expected a partial response"),
+        };
+        let walked = Budget {
+            max_events_walked: Some(5),
+            max_bytes_read: None,
+        };
+        let read = Budget {
+            max_events_walked: None,
+            max_bytes_read: Some(1000),
+        };
+
+        for ordering in [Ordering::Ascending, Ordering::Descending].iter() {
+            assert_eq!(sent(&request(*ordering), walked).len(), 5);
+            let read_pairs = sent(&request(*ordering), read);
+            let bytes: usize = read_pairs
+                .iter()
+                .map(|pair| pair.event.encoding_length() + 10)
+                .sum();
+            assert!(bytes <= 1000);
+        }
+        assert_eq!(
+            sent(&request(Ordering::Ascending), walked)[0]
+                .event
+                .sequence_number()
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn old_above_new_is_not_an_ancestor() {
+        let log = log(&[vec![1], vec![2], vec![3]]);
+        let request = || Request::<Blake2b> {
+            new: log.get(2).unwrap().digest,
+            old: Some(log.get(3).unwrap().digest),
+            ordering: Ordering::Descending,
+            path_length: PathLength::ShortestPath,
+            include_values: false,
+            limits: Limits::default(),
+        };
+
+        let response = log.respond(&request());
+        assert!(matches!(response, Response::OldNotAncestorOfNew { .. }));
+        assert!(matches!(
+            UnvalidatedResponse::from(response).try_into_valid_response(request()),
+            Err(ResponseValidationError::OldNotAncestorOfNew)
+        ));
+    }
+
+    #[test]
+    fn old_at_new_gets_an_empty_path() {
+        let log = log(&[vec![1], vec![2], vec![3]]);
+        let head = log.get(3).unwrap();
+        let request = || Request::<Blake2b> {
+            new: head.digest,
+            old: Some(head.digest),
+            ordering: Ordering::Descending,
+            path_length: PathLength::ShortestPath,
+            include_values: true,
+            limits: Limits::default(),
+        };
+
+        let response = log.respond(&request());
+        assert!(matches!(&response, Response::Data(pairs) if pairs.is_empty()));
+        let valid = UnvalidatedResponse::from(response)
+            .try_into_valid_response(request())
+            .unwrap();
+        assert!(valid.events.is_empty());
+
+        // Nor does `new` prove that it isn't its own ancestor.
+        let proof = UnvalidatedResponse::<_, Bytes>::OldNotAncestorOfNew {
+            old: head.event.clone(),
+            path: vec![head.event.clone()],
+        };
+        assert!(matches!(
+            proof.try_into_valid_response(request()),
+            Err(ResponseValidationError::OldWasAncestorOfNew)
+        ));
+    }
+
+    #[test]
+    fn proves_old_on_another_fork_is_not_an_ancestor() {
+        let ours = log(&[vec![1], vec![2], vec![3], vec![4], vec![5], vec![6]]);
+        let theirs = log(&[vec![1], vec![2], vec![3], vec![9]]);
+        let request = |old: &Entry<Blake2b, Bytes>| Request::<Blake2b> {
+            new: ours.get(6).unwrap().digest,
+            old: Some(old.digest),
+            ordering: Ordering::Descending,
+            path_length: PathLength::ShortestPath,
+            include_values: false,
+            limits: Limits::default(),
+        };
+        // The path from `new` down to event 4 of our log, as a server knowing both forks sends it.
+        let path = || match ours.respond_event_at(&EventAtRequest {
+            new: ours.get(6).unwrap().digest,
+            sequence_number: NonZeroU64::new(4).unwrap(),
+            include_value: false,
+        }) {
+            Response::Data(pairs) => pairs.into_iter().map(|pair| pair.event).collect(),
+            _ => panic!("expected the path to event 4"),
+        };
+        let proof =
+            |old: &Entry<Blake2b, Bytes>| UnvalidatedResponse::<_, Bytes>::OldNotAncestorOfNew {
+                old: old.event.clone(),
+                path: path(),
+            };
+
+        let fork = theirs.get(4).unwrap();
+        assert!(matches!(
+            proof(fork).try_into_valid_response(request(fork)),
+            Err(ResponseValidationError::OldNotAncestorOfNew)
+        ));
+
+        let ancestor = ours.get(4).unwrap();
+        assert!(matches!(
+            proof(ancestor).try_into_valid_response(request(ancestor)),
+            Err(ResponseValidationError::OldWasAncestorOfNew)
+        ));
+
+        assert!(matches!(
+            proof(ancestor).try_into_valid_response(request(fork)),
+            Err(ResponseValidationError::OldEventHashDidNotMatchHashOfRequestOld)
+        ));
+    }
+
+    #[test]
+    fn unknown_events_get_an_unknown_event_response() {
+        let log = log(&[b"a".to_vec()]);
+        let request = EventAtRequest::<Blake2b> {
+            new: Blake2b::digest(b"not an event"),
+            sequence_number: NonZeroU64::new(1).unwrap(),
+            include_value: false,
+        };
+
+        assert!(matches!(
+            log.respond_event_at(&request),
+            Response::UnknownEvent
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn pushing_appended_events_round_trips(written in log_strategy()) {
+            let mut log = MyLog::new();
+            for entry in written.entries() {
+                log.push(&entry.encoded, entry.payload.clone().unwrap()).unwrap();
+            }
+            prop_assert_eq!(log.head().unwrap().digest, written.head().unwrap().digest);
+        }
+
+        #[test]
+        fn folding_checks_accumulated_values(
+            payloads in prop::collection::vec(any::<Vec<u8>>(), 2..40),
+            new in any::<prop::sample::Index>(),
+            old in any::<prop::sample::Index>(),
+            shortest in any::<bool>(),
+            descending in any::<bool>(),
+        ) {
+            let log = log_with_accumulated_digests(&payloads);
+            let new = new.index(log.len() as usize - 1) as u64 + 2;
+            let old = old.index(new as usize - 1) as u64 + 1;
+            let request = |old: Option<u64>| Request::<Blake2b> {
+                new: log.get(new).unwrap().digest,
+                old: old.map(|old| log.get(old).unwrap().digest),
+                ordering: if descending { Ordering::Descending } else { Ordering::Ascending },
+                path_length: if shortest { PathLength::ShortestPath } else { PathLength::LongestPath },
+                include_values: true,
+                limits: Limits::default(),
+            };
+            let valid = |request: Request<Blake2b>| {
+                let response: UnvalidatedResponse<_, _> = log.respond(&request).into();
+                response.try_into_valid_response(request).unwrap()
+            };
+
+            let folded = valid(request(None)).fold(None).unwrap();
+            prop_assert_eq!(Some(folded), log.combined_payload(0, new));
+
+            let base = log.combined_payload(0, old).unwrap();
+            let folded = valid(request(Some(old))).fold(Some(base.clone())).unwrap();
+            prop_assert_eq!(Some(folded), log.combined_payload(0, new));
+
+            let wrong_base = base.combine(&Bytes(b"!".to_vec()));
+            let res = valid(request(Some(old))).fold(Some(wrong_base));
+            prop_assert!(matches!(res, Err(ResponseValidationError::AccumulatedValueDidNotMatch)));
+        }
+
+        #[test]
+        fn responses_to_every_request_validate(
+            log in log_strategy(),
+            new in any::<prop::sample::Index>(),
+            old in any::<prop::sample::Index>(),
+            has_old in any::<bool>(),
+            shortest in any::<bool>(),
+            descending in any::<bool>(),
+        ) {
+            let new = new.index(log.len() as usize) as u64 + 1;
+            let old = if has_old && new > 1 { Some(old.index(new as usize - 1) as u64 + 1) } else { None };
+            let request = Request::<Blake2b> {
+                new: log.get(new).unwrap().digest,
+                old: old.map(|old| log.get(old).unwrap().digest),
+                ordering: if descending { Ordering::Descending } else { Ordering::Ascending },
+                path_length: if shortest { PathLength::ShortestPath } else { PathLength::LongestPath },
+                include_values: true,
+                limits: Limits::default(),
+            };
+
+            let response: UnvalidatedResponse<_, _> = log.respond(&request).into();
+            let valid = response.try_into_valid_response(request).unwrap();
+
+            let expected = log.path(new, old, if shortest { PathLength::ShortestPath } else { PathLength::LongestPath }).unwrap().count();
+            prop_assert_eq!(valid.events.len(), expected);
+        }
+
+        #[test]
+        fn limited_responses_resume_to_the_full_path(
+            log in log_strategy(),
+            new in any::<prop::sample::Index>(),
+            shortest in any::<bool>(),
+            descending in any::<bool>(),
+            max_events in prop::option::of(0..5u64),
+            max_payload_bytes in prop::option::of(0..64u64),
+            max_events_walked in prop::option::of(0..5u64),
+            max_bytes_read in prop::option::of(0..512u64),
+        ) {
+            let new = new.index(log.len() as usize) as u64 + 1;
+            let request = |limits| Request::<Blake2b> {
+                new: log.get(new).unwrap().digest,
+                old: None,
+                ordering: if descending { Ordering::Descending } else { Ordering::Ascending },
+                path_length: if shortest { PathLength::ShortestPath } else { PathLength::LongestPath },
+                include_values: true,
+                limits,
+            };
+            let full: UnvalidatedResponse<_, _> = log.respond(&request(Limits::default())).into();
+            let full = full.try_into_valid_response(request(Limits::default())).unwrap();
+
+            let mut events: Vec<Event<Blake2b>> = Vec::new();
+            let mut values = Vec::new();
+            let mut next = Some(request(Limits { max_events, max_payload_bytes }));
+            while let Some(request) = next {
+                prop_assert!(events.len() <= full.events.len());
+                let budget = Budget { max_events_walked, max_bytes_read };
+                let response: UnvalidatedResponse<_, _> = log.respond_within(&request, &budget).into();
+                let page = response.try_into_valid_response(request).unwrap();
+                if descending && !events.is_empty() {
+                    // The page starts with the last event of the one before, now with its payload.
+                    events.pop();
+                    values.pop();
+                }
+                events.extend(page.events.iter().cloned());
+                values.extend(page.values.iter().cloned());
+                next = page.continuation.clone();
+            }
+
+            prop_assert_eq!(events, full.events.clone());
+            prop_assert_eq!(values, full.values.clone());
+        }
+
+        #[test]
+        fn responses_to_every_event_at_request_validate(
+            log in log_strategy(),
+            new in any::<prop::sample::Index>(),
+            sequence_number in any::<prop::sample::Index>(),
+        ) {
+            let new = new.index(log.len() as usize) as u64 + 1;
+            let sequence_number = sequence_number.index(new as usize) as u64 + 1;
+            let request = EventAtRequest::<Blake2b> {
+                new: log.get(new).unwrap().digest,
+                sequence_number: NonZeroU64::new(sequence_number).unwrap(),
+                include_value: true,
+            };
+
+            let response: UnvalidatedResponse<_, _> = log.respond_event_at(&request).into();
+            let valid = response.try_into_valid_event_at(request).unwrap();
+
+            let event = valid.events.last().unwrap();
+            prop_assert_eq!(event.sequence_number().get(), sequence_number);
+            prop_assert_eq!(valid.values.last().unwrap(), &log.get(sequence_number).unwrap().payload);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Pruning the payloads of old events while still answering requests from far behind.
+//!
+//! A pruned event keeps its payload only if its skip link is its predecessor link. Events whose
+//! skip delta combines pruned payloads keep that combination instead, so the shortest path from
+//! any later event still has a payload for every link it follows. What can no longer be answered
+//! are paths that follow the predecessor link of a pruned event, which [Log::can_answer] checks.
+use alloc::vec::Vec;
+use digest::Digest;
+
+use super::Log;
+use crate::replication::path::skip_link_target;
+use crate::replication::request::Request;
+use crate::{CanonicalEncoding, Semigroup};
+
+/// How long a [Log] keeps the payloads of individual events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// The payloads of this many of the latest events are always kept.
+    pub keep_latest: u64,
+}
+
+/// What [Log::prune] dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pruned {
+    /// The events whose payloads were dropped, in ascending order. A request that includes values
+    /// can't be answered if its path follows the predecessor link of any of them.
+    pub sequence_numbers: Vec<u64>,
+}
+
+impl<D, S> Log<D, S>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding + Clone,
+{
+    /// Drops the payloads of events older than `policy` allows, keeping the skip payloads needed
+    /// for shortest paths.
+    ///
+    /// This only saves storage if combined payloads are smaller than the payloads they combine,
+    /// e.g. for a semigroup whose values have a constant size. For one that concatenates, the
+    /// skip payloads it keeps can take up more than the payloads it drops.
+    pub fn prune(&mut self, policy: &RetentionPolicy) -> Pruned {
+        let up_to = self.len().saturating_sub(policy.keep_latest);
+        if up_to <= self.pruned_up_to {
+            return Pruned {
+                sequence_numbers: Vec::new(),
+            };
+        }
+
+        // Every skip delta reaching into the pruned events has to be kept before dropping them.
+        for sequence_number in 2..=self.len() {
+            let skip_target = skip_link_target(sequence_number);
+            let entry = &self.entries[sequence_number as usize - 1];
+            if skip_target + 1 == sequence_number
+                || skip_target >= up_to
+                || entry.skip_payload.is_some()
+            {
+                continue;
+            }
+            let skip_payload = self
+                .combined_payload(skip_target, sequence_number)
+                .expect("Pruning keeps the payloads along skip links");
+            self.entries[sequence_number as usize - 1].skip_payload = Some(skip_payload);
+        }
+
+        let sequence_numbers = (self.pruned_up_to + 1..=up_to)
+            .filter(|&sequence_number| skip_link_target(sequence_number) + 1 != sequence_number)
+            .collect::<Vec<_>>();
+        for &sequence_number in &sequence_numbers {
+            self.entries[sequence_number as usize - 1].payload = None;
+        }
+        self.pruned_up_to = up_to;
+
+        Pruned { sequence_numbers }
+    }
+
+    /// Whether [Log::respond] answers `request` with its path rather than an error, in
+    /// particular without [crate::replication::response::Response::PayloadsUnavailable].
+    pub fn can_answer(&self, request: &Request<D>) -> bool {
+        let new = match self.find(&request.new) {
+            Some(new) => new.sequence_number(),
+            None => return false,
+        };
+        let old = match &request.old {
+            Some(old) => match self.find(old) {
+                Some(old) => Some(old.sequence_number()),
+                None => return false,
+            },
+            None => None,
+        };
+        let path = match self.path(new, old, request.path_length) {
+            Some(path) => path,
+            None => return false,
+        };
+        if !request.include_values {
+            return true;
+        }
+
+        let mut path = path.map(|entry| entry.sequence_number()).peekable();
+        while let Some(upper) = path.next() {
+            let lower = path.peek().copied().or(old).unwrap_or(0);
+            if !self.has_hop_payload(lower, upper) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn has_hop_payload(&self, lower: u64, upper: u64) -> bool {
+        let entry = &self.entries[upper as usize - 1];
+        if lower + 1 == upper {
+            entry.payload.is_some()
+        } else if lower == skip_link_target(upper) && entry.skip_payload.is_some() {
+            true
+        } else {
+            self.entries[lower as usize..upper as usize]
+                .iter()
+                .all(|entry| entry.payload.is_some())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::tests::{log, Bytes};
+    use crate::replication::request::{Limits, Ordering, PathLength};
+    use crate::replication::response::{Response, UnvalidatedResponse};
+    use blake2::Blake2b;
+    use proptest::prelude::*;
+
+    fn payloads(length: u8) -> Vec<Vec<u8>> {
+        (0..length).map(|i| vec![i]).collect()
+    }
+
+    fn request(
+        log: &Log<Blake2b, Bytes>,
+        new: u64,
+        old: Option<u64>,
+        path_length: PathLength,
+    ) -> Request<Blake2b> {
+        Request {
+            new: log.get(new).unwrap().digest,
+            old: old.map(|old| log.get(old).unwrap().digest),
+            ordering: Ordering::Descending,
+            path_length,
+            include_values: true,
+            limits: Limits::default(),
+        }
+    }
+
+    #[test]
+    fn keeps_payloads_of_events_that_skip_to_their_predecessor() {
+        let mut log = log(&payloads(14));
+
+        let pruned = log.prune(&RetentionPolicy { keep_latest: 4 });
+
+        assert_eq!(pruned.sequence_numbers, [4, 8]);
+        assert!(log.get(4).unwrap().payload.is_none());
+        assert!(log.get(5).unwrap().payload.is_some());
+        assert!(log.get(13).unwrap().skip_payload.is_some());
+        assert!(log
+            .prune(&RetentionPolicy { keep_latest: 4 })
+            .sequence_numbers
+            .is_empty());
+    }
+
+    #[test]
+    fn predecessor_links_of_pruned_events_are_unavailable() {
+        let mut log = log(&payloads(14));
+        log.prune(&RetentionPolicy { keep_latest: 4 });
+
+        let longest = request(&log, 13, None, PathLength::LongestPath);
+        assert!(!log.can_answer(&longest));
+        assert!(matches!(
+            log.respond(&longest),
+            Response::PayloadsUnavailable
+        ));
+
+        let shortest = request(&log, 13, None, PathLength::ShortestPath);
+        assert!(log.can_answer(&shortest));
+        let response = UnvalidatedResponse::from(log.respond(&shortest));
+        assert!(response.try_into_valid_response(shortest).is_ok());
+    }
+
+    proptest! {
+        #[test]
+        fn can_answer_matches_respond(
+            length in 1..60u8,
+            keep_latest in 0..20u64,
+            new in any::<prop::sample::Index>(),
+            old in any::<prop::sample::Index>(),
+            has_old in any::<bool>(),
+            shortest in any::<bool>(),
+        ) {
+            let mut log = log(&payloads(length));
+            log.prune(&RetentionPolicy { keep_latest });
+            let new = new.index(log.len() as usize) as u64 + 1;
+            let old = if has_old && new > 1 { Some(old.index(new as usize - 1) as u64 + 1) } else { None };
+            let path_length = if shortest { PathLength::ShortestPath } else { PathLength::LongestPath };
+            let catch_up = request(&log, new, None, PathLength::ShortestPath);
+            let request = request(&log, new, old, path_length);
+
+            match log.respond(&request) {
+                Response::Data(pairs) => {
+                    prop_assert!(log.can_answer(&request));
+                    let response = UnvalidatedResponse::Data(pairs);
+                    prop_assert!(response.try_into_valid_response(request).is_ok());
+                }
+                Response::PayloadsUnavailable => prop_assert!(!log.can_answer(&request)),
+                other => prop_assert!(false, "unexpected response {:?}", other),
+            }
+            // Pruning never stops a client from catching up from the root.
+            prop_assert!(log.can_answer(&catch_up));
+        }
+
+        #[test]
+        fn appending_after_pruning_writes_the_same_events(
+            before in 1..40u8,
+            after in 1..40u8,
+            keep_latest in 0..10u64,
+        ) {
+            let payloads: Vec<Vec<u8>> = (0..before + after).map(|i| vec![i]).collect();
+            let mut pruned = log(&payloads[..before as usize]);
+            pruned.prune(&RetentionPolicy { keep_latest });
+            for payload in &payloads[before as usize..] {
+                pruned.append(Bytes(payload.clone()));
+                pruned.prune(&RetentionPolicy { keep_latest });
+            }
+
+            let expected = log(&payloads);
+            for (pruned, expected) in pruned.entries().zip(expected.entries()) {
+                prop_assert_eq!(&pruned.digest, &expected.digest);
+            }
+        }
+    }
+}
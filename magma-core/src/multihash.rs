@@ -0,0 +1,470 @@
+//! Self-describing digests, so a log can say which hash it was written with.
+//!
+//! In the [multihash](https://multiformats.io/multihash/) format a digest is written as the
+//! algorithm's code and the digest length, both as unsigned LEB128 varints, followed by the digest.
+//! Events encoded with [DigestFormat::Multihash] write every link and delta digest this way.
+use digest::Digest;
+use snafu::{ensure, OptionExt, Snafu};
+
+/// The longest digest any of the supported algorithms produces.
+pub const MAX_DIGEST_SIZE: usize = 64;
+
+/// How the digests inside an encoded event are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestFormat {
+    /// Only the digest bytes, the algorithm is implied by the event's `D`.
+    Raw,
+    /// Every digest is prefixed with the algorithm's multihash `code` and the digest length.
+    Multihash { code: u64 },
+}
+
+/// A [Digest] with a multihash code.
+pub trait MultihashDigest: Digest {
+    const CODE: u64;
+}
+
+#[cfg(feature = "blake2")]
+impl MultihashDigest for blake2::Blake2b {
+    const CODE: u64 = 0xb240;
+}
+
+#[cfg(feature = "blake3")]
+impl MultihashDigest for blake3::Hasher {
+    const CODE: u64 = 0x1e;
+}
+
+#[cfg(feature = "sha2")]
+impl MultihashDigest for sha2::Sha256 {
+    const CODE: u64 = 0x12;
+}
+
+#[derive(Snafu, Debug)]
+#[snafu(visibility = "pub(crate)")]
+pub enum Error {
+    #[snafu(display("Digest algorithm with multihash code {:#x} is not supported", code))]
+    UnknownDigestAlgorithm { code: u64 },
+
+    #[snafu(display(
+        "Expected a digest with multihash code {:#x} but found {:#x}",
+        expected,
+        actual
+    ))]
+    MixedDigestAlgorithms { expected: u64, actual: u64 },
+
+    #[snafu(display(
+        "Multihash digest had length {}, expected {}",
+        actual_length,
+        expected_length
+    ))]
+    InvalidMultihashLength {
+        expected_length: u64,
+        actual_length: u64,
+    },
+
+    #[snafu(display("Failed to decode an unsigned varint in a multihash"))]
+    DecodeMultihashVarint,
+
+    #[snafu(display("Input ended before the end of the multihash"))]
+    MultihashTooShort,
+}
+
+/// The digest algorithms known at runtime, for reading logs whose algorithm isn't known up front.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DigestAlgorithm {
+    #[cfg(feature = "blake2")]
+    Blake2b,
+    #[cfg(feature = "blake3")]
+    Blake3,
+    #[cfg(feature = "sha2")]
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code {
+            #[cfg(feature = "blake2")]
+            <blake2::Blake2b as MultihashDigest>::CODE => Some(Self::Blake2b),
+            #[cfg(feature = "blake3")]
+            <blake3::Hasher as MultihashDigest>::CODE => Some(Self::Blake3),
+            #[cfg(feature = "sha2")]
+            <sha2::Sha256 as MultihashDigest>::CODE => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> u64 {
+        match *self {
+            #[cfg(feature = "blake2")]
+            Self::Blake2b => <blake2::Blake2b as MultihashDigest>::CODE,
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => <blake3::Hasher as MultihashDigest>::CODE,
+            #[cfg(feature = "sha2")]
+            Self::Sha256 => <sha2::Sha256 as MultihashDigest>::CODE,
+        }
+    }
+
+    pub fn output_size(&self) -> usize {
+        match *self {
+            #[cfg(feature = "blake2")]
+            Self::Blake2b => blake2::Blake2b::output_size(),
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => blake3::Hasher::output_size(),
+            #[cfg(feature = "sha2")]
+            Self::Sha256 => sha2::Sha256::output_size(),
+        }
+    }
+
+    #[cfg(any(feature = "blake2", feature = "blake3", feature = "sha2"))]
+    pub fn digest(&self, data: &[u8]) -> Multihash {
+        match *self {
+            #[cfg(feature = "blake2")]
+            Self::Blake2b => Multihash::new::<blake2::Blake2b>(*self, data),
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => Multihash::new::<blake3::Hasher>(*self, data),
+            #[cfg(feature = "sha2")]
+            Self::Sha256 => Multihash::new::<sha2::Sha256>(*self, data),
+        }
+    }
+
+    /// Reads which algorithm an event encoded with [DigestFormat::Multihash] was written with, so
+    /// the caller can pick the `D` to decode it with.
+    pub fn of_encoded_event(bytes: &[u8]) -> Result<Self, Error> {
+        let first = *bytes.first().context(MultihashTooShort)?;
+        let bytes = if first == 0 {
+            // A root, the delta digest comes right after the flag byte.
+            &bytes[1..]
+        } else {
+            // A child, the predecessor link comes right after the sequence number.
+            varu64::decode(bytes)
+                .map_err(|_| Error::DecodeMultihashVarint)?
+                .1
+        };
+        let (code, _) = decode_varint(bytes)?;
+        Self::from_code(code).context(UnknownDigestAlgorithm { code })
+    }
+}
+
+/// A digest tagged with the algorithm that produced it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Multihash {
+    algorithm: DigestAlgorithm,
+    digest: [u8; MAX_DIGEST_SIZE],
+}
+
+impl Multihash {
+    #[cfg(any(feature = "blake2", feature = "blake3", feature = "sha2"))]
+    fn new<D: Digest>(algorithm: DigestAlgorithm, data: &[u8]) -> Self {
+        let mut digest = [0; MAX_DIGEST_SIZE];
+        digest[..D::output_size()].copy_from_slice(&D::digest(data));
+        Multihash { algorithm, digest }
+    }
+
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        self.algorithm
+    }
+
+    pub fn digest(&self) -> &[u8] {
+        &self.digest[..self.algorithm.output_size()]
+    }
+
+    pub fn encoding_length(&self) -> usize {
+        DigestFormat::Multihash {
+            code: self.algorithm.code(),
+        }
+        .encoded_digest_length(self.algorithm.output_size())
+    }
+
+    /// Writes the multihash to `out`, which must be at least [Multihash::encoding_length] long.
+    pub fn encode(&self, out: &mut [u8]) -> usize {
+        DigestFormat::Multihash {
+            code: self.algorithm.code(),
+        }
+        .encode_digest(self.digest(), out)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let (code, _) = decode_varint(bytes)?;
+        let algorithm =
+            DigestAlgorithm::from_code(code).context(UnknownDigestAlgorithm { code })?;
+        let output_size = algorithm.output_size();
+        let (digest_bytes, rest) = DigestFormat::Multihash { code }
+            .decode_digest(bytes, output_size)?
+            .context(MultihashTooShort)?;
+
+        let mut digest = [0; MAX_DIGEST_SIZE];
+        digest[..output_size].copy_from_slice(digest_bytes);
+        Ok((Multihash { algorithm, digest }, rest))
+    }
+}
+
+/// A digest and the bytes following it.
+type DigestAndRest<'a> = (&'a [u8], &'a [u8]);
+
+impl DigestFormat {
+    pub fn multihash<D: MultihashDigest>() -> Self {
+        Self::Multihash { code: D::CODE }
+    }
+
+    pub(crate) fn encoded_digest_length(&self, digest_size: usize) -> usize {
+        match *self {
+            Self::Raw => digest_size,
+            Self::Multihash { code } => {
+                varint_length(code) + varint_length(digest_size as u64) + digest_size
+            }
+        }
+    }
+
+    /// Writes `digest` to the start of `out`, returning the number of bytes written.
+    pub(crate) fn encode_digest(&self, digest: &[u8], out: &mut [u8]) -> usize {
+        let mut next_byte_num = 0;
+        if let Self::Multihash { code } = *self {
+            next_byte_num += encode_varint(code, &mut out[next_byte_num..]);
+            next_byte_num += encode_varint(digest.len() as u64, &mut out[next_byte_num..]);
+        }
+        out[next_byte_num..next_byte_num + digest.len()].copy_from_slice(digest);
+        next_byte_num + digest.len()
+    }
+
+    /// Reads a digest of `digest_size` bytes from the start of `bytes`, returning `None` if
+    /// `bytes` is too short to hold it.
+    pub(crate) fn decode_digest<'a>(
+        &self,
+        bytes: &'a [u8],
+        digest_size: usize,
+    ) -> Result<Option<DigestAndRest<'a>>, Error> {
+        let bytes = match *self {
+            Self::Raw => bytes,
+            Self::Multihash { code: expected } => {
+                let (actual, bytes) = decode_varint(bytes)?;
+                if actual != expected {
+                    ensure!(
+                        DigestAlgorithm::from_code(actual).is_some(),
+                        UnknownDigestAlgorithm { code: actual }
+                    );
+                    return MixedDigestAlgorithms { expected, actual }.fail();
+                }
+                let (length, bytes) = decode_varint(bytes)?;
+                ensure!(
+                    length == digest_size as u64,
+                    InvalidMultihashLength {
+                        expected_length: digest_size as u64,
+                        actual_length: length
+                    }
+                );
+                bytes
+            }
+        };
+        Ok(bytes
+            .get(..digest_size)
+            .map(|digest| (digest, &bytes[digest_size..])))
+    }
+}
+
+fn varint_length(mut value: u64) -> usize {
+    let mut length = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        length += 1;
+    }
+    length
+}
+
+fn encode_varint(mut value: u64, out: &mut [u8]) -> usize {
+    let mut next_byte_num = 0;
+    while value >= 0x80 {
+        out[next_byte_num] = (value as u8) | 0x80;
+        value >>= 7;
+        next_byte_num += 1;
+    }
+    out[next_byte_num] = value as u8;
+    next_byte_num + 1
+}
+
+/// Decodes a minimally encoded unsigned LEB128 varint of at most 9 bytes, as multihash requires.
+fn decode_varint(bytes: &[u8]) -> Result<(u64, &[u8]), Error> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(9) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            // A trailing zero byte would make the encoding longer than needed.
+            ensure!(i == 0 || *byte != 0, DecodeMultihashVarint);
+            return Ok((value, &bytes[i + 1..]));
+        }
+    }
+    DecodeMultihashVarint.fail()
+}
+
+#[cfg(all(test, feature = "multihash"))]
+mod tests {
+    use super::*;
+    use crate::event::decode::error::Error as DecodeError;
+    use crate::replication::request::{Limits, Ordering, PathLength, Request};
+    use crate::replication::response::Validator;
+    use crate::{Event, NonZeroU64, Output};
+    use blake2::Blake2b;
+    use proptest::prelude::*;
+    use sha2::Sha256;
+
+    fn child<D: Digest>(payload: &[u8], predecessor: &[u8], skip: &[u8]) -> Event<D> {
+        Event::Child {
+            sequence_number: NonZeroU64::new(3).unwrap(),
+            predecessor_event_link: D::digest(predecessor),
+            delta_digest: D::digest(payload),
+            delta_size: payload.len() as u64,
+            skip_event_link: D::digest(skip),
+            skip_delta_digest: D::digest(payload),
+            skip_delta_size: payload.len() as u64,
+            accumulated: None,
+        }
+    }
+
+    fn encode<D: MultihashDigest>(event: &Event<D>) -> Vec<u8> {
+        let mut buffer = vec![0; event.encoding_length_multihash()];
+        event.encode_multihash(&mut buffer).unwrap();
+        buffer
+    }
+
+    proptest! {
+        #[test]
+        fn varint_round_trip(value in any::<u64>().prop_map(|n| n >> 1)) {
+            let mut buffer = [0; 10];
+            let length = encode_varint(value, &mut buffer);
+
+            assert_eq!(length, varint_length(value));
+            assert_eq!(decode_varint(&buffer[..length]).unwrap(), (value, &[][..]));
+        }
+
+        #[test]
+        fn encode_decode_multihash_event(payload in any::<Vec<u8>>(), predecessor in any::<Vec<u8>>(), skip in any::<Vec<u8>>()) {
+            let event = child::<Blake2b>(&payload, &predecessor, &skip);
+            let buffer = encode(&event);
+
+            assert_eq!(Event::<Blake2b>::decode_multihash(&buffer).unwrap(), event);
+            assert_eq!(DigestAlgorithm::of_encoded_event(&buffer).unwrap(), DigestAlgorithm::Blake2b);
+        }
+
+        #[test]
+        fn multihash_decoding_never_panics(bytes in any::<Vec<u8>>()) {
+            let _ = Event::<Sha256>::decode_multihash(&bytes);
+        }
+
+        #[test]
+        fn multihash_round_trip(data in any::<Vec<u8>>()) {
+            for algorithm in [DigestAlgorithm::Blake2b, DigestAlgorithm::Blake3, DigestAlgorithm::Sha256] {
+                let multihash = algorithm.digest(&data);
+                let mut buffer = vec![0; multihash.encoding_length()];
+                multihash.encode(&mut buffer);
+
+                assert_eq!(Multihash::decode(&buffer).unwrap(), (multihash, &[][..]));
+            }
+        }
+    }
+
+    #[test]
+    fn sha256_multihash_matches_the_spec() {
+        let multihash = DigestAlgorithm::Sha256.digest(b"");
+        let mut buffer = [0; 34];
+        multihash.encode(&mut buffer);
+
+        assert_eq!(&buffer[..2], &[0x12, 0x20]);
+        assert_eq!(&buffer[2..], &Sha256::digest(b"")[..]);
+    }
+
+    #[test]
+    fn decoding_rejects_a_different_algorithm() {
+        let buffer = encode(&child::<Sha256>(b"a", b"b", b"c"));
+        let res = Event::<blake3::Hasher>::decode_multihash(&buffer);
+
+        assert!(matches!(
+            res,
+            Err(DecodeError::DecodeMultihash {
+                source: Error::MixedDigestAlgorithms {
+                    expected: 0x1e,
+                    actual: 0x12
+                }
+            })
+        ));
+    }
+
+    #[test]
+    fn decoding_rejects_mixed_algorithms_within_an_event() {
+        // Same length digests, but the skip link claims to be BLAKE3.
+        let mut buffer = encode(&child::<Sha256>(b"a", b"b", b"c"));
+        let skip_link = 1 + 2 * 34 + 1;
+        assert_eq!(buffer[skip_link], 0x12);
+        buffer[skip_link] = 0x1e;
+
+        let res = Event::<Sha256>::decode_multihash(&buffer);
+
+        assert!(matches!(
+            res,
+            Err(DecodeError::DecodeMultihash {
+                source: Error::MixedDigestAlgorithms {
+                    expected: 0x12,
+                    actual: 0x1e
+                }
+            })
+        ));
+    }
+
+    #[test]
+    fn decoding_rejects_unknown_algorithms() {
+        let mut buffer = encode(&Event::<Sha256>::Root {
+            delta_digest: Sha256::digest(b"a"),
+            delta_size: 1,
+        });
+        buffer[1] = 0x13;
+
+        assert!(matches!(
+            Event::<Sha256>::decode_multihash(&buffer),
+            Err(DecodeError::DecodeMultihash {
+                source: Error::UnknownDigestAlgorithm { code: 0x13 }
+            })
+        ));
+        assert!(matches!(
+            DigestAlgorithm::of_encoded_event(&buffer),
+            Err(Error::UnknownDigestAlgorithm { code: 0x13 })
+        ));
+    }
+
+    #[test]
+    fn validator_checks_the_multihash_format() {
+        let root = encode(&Event::<Sha256>::Root {
+            delta_digest: Sha256::digest(b"a"),
+            delta_size: 1,
+        });
+        let request = Request::<Sha256> {
+            new: Sha256::digest(&root),
+            old: None,
+            ordering: Ordering::Descending,
+            path_length: PathLength::ShortestPath,
+            include_values: true,
+            limits: Limits::default(),
+        };
+
+        let mut validator = Validator::new(&request).with_multihash();
+        validator.push(&root, Some(b"a")).unwrap();
+        validator.finish().unwrap();
+
+        // Without the multihash format the prefixed digests are read as raw digest bytes.
+        let mut validator = Validator::new(&request);
+        let res = validator
+            .push(&root, Some(b"a"))
+            .and_then(|_| validator.finish());
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn multihash_events_have_a_different_event_digest() {
+        let event = Event::<Blake2b>::Root {
+            delta_digest: Blake2b::digest(b"a"),
+            delta_size: 1,
+        };
+        let mut raw = vec![0; event.encoding_length()];
+        event.encode(&mut raw).unwrap();
+        let multihash = encode(&event);
+
+        let raw_digest: Output<Blake2b> = Blake2b::digest(&raw);
+        assert_ne!(raw_digest, Blake2b::digest(&multihash));
+    }
+}
@@ -0,0 +1,390 @@
+//! Replication between peers that each serve and request, e.g. a mesh of nodes gossiping logs.
+//!
+//! A [Peer] tells its neighbours about the heads it learns, requests the events it's missing from
+//! the neighbour that told it, and forwards the heads once it has them, so news spreads through
+//! the network and every peer ends up with the same logs. Every response is validated before its
+//! events are added to the store. Peers also regularly send all their heads, so a dropped message
+//! only delays convergence.
+//!
+//! A peer doesn't do any I/O itself, the caller delivers [Message]s with [Peer::handle], calls
+//! [Peer::tick] as time passes and sends what [Peer::take_outgoing] returns. The [simulation] does
+//! this for many peers in one process.
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use digest::{Digest, Output};
+use serde::{Deserialize, Serialize};
+
+use crate::replication::reconciliation::LogHead;
+use crate::replication::request::{Limits, Ordering, PathLength, Request};
+use crate::replication::response::UnvalidatedResponse;
+use crate::store::{self, Store};
+use crate::{CanonicalEncoding, Semigroup};
+
+pub mod simulation;
+
+/// Identifies a peer among the neighbours of another.
+pub type PeerId = usize;
+
+#[derive(Debug)]
+pub enum Message<D: Digest, S: Semigroup + CanonicalEncoding> {
+    /// The sender has these heads.
+    Heads(Vec<LogHead<D>>),
+    Request {
+        id: u64,
+        request: Request<D>,
+    },
+    /// The answer to the request with the same id.
+    Response {
+        id: u64,
+        response: UnvalidatedResponse<D, S>,
+    },
+}
+
+/// Timings, in the units of time passed to [Peer::tick].
+#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
+pub struct Config {
+    /// How often to send every head to every neighbour.
+    pub gossip_interval: u64,
+    /// How long to wait for a response before another neighbour can be asked instead.
+    pub request_timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            gossip_interval: 10,
+            request_timeout: 20,
+        }
+    }
+}
+
+/// Counts of what a peer received, e.g. to check a simulation.
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq)]
+pub struct PeerStats {
+    pub messages: u64,
+    /// Responses that failed validation, or that answered no request.
+    pub rejected: u64,
+    /// Events added to the store from responses.
+    pub fetched: u64,
+}
+
+#[derive(Debug)]
+struct Pending<D: Digest> {
+    root: Output<D>,
+    request: Request<D>,
+    sent_at: u64,
+}
+
+/// A node that serves its store to its neighbours and fetches what they have.
+#[derive(Debug)]
+pub struct Peer<D: Digest, S: Semigroup + CanonicalEncoding> {
+    store: Store<D, S>,
+    neighbours: Vec<PeerId>,
+    config: Config,
+    now: u64,
+    last_gossip: u64,
+    next_request_id: u64,
+    /// The requests without a response yet, by the neighbour they were sent to and their id.
+    pending: BTreeMap<(PeerId, u64), Pending<D>>,
+    /// The latest head we heard of for every log we're behind on, and who told us.
+    wanted: BTreeMap<Output<D>, (PeerId, LogHead<D>)>,
+    outgoing: Vec<(PeerId, Message<D, S>)>,
+    stats: PeerStats,
+}
+
+impl<D, S> Peer<D, S>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding + Clone,
+{
+    pub fn new(store: Store<D, S>, config: Config) -> Self {
+        Peer {
+            store,
+            neighbours: Vec::new(),
+            config,
+            now: 0,
+            last_gossip: 0,
+            next_request_id: 0,
+            pending: BTreeMap::new(),
+            wanted: BTreeMap::new(),
+            outgoing: Vec::new(),
+            stats: PeerStats::default(),
+        }
+    }
+
+    pub fn store(&self) -> &Store<D, S> {
+        &self.store
+    }
+
+    pub fn stats(&self) -> PeerStats {
+        self.stats
+    }
+
+    pub fn neighbours(&self) -> &[PeerId] {
+        &self.neighbours
+    }
+
+    pub fn add_neighbour(&mut self, neighbour: PeerId) {
+        if !self.neighbours.contains(&neighbour) {
+            self.neighbours.push(neighbour);
+        }
+    }
+
+    /// The messages to send since the last call, with the neighbour to send each to.
+    pub fn take_outgoing(&mut self) -> Vec<(PeerId, Message<D, S>)> {
+        core::mem::take(&mut self.outgoing)
+    }
+
+    /// Starts a log as its author, see [Store::create].
+    pub fn create(&mut self, payload: S) -> Result<Output<D>, store::Error> {
+        let root = self.store.create(payload)?.digest.clone();
+        self.announce(&root, None);
+        Ok(root)
+    }
+
+    /// Appends to a log as its author, see [Store::append].
+    pub fn append(&mut self, root: &Output<D>, payload: S) -> Result<(), store::Error> {
+        self.store.append(root, payload)?;
+        self.announce(root, None);
+        Ok(())
+    }
+
+    /// Lets time pass: gossips every head when it's time to, and gives up on old requests.
+    pub fn tick(&mut self, now: u64) {
+        self.now = now;
+        let timeout = self.config.request_timeout;
+        let mut expired = Vec::new();
+        self.pending.retain(|_, pending| {
+            let waiting = now.saturating_sub(pending.sent_at) < timeout;
+            if !waiting {
+                expired.push(pending.root.clone());
+            }
+            waiting
+        });
+        for root in expired {
+            self.fetch_wanted(&root);
+        }
+
+        if now.saturating_sub(self.last_gossip) >= self.config.gossip_interval {
+            self.last_gossip = now;
+            let heads: Vec<_> = self.store.heads().map(LogHead::from).collect();
+            if !heads.is_empty() {
+                for neighbour in self.neighbours.clone() {
+                    self.send(neighbour, Message::Heads(heads.clone()));
+                }
+            }
+        }
+    }
+
+    /// Handles a message from a neighbour.
+    pub fn handle(&mut self, from: PeerId, message: Message<D, S>) {
+        self.stats.messages += 1;
+        match message {
+            Message::Heads(heads) => {
+                for head in heads {
+                    self.fetch(from, head);
+                }
+            }
+            Message::Request { id, request } => {
+                let response = self.store.respond(&request).into();
+                self.send(from, Message::Response { id, response });
+            }
+            Message::Response { id, response } => match self.pending.remove(&(from, id)) {
+                Some(pending) => self.apply(from, pending, response),
+                None => self.stats.rejected += 1,
+            },
+        }
+    }
+
+    /// Requests the events up to `head` from `from`, unless we have them or already asked.
+    fn fetch(&mut self, from: PeerId, head: LogHead<D>) {
+        let newer = match self.wanted.get(&head.root) {
+            Some((_, wanted)) => wanted.sequence_number < head.sequence_number,
+            None => true,
+        };
+        let root = head.root.clone();
+        if newer {
+            self.wanted.insert(root.clone(), (from, head));
+        }
+        if !self.pending.values().any(|pending| pending.root == root) {
+            self.fetch_wanted(&root);
+        }
+    }
+
+    /// Requests the latest head we heard of for the log with root `root`, if we're behind.
+    fn fetch_wanted(&mut self, root: &Output<D>) {
+        let (from, head) = match self.wanted.get(root) {
+            Some((from, head)) => (*from, head.clone()),
+            None => return,
+        };
+        let ours = self.store.log(root).and_then(|log| log.head());
+        if ours.map_or(0, |ours| ours.sequence_number()) >= head.sequence_number {
+            self.wanted.remove(root);
+            return;
+        }
+        let request = Request {
+            new: head.head,
+            old: ours.map(|ours| ours.digest.clone()),
+            ordering: Ordering::Ascending,
+            path_length: PathLength::LongestPath,
+            include_values: true,
+            limits: Limits::default(),
+        };
+        self.request(from, root.clone(), request);
+    }
+
+    fn request(&mut self, to: PeerId, root: Output<D>, request: Request<D>) {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        let pending = Pending {
+            root,
+            request: request.clone(),
+            sent_at: self.now,
+        };
+        self.pending.insert((to, id), pending);
+        self.send(to, Message::Request { id, request });
+    }
+
+    /// Adds the events of a validated response to the store and forwards the new head.
+    fn apply(&mut self, from: PeerId, pending: Pending<D>, response: UnvalidatedResponse<D, S>) {
+        let valid = match response.try_into_valid_response(pending.request) {
+            Ok(valid) => valid,
+            Err(_) => {
+                self.stats.rejected += 1;
+                return;
+            }
+        };
+        let mut encoded = Vec::new();
+        let mut fetched = 0;
+        for (event, payload) in valid.events.iter().zip(valid.values.iter()) {
+            encoded.resize(event.encoding_length(), 0);
+            event
+                .encode(&mut encoded)
+                .expect("Encoding event failed unexpectedly");
+            if self.store.find(&D::digest(&encoded)).is_some() {
+                continue;
+            }
+            // Without the payload the store can't take the event, the next gossip round will
+            // ask again.
+            let pushed = payload
+                .clone()
+                .map(|payload| self.store.push(&encoded, payload));
+            match pushed {
+                Some(Ok(_)) => fetched += 1,
+                _ => break,
+            }
+        }
+        self.stats.fetched += fetched;
+        match valid.continuation.clone() {
+            Some(continuation) => self.request(from, pending.root.clone(), continuation),
+            None => self.fetch_wanted(&pending.root),
+        }
+        if fetched > 0 {
+            self.announce(&pending.root, Some(from));
+        }
+    }
+
+    /// Tells every neighbour but `except` about the head of the log with root `root`.
+    fn announce(&mut self, root: &Output<D>, except: Option<PeerId>) {
+        let head = match self.store.log(root).and_then(|log| log.head()) {
+            Some(head) => LogHead {
+                root: root.clone(),
+                head: head.digest.clone(),
+                sequence_number: head.sequence_number(),
+            },
+            None => return,
+        };
+        for neighbour in self.neighbours.clone() {
+            if Some(neighbour) != except {
+                self.send(neighbour, Message::Heads(vec![head.clone()]));
+            }
+        }
+    }
+
+    fn send(&mut self, to: PeerId, message: Message<D, S>) {
+        self.outgoing.push((to, message));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::tests::Bytes;
+    use crate::replication::response::EventPayloadPair;
+    use blake2::Blake2b;
+
+    type MyPeer = Peer<Blake2b, Bytes>;
+
+    /// Two neighbours, the first with a log of three events.
+    fn peers() -> (MyPeer, MyPeer) {
+        let mut author = MyPeer::new(Store::new(), Config::default());
+        let mut reader = MyPeer::new(Store::new(), Config::default());
+        author.add_neighbour(1);
+        reader.add_neighbour(0);
+        let root = author.create(Bytes(vec![0])).unwrap();
+        author.append(&root, Bytes(vec![1])).unwrap();
+        author.append(&root, Bytes(vec![2])).unwrap();
+        (author, reader)
+    }
+
+    /// Delivers the messages `from` sent to `to`, returning how many there were.
+    fn deliver(from: (PeerId, &mut MyPeer), to: &mut MyPeer) -> usize {
+        let outgoing = from.1.take_outgoing();
+        let count = outgoing.len();
+        for (_, message) in outgoing {
+            to.handle(from.0, message);
+        }
+        count
+    }
+
+    #[test]
+    fn fetches_announced_heads() {
+        let (mut author, mut reader) = peers();
+
+        while deliver((0, &mut author), &mut reader) + deliver((1, &mut reader), &mut author) > 0 {}
+
+        assert!(author
+            .store()
+            .heads()
+            .map(|head| head.head)
+            .eq(reader.store().heads().map(|head| head.head)));
+        assert_eq!(reader.stats().fetched, 3);
+    }
+
+    #[test]
+    fn rejects_tampered_and_unrequested_responses() {
+        let (mut author, mut reader) = peers();
+        deliver((0, &mut author), &mut reader);
+
+        let mut requests = reader.take_outgoing();
+        assert_eq!(requests.len(), 1);
+        for (_, request) in requests.drain(..) {
+            author.handle(1, request);
+        }
+        for (_, message) in author.take_outgoing() {
+            let message = match message {
+                Message::Response {
+                    id,
+                    response: UnvalidatedResponse::Data(mut pairs),
+                } => {
+                    pairs[0].payload = Some(Bytes(vec![9]));
+                    Message::Response {
+                        id,
+                        response: UnvalidatedResponse::Data(pairs),
+                    }
+                }
+                message => message,
+            };
+            reader.handle(0, message);
+        }
+        reader.handle(
+            0,
+            Message::Response {
+                id: 7,
+                response: UnvalidatedResponse::Data(Vec::<EventPayloadPair<_, _>>::new()),
+            },
+        );
+
+        assert!(reader.store().is_empty());
+        assert_eq!(reader.stats().rejected, 2);
+    }
+}
@@ -0,0 +1,317 @@
+//! A deterministic network of [Peer]s in one process, for testing replication under message
+//! drops, delays and partitions.
+//!
+//! Time advances in steps. Every message is delivered a random number of steps after it is sent,
+//! unless it is dropped or crosses a partition. The randomness comes from a seed, so a run can be
+//! repeated exactly.
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use digest::{Digest, Output};
+use serde::{Deserialize, Serialize};
+
+use super::{Message, Peer, PeerId};
+use crate::{CanonicalEncoding, Semigroup};
+
+/// How unreliable the network is.
+#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
+pub struct Conditions {
+    /// The chance of a message being dropped, in thousandths.
+    pub drop_per_mille: u32,
+    /// The fewest steps a message takes, at least one.
+    pub min_delay: u64,
+    /// The most steps a message takes.
+    pub max_delay: u64,
+}
+
+impl Default for Conditions {
+    /// A network that delivers every message in the next step.
+    fn default() -> Self {
+        Conditions {
+            drop_per_mille: 0,
+            min_delay: 1,
+            max_delay: 1,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq)]
+pub struct NetworkStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub delivered: u64,
+}
+
+/// SplitMix64, which is plenty for picking drops and delays.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number from `low` up to and including `high`.
+    fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low + 1)
+    }
+}
+
+#[derive(Debug)]
+struct Envelope<D: Digest, S: Semigroup + CanonicalEncoding> {
+    from: PeerId,
+    to: PeerId,
+    message: Message<D, S>,
+}
+
+/// Peers connected by simulated links.
+#[derive(Debug)]
+pub struct Network<D: Digest, S: Semigroup + CanonicalEncoding> {
+    peers: Vec<Peer<D, S>>,
+    conditions: Conditions,
+    rng: Rng,
+    now: u64,
+    /// The messages on their way, by the step they arrive at and then the order they were sent in.
+    in_flight: BTreeMap<(u64, u64), Envelope<D, S>>,
+    /// The side of the partition of every peer, if the network is partitioned.
+    sides: Option<Vec<bool>>,
+    stats: NetworkStats,
+}
+
+impl<D, S> Network<D, S>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding + Clone,
+{
+    pub fn new(seed: u64, conditions: Conditions) -> Self {
+        Network {
+            peers: Vec::new(),
+            conditions,
+            rng: Rng(seed),
+            now: 0,
+            in_flight: BTreeMap::new(),
+            sides: None,
+            stats: NetworkStats::default(),
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: Peer<D, S>) -> PeerId {
+        self.peers.push(peer);
+        self.peers.len() - 1
+    }
+
+    /// Makes two peers neighbours of each other.
+    pub fn connect(&mut self, a: PeerId, b: PeerId) {
+        self.peers[a].add_neighbour(b);
+        self.peers[b].add_neighbour(a);
+    }
+
+    pub fn peer(&self, id: PeerId) -> &Peer<D, S> {
+        &self.peers[id]
+    }
+
+    /// The peer, e.g. to author events. What it sends is picked up with the next step.
+    pub fn peer_mut(&mut self, id: PeerId) -> &mut Peer<D, S> {
+        &mut self.peers[id]
+    }
+
+    pub fn peers(&self) -> &[Peer<D, S>] {
+        &self.peers
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+
+    /// Drops every message between `side` and the other peers until [heal](Network::heal).
+    pub fn partition(&mut self, side: &[PeerId]) {
+        let mut sides = vec![false; self.peers.len()];
+        for id in side {
+            sides[*id] = true;
+        }
+        self.sides = Some(sides);
+    }
+
+    pub fn heal(&mut self) {
+        self.sides = None;
+    }
+
+    /// Advances time by one step, delivering the messages due by then.
+    pub fn step(&mut self) {
+        self.now += 1;
+        for id in 0..self.peers.len() {
+            self.peers[id].tick(self.now);
+            self.collect(id);
+        }
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > self.now {
+                break;
+            }
+            let Envelope { from, to, message } = entry.remove();
+            if self.crosses_partition(from, to) {
+                self.stats.dropped += 1;
+                continue;
+            }
+            self.stats.delivered += 1;
+            self.peers[to].handle(from, message);
+            self.collect(to);
+        }
+    }
+
+    pub fn run(&mut self, steps: u64) {
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    /// Steps until every peer has the same heads, and returns the step that happened at, or
+    /// `None` if it didn't within `steps`.
+    pub fn run_until_converged(&mut self, steps: u64) -> Option<u64> {
+        for _ in 0..steps {
+            self.step();
+            if self.converged() {
+                return Some(self.now);
+            }
+        }
+        None
+    }
+
+    /// Whether every peer has the same logs with the same heads.
+    pub fn converged(&self) -> bool {
+        let heads = |peer: &Peer<D, S>| -> BTreeMap<Output<D>, Output<D>> {
+            peer.store()
+                .heads()
+                .map(|head| (head.root, head.head))
+                .collect()
+        };
+        let mut peers = self.peers.iter();
+        match peers.next() {
+            Some(first) => {
+                let first = heads(first);
+                peers.all(|peer| heads(peer) == first)
+            }
+            None => true,
+        }
+    }
+
+    /// Puts what a peer sent on the network.
+    fn collect(&mut self, from: PeerId) {
+        for (to, message) in self.peers[from].take_outgoing() {
+            self.stats.sent += 1;
+            if to >= self.peers.len()
+                || self.rng.between(0, 999) < u64::from(self.conditions.drop_per_mille)
+            {
+                self.stats.dropped += 1;
+                continue;
+            }
+            let min_delay = self.conditions.min_delay.max(1);
+            let delay = self
+                .rng
+                .between(min_delay, self.conditions.max_delay.max(min_delay));
+            let key = (self.now + delay, self.stats.sent);
+            self.in_flight.insert(key, Envelope { from, to, message });
+        }
+    }
+
+    fn crosses_partition(&self, from: PeerId, to: PeerId) -> bool {
+        self.sides
+            .as_ref()
+            .is_some_and(|sides| sides.get(from) != sides.get(to))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::tests::Bytes;
+    use crate::peer::Config;
+    use crate::store::Store;
+    use blake2::Blake2b;
+
+    type MyNetwork = Network<Blake2b, Bytes>;
+
+    fn network(seed: u64, conditions: Conditions, peers: usize) -> MyNetwork {
+        let mut network = MyNetwork::new(seed, conditions);
+        for _ in 0..peers {
+            network.add_peer(Peer::new(Store::new(), Config::default()));
+        }
+        network
+    }
+
+    /// Authors a log of `length` events on a peer.
+    fn author(network: &mut MyNetwork, id: PeerId, length: u8) -> Output<Blake2b> {
+        let peer = network.peer_mut(id);
+        let root = peer.create(Bytes(vec![id as u8])).unwrap();
+        for payload in 1..length {
+            peer.append(&root, Bytes(vec![payload])).unwrap();
+        }
+        root
+    }
+
+    fn lossy() -> Conditions {
+        Conditions {
+            drop_per_mille: 200,
+            min_delay: 1,
+            max_delay: 5,
+        }
+    }
+
+    #[test]
+    fn converges_over_a_lossy_line() {
+        let mut network = network(7, lossy(), 6);
+        for id in 1..6 {
+            network.connect(id - 1, id);
+        }
+        let first = author(&mut network, 0, 10);
+        let last = author(&mut network, 5, 10);
+
+        assert!(network.run_until_converged(1000).is_some());
+        for peer in network.peers() {
+            for root in [first, last].iter() {
+                assert_eq!(peer.store().log(root).unwrap().len(), 10);
+            }
+        }
+        assert!(network.stats().dropped > 0);
+    }
+
+    #[test]
+    fn partitioned_peers_converge_after_healing() {
+        let mut network = network(3, lossy(), 4);
+        for id in 0..4 {
+            network.connect(id, (id + 1) % 4);
+        }
+        network.partition(&[0, 1]);
+        let left = author(&mut network, 0, 5);
+        let right = author(&mut network, 3, 5);
+
+        network.run(200);
+        assert!(!network.converged());
+        assert!(network.peer(1).store().log(&left).is_some());
+        assert!(network.peer(1).store().log(&right).is_none());
+
+        network.heal();
+        assert!(network.run_until_converged(1000).is_some());
+    }
+
+    #[test]
+    fn runs_with_the_same_seed_are_the_same() {
+        let run = |seed| {
+            let mut network = network(seed, lossy(), 5);
+            for id in 0..5 {
+                network.connect(id, (id + 2) % 5);
+            }
+            author(&mut network, 0, 20);
+            author(&mut network, 4, 20);
+            let converged = network.run_until_converged(1000);
+            (converged, network.stats())
+        };
+
+        assert_eq!(run(11), run(11));
+    }
+}
@@ -0,0 +1,141 @@
+use alloc::{vec, vec::Vec};
+use core::convert::TryFrom;
+use digest::{Digest, Output};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, AsErrorSource, ResultExt, Snafu};
+
+use crate::replication::response::dto::{Compression, Error as ResponseDtoError, Response};
+use crate::{CanonicalEncoding, Semigroup};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Checkpoint {
+    pub event: Vec<u8>,
+    pub value: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// A Data Transfer Object representation of a [super::CheckpointResponse].
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CheckpointResponse {
+    pub checkpoint: Option<Checkpoint>,
+    pub path: Response,
+}
+
+#[derive(Snafu, Debug)]
+pub enum Error<E: AsErrorSource + core::fmt::Display> {
+    EventWasIncorrectLength,
+    DecodeValue {
+        source: E,
+    },
+    DecodeEvent {
+        source: crate::event::decode::error::Error,
+    },
+    DecodePayload {
+        source: E,
+    },
+    MismatchedReference,
+    MissingPayload,
+    UnsupportedCompression,
+    InvalidCompressedPayload,
+    PayloadTooLarge,
+    PayloadSizeMismatch,
+}
+
+impl<E: AsErrorSource + core::fmt::Display> From<ResponseDtoError<E>> for Error<E> {
+    fn from(error: ResponseDtoError<E>) -> Self {
+        match error {
+            ResponseDtoError::DecodeEvent { source } => Error::DecodeEvent { source },
+            ResponseDtoError::DecodePayload { source } => Error::DecodePayload { source },
+            ResponseDtoError::MismatchedReference => Error::MismatchedReference,
+            ResponseDtoError::MissingPayload => Error::MissingPayload,
+            ResponseDtoError::UnsupportedCompression => Error::UnsupportedCompression,
+            ResponseDtoError::InvalidCompressedPayload => Error::InvalidCompressedPayload,
+            ResponseDtoError::PayloadTooLarge => Error::PayloadTooLarge,
+            ResponseDtoError::PayloadSizeMismatch => Error::PayloadSizeMismatch,
+        }
+    }
+}
+
+impl<D, S> TryFrom<Checkpoint> for super::Checkpoint<D, S>
+where
+    D: Digest,
+    S: CanonicalEncoding,
+    <S as CanonicalEncoding>::Error: AsErrorSource + core::fmt::Display,
+{
+    type Error = Error<S::Error>;
+
+    fn try_from(checkpoint: Checkpoint) -> Result<Self, Self::Error> {
+        ensure!(
+            checkpoint.event.len() == D::output_size(),
+            EventWasIncorrectLength
+        );
+        let (value, _) = S::decode(&checkpoint.value).context(DecodeValue)?;
+        Ok(Self {
+            event: <&Output<D>>::from(checkpoint.event.as_slice()).clone(),
+            value,
+            signature: checkpoint.signature,
+        })
+    }
+}
+
+impl<D, S> From<super::Checkpoint<D, S>> for Checkpoint
+where
+    D: Digest,
+    S: CanonicalEncoding,
+{
+    fn from(checkpoint: super::Checkpoint<D, S>) -> Self {
+        let mut value = vec![0; checkpoint.value.encoding_length()];
+        checkpoint.value.encode(&mut value).expect(
+            "Encoding Semigroup value failed unexpectedly. Is payload.encoding_length buggy?",
+        );
+        Checkpoint {
+            event: checkpoint.event.to_vec(),
+            value,
+            signature: checkpoint.signature,
+        }
+    }
+}
+
+impl<D, S> TryFrom<CheckpointResponse> for super::UnvalidatedCheckpointResponse<D, S>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding,
+    <S as CanonicalEncoding>::Error: AsErrorSource + core::fmt::Display,
+{
+    type Error = Error<S::Error>;
+
+    fn try_from(response: CheckpointResponse) -> Result<Self, Self::Error> {
+        Ok(Self {
+            checkpoint: response.checkpoint.map(TryFrom::try_from).transpose()?,
+            path: TryFrom::try_from(response.path)?,
+        })
+    }
+}
+
+impl<D, S> From<super::CheckpointResponse<D, S>> for CheckpointResponse
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding,
+{
+    fn from(response: super::CheckpointResponse<D, S>) -> Self {
+        Self::with_compression(response, None)
+    }
+}
+
+impl CheckpointResponse {
+    /// Encodes a response, compressing the payloads of the path with `compression` where that
+    /// makes them smaller.
+    pub fn with_compression<D, S>(
+        response: super::CheckpointResponse<D, S>,
+        compression: Option<Compression>,
+    ) -> Self
+    where
+        D: Digest,
+        S: Semigroup + CanonicalEncoding,
+    {
+        CheckpointResponse {
+            checkpoint: response.checkpoint.map(Into::into),
+            path: Response::with_compression(response.path, compression),
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! Checkpoints of accumulated values, so clients joining late don't have to fold from the root.
+//!
+//! Events don't have to commit to their accumulated value, and the checkpoint event is not sent
+//! anyway, so a checkpoint is vouched for by a signature over its
+//! [statement](Checkpoint::statement). The signature scheme is up to the application, the author
+//! signs with a [CheckpointSigner] and clients check with a [CheckpointVerifier]. Events on the
+//! path that do commit to their accumulated value are checked as the values are folded.
+use alloc::{vec, vec::Vec};
+use digest::{Digest, Output};
+use snafu::{ensure, ResultExt, Snafu};
+
+use crate::replication::request::{CheckpointRequest, Limits, Ordering, Request};
+use crate::replication::response::{
+    Response, ResponseValidationError, UnvalidatedResponse, ValidResponse,
+};
+use crate::{CanonicalEncoding, Semigroup};
+
+pub mod dto;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("The checkpoint signature is invalid"))]
+    InvalidSignature,
+    #[snafu(display("The path from the checkpoint is invalid: {}", source))]
+    InvalidPath { source: ResponseValidationError },
+    #[snafu(display("The path from the checkpoint doesn't reach new"))]
+    PartialPath,
+}
+
+/// Signs checkpoint statements, e.g. with the log author's key.
+pub trait CheckpointSigner {
+    fn sign(&self, statement: &[u8]) -> Vec<u8>;
+}
+
+/// Checks signatures made by a [CheckpointSigner] the client trusts.
+pub trait CheckpointVerifier {
+    fn verify(&self, statement: &[u8], signature: &[u8]) -> bool;
+}
+
+/// The accumulated value of a log up to and including an event.
+#[derive(Debug)]
+pub struct Checkpoint<D: Digest, S> {
+    /// The hash of the event the value is accumulated up to.
+    pub event: Output<D>,
+    /// The combination of the payloads of every event up to and including `event`.
+    pub value: S,
+    /// The signature over the statement of the checkpoint.
+    pub signature: Vec<u8>,
+}
+
+// Not derived, which would require `D: Clone` even though only `Output<D>` is cloned.
+impl<D: Digest, S: Clone> Clone for Checkpoint<D, S> {
+    fn clone(&self) -> Self {
+        Checkpoint {
+            event: self.event.clone(),
+            value: self.value.clone(),
+            signature: self.signature.clone(),
+        }
+    }
+}
+
+impl<D: Digest, S: CanonicalEncoding> Checkpoint<D, S> {
+    pub fn sign(event: Output<D>, value: S, signer: &impl CheckpointSigner) -> Self {
+        let signature = signer.sign(&statement::<D, S>(&event, &value));
+        Checkpoint {
+            event,
+            value,
+            signature,
+        }
+    }
+
+    /// The bytes that are signed: the event digest, then the digest of the encoded value and its
+    /// length as a varu64.
+    pub fn statement(&self) -> Vec<u8> {
+        statement::<D, S>(&self.event, &self.value)
+    }
+
+    pub fn verify(&self, verifier: &impl CheckpointVerifier) -> Result<(), Error> {
+        ensure!(
+            verifier.verify(&self.statement(), &self.signature),
+            InvalidSignature
+        );
+        Ok(())
+    }
+}
+
+fn statement<D: Digest, S: CanonicalEncoding>(event: &Output<D>, value: &S) -> Vec<u8> {
+    let mut encoded = vec![0; value.encoding_length()];
+    value
+        .encode(&mut encoded)
+        .expect("Encoding Semigroup value failed unexpectedly. Is payload.encoding_length buggy?");
+    let size = encoded.len() as u64;
+
+    let mut statement = vec![0; 2 * D::output_size() + varu64::encoding_length(size)];
+    statement[..D::output_size()].copy_from_slice(event);
+    statement[D::output_size()..2 * D::output_size()].copy_from_slice(&D::digest(&encoded));
+    varu64::encode(size, &mut statement[2 * D::output_size()..]);
+    statement
+}
+
+/// The request for the path from `checkpoint` up to the `new` of a [CheckpointRequest].
+pub(crate) fn path_request<D: Digest>(
+    request: &CheckpointRequest<D>,
+    checkpoint: Option<&Output<D>>,
+) -> Request<D> {
+    Request {
+        new: request.new.clone(),
+        old: checkpoint.cloned(),
+        ordering: Ordering::Ascending,
+        path_length: request.path_length,
+        include_values: true,
+        limits: Limits::default(),
+    }
+}
+
+/// The answer to a [CheckpointRequest].
+#[derive(Debug)]
+pub struct CheckpointResponse<D: Digest, S: Semigroup + CanonicalEncoding> {
+    /// The latest checkpoint below `new`, if the server has one.
+    pub checkpoint: Option<Checkpoint<D, S>>,
+    /// The path from the checkpoint, or the root, up to `new`.
+    pub path: Response<D, S>,
+}
+
+#[derive(Debug)]
+pub struct UnvalidatedCheckpointResponse<D: Digest, S: Semigroup + CanonicalEncoding> {
+    pub checkpoint: Option<Checkpoint<D, S>>,
+    pub path: UnvalidatedResponse<D, S>,
+}
+
+/// Treats a response built in the same process as untrusted, e.g. to test a server.
+impl<D: Digest, S: Semigroup + CanonicalEncoding> From<CheckpointResponse<D, S>>
+    for UnvalidatedCheckpointResponse<D, S>
+{
+    fn from(response: CheckpointResponse<D, S>) -> Self {
+        UnvalidatedCheckpointResponse {
+            checkpoint: response.checkpoint,
+            path: response.path.into(),
+        }
+    }
+}
+
+impl<D: Digest, S: Semigroup + CanonicalEncoding + Clone> UnvalidatedCheckpointResponse<D, S> {
+    /// Checks the checkpoint signature and the path from it, and folds the values onto it.
+    ///
+    /// The path has to reach `new`, a server answers with [Response::TooLarge] rather than part
+    /// of it.
+    pub fn try_into_valid_response(
+        self,
+        request: CheckpointRequest<D>,
+        verifier: &impl CheckpointVerifier,
+    ) -> Result<ValidCheckpointResponse<D, S>, Error> {
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.verify(verifier)?;
+        }
+        let path_request = path_request(
+            &request,
+            self.checkpoint.as_ref().map(|checkpoint| &checkpoint.event),
+        );
+        let path = self
+            .path
+            .try_into_valid_response(path_request)
+            .context(InvalidPath)?;
+        ensure!(path.continuation.is_none(), PartialPath);
+
+        let value = path
+            .fold(
+                self.checkpoint
+                    .as_ref()
+                    .map(|checkpoint| checkpoint.value.clone()),
+            )
+            .context(InvalidPath)?;
+
+        Ok(ValidCheckpointResponse {
+            checkpoint: self.checkpoint,
+            path,
+            value,
+        })
+    }
+}
+
+#[readonly::make]
+#[derive(Debug)]
+pub struct ValidCheckpointResponse<D: Digest, S: Semigroup> {
+    pub checkpoint: Option<Checkpoint<D, S>>,
+    pub path: ValidResponse<D, S>,
+    /// The accumulated value at `new`.
+    pub value: S,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::tests::{log, Bytes};
+    use crate::log::{self, RetentionPolicy};
+    use crate::replication::request::PathLength;
+    use blake2::Blake2b;
+    use core::convert::TryInto;
+
+    /// Signs with a keyed hash, which is enough to tell keys apart in tests.
+    struct Key(&'static [u8]);
+
+    impl CheckpointSigner for Key {
+        fn sign(&self, statement: &[u8]) -> Vec<u8> {
+            Blake2b::digest(&[self.0, statement].concat()).to_vec()
+        }
+    }
+
+    impl CheckpointVerifier for Key {
+        fn verify(&self, statement: &[u8], signature: &[u8]) -> bool {
+            self.sign(statement) == signature
+        }
+    }
+
+    fn payloads(length: u8) -> Vec<Vec<u8>> {
+        (0..length).map(|i| vec![i]).collect()
+    }
+
+    fn checkpoint(
+        log: &log::Log<Blake2b, Bytes>,
+        sequence_number: u64,
+    ) -> Checkpoint<Blake2b, Bytes> {
+        Checkpoint::sign(
+            log.get(sequence_number).unwrap().digest,
+            log.combined_payload(0, sequence_number).unwrap(),
+            &Key(b"author"),
+        )
+    }
+
+    fn request(log: &log::Log<Blake2b, Bytes>) -> CheckpointRequest<Blake2b> {
+        CheckpointRequest {
+            new: log.head().unwrap().digest,
+            path_length: PathLength::ShortestPath,
+        }
+    }
+
+    #[test]
+    fn joining_late_folds_onto_the_checkpoint() {
+        let mut log = log(&payloads(20));
+        let expected = log.combined_payload(0, 20).unwrap();
+        log.add_checkpoint(checkpoint(&log, 13)).unwrap();
+        log.prune(&RetentionPolicy { keep_latest: 7 });
+
+        let response: dto::CheckpointResponse = log.respond_checkpoint(&request(&log)).into();
+        let response: UnvalidatedCheckpointResponse<Blake2b, Bytes> = response.try_into().unwrap();
+        let valid = response
+            .try_into_valid_response(request(&log), &Key(b"author"))
+            .unwrap();
+
+        assert_eq!(valid.value, expected);
+        // Event 17 skip links to the checkpoint.
+        assert_eq!(valid.path.events[0].sequence_number().get(), 17);
+    }
+
+    #[test]
+    fn without_a_checkpoint_folds_from_the_root() {
+        let log = log(&payloads(5));
+
+        let response = UnvalidatedCheckpointResponse::from(log.respond_checkpoint(&request(&log)));
+        let valid = response
+            .try_into_valid_response(request(&log), &Key(b"author"))
+            .unwrap();
+
+        assert!(valid.checkpoint.is_none());
+        assert_eq!(valid.value, log.combined_payload(0, 5).unwrap());
+    }
+
+    #[test]
+    fn rejects_partial_paths() {
+        let log = log(&payloads(5));
+        let path_request = Request {
+            limits: Limits {
+                max_events: Some(2),
+                max_payload_bytes: None,
+            },
+            ..path_request(&request(&log), None)
+        };
+
+        let response = UnvalidatedCheckpointResponse {
+            checkpoint: None,
+            path: log.respond(&path_request).into(),
+        };
+        assert!(matches!(response.path, UnvalidatedResponse::Partial(_)));
+        assert!(matches!(
+            response.try_into_valid_response(request(&log), &Key(b"author")),
+            Err(Error::PartialPath)
+        ));
+    }
+
+    #[test]
+    fn rejects_checkpoints_not_signed_by_the_author() {
+        let mut log = log(&payloads(5));
+        log.add_checkpoint(checkpoint(&log, 3)).unwrap();
+
+        let response = UnvalidatedCheckpointResponse::from(log.respond_checkpoint(&request(&log)));
+        assert!(matches!(
+            response.try_into_valid_response(request(&log), &Key(b"someone else")),
+            Err(Error::InvalidSignature)
+        ));
+
+        let mut response =
+            UnvalidatedCheckpointResponse::from(log.respond_checkpoint(&request(&log)));
+        response.checkpoint.as_mut().unwrap().value = Bytes(vec![9]);
+        assert!(matches!(
+            response.try_into_valid_response(request(&log), &Key(b"author")),
+            Err(Error::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn log_rejects_checkpoints_that_do_not_match_its_payloads() {
+        let mut log = log(&payloads(5));
+        let checkpoint =
+            Checkpoint::sign(log.get(3).unwrap().digest, Bytes(vec![9]), &Key(b"author"));
+
+        assert!(matches!(
+            log.add_checkpoint(checkpoint),
+            Err(log::Error::CheckpointValueDidNotMatchPayloads)
+        ));
+    }
+}
@@ -1,5 +1,9 @@
-#[cfg(any(feature = "alloc", feature = "std"))]
+#[cfg(feature = "alloc")]
+pub mod checkpoint;
+pub mod path;
+#[cfg(feature = "alloc")]
+pub mod reconciliation;
 pub mod request;
-
-#[cfg(any(feature = "alloc", feature = "std"))]
 pub mod response;
+#[cfg(feature = "alloc")]
+pub mod subscription;
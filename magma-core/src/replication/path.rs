@@ -0,0 +1,167 @@
+//! Which events lie on a path between two events of a log.
+//!
+//! Event `n` of a log skip links to event [skip_link_target]`(n)`, the same skip links
+//! [Bamboo](https://github.com/AljoschaMeyer/bamboo) uses. These give a path of logarithmic length
+//! between any two events.
+use super::request::PathLength;
+
+/// The sequence number of the event that event `sequence_number` skip links to.
+///
+/// Returns `0` for the root, which has no links. When the target is `sequence_number - 1` the
+/// skip link is the predecessor link.
+pub fn skip_link_target(sequence_number: u64) -> u64 {
+    // Powers of three past `u64::MAX / 2` would overflow a u64.
+    let n = u128::from(sequence_number);
+    let mut m = 1u128;
+    let mut po3 = 3u128;
+    let mut u = n;
+
+    // Find the smallest k such that (3^k - 1) / 2 >= n.
+    while m < u {
+        po3 *= 3;
+        m = (po3 - 1) / 2;
+    }
+
+    // Find the longest possible jump back.
+    po3 /= 3;
+    if m != n {
+        while u != 0 {
+            m = (po3 - 1) / 2;
+            po3 /= 3;
+            u %= m;
+        }
+        if m != po3 {
+            po3 = m;
+        }
+    }
+
+    (n - po3) as u64
+}
+
+/// The sequence numbers of the events on a path, from `new` down to but excluding `old`.
+///
+/// Without `old` the path ends at the root. The events are yielded in descending order, each one
+/// linked from the one before it.
+#[derive(Debug, Clone)]
+pub struct Path {
+    next: u64,
+    old: u64,
+    path_length: PathLength,
+}
+
+impl Path {
+    /// Returns `None` if `old` is not lower than `new`, or `new` is zero.
+    pub fn new(new: u64, old: Option<u64>, path_length: PathLength) -> Option<Self> {
+        let old = old.unwrap_or(0);
+        if new == 0 || old >= new {
+            return None;
+        }
+        Some(Path {
+            next: new,
+            old,
+            path_length,
+        })
+    }
+}
+
+impl Iterator for Path {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.next <= self.old {
+            return None;
+        }
+        let current = self.next;
+        let skip = skip_link_target(current);
+
+        self.next = match self.path_length {
+            // Following a skip link never overshoots `old`, so greedily taking it is shortest.
+            PathLength::ShortestPath if skip >= self.old => skip,
+            _ => current - 1,
+        };
+        Some(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn path(new: u64, old: Option<u64>, path_length: PathLength) -> Vec<u64> {
+        Path::new(new, old, path_length).unwrap().collect()
+    }
+
+    #[test]
+    fn skip_link_targets_match_bamboo() {
+        let targets: Vec<u64> = (1..=14).map(skip_link_target).collect();
+        assert_eq!(targets, [0, 1, 2, 1, 4, 5, 6, 4, 8, 9, 10, 8, 4, 13]);
+        assert_eq!(skip_link_target(40), 13);
+        assert_eq!(skip_link_target(121), 40);
+    }
+
+    #[test]
+    fn shortest_path_follows_skip_links() {
+        assert_eq!(path(13, None, PathLength::ShortestPath), [13, 4, 1]);
+        assert_eq!(path(13, Some(4), PathLength::ShortestPath), [13]);
+        assert_eq!(
+            path(13, Some(5), PathLength::ShortestPath),
+            [13, 12, 8, 7, 6]
+        );
+        assert_eq!(path(4, Some(3), PathLength::LongestPath), [4]);
+    }
+
+    #[test]
+    fn old_must_be_lower_than_new() {
+        assert!(Path::new(3, Some(3), PathLength::ShortestPath).is_none());
+        assert!(Path::new(0, None, PathLength::ShortestPath).is_none());
+    }
+
+    proptest! {
+        #[test]
+        fn skip_link_target_is_lower(n in 2..u64::MAX) {
+            let target = skip_link_target(n);
+            prop_assert!(target >= 1 && target < n);
+        }
+
+        #[test]
+        fn paths_are_linked(new in 1..100_000u64, old in any::<prop::sample::Index>(), has_old in any::<bool>(), shortest in any::<bool>()) {
+            let old = if has_old && new > 1 { Some(old.index(new as usize - 1) as u64 + 1) } else { None };
+            let path_length = if shortest { PathLength::ShortestPath } else { PathLength::LongestPath };
+            let path = path(new, old, path_length);
+
+            prop_assert_eq!(path[0], new);
+            for pair in path.windows(2) {
+                prop_assert!(pair[1] == pair[0] - 1 || pair[1] == skip_link_target(pair[0]));
+            }
+            let lowest = *path.last().unwrap();
+            match old {
+                Some(old) => prop_assert!(lowest - 1 == old || skip_link_target(lowest) == old),
+                None => prop_assert_eq!(lowest, 1),
+            }
+            if !shortest {
+                prop_assert_eq!(path.len() as u64, new - old.unwrap_or(0));
+            }
+        }
+
+        #[test]
+        fn shortest_path_is_shortest(new in 1..2_000u64, old in any::<prop::sample::Index>()) {
+            let old = old.index(new as usize) as u64;
+
+            // Fewest links from each event down to `old`, found by trying both links.
+            let mut hops = vec![0usize; new as usize + 1];
+            for n in old + 1..=new {
+                let skip = skip_link_target(n);
+                let predecessor_hops = hops[(n - 1) as usize];
+                hops[n as usize] = 1 + if skip >= old {
+                    predecessor_hops.min(hops[skip as usize])
+                } else {
+                    predecessor_hops
+                };
+            }
+
+            let old = if old == 0 { None } else { Some(old) };
+            prop_assert_eq!(path(new, old, PathLength::ShortestPath).len(), hops[new as usize]);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+use digest::{Digest, Output};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, Snafu};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LogHead {
+    pub root: Vec<u8>,
+    pub head: Vec<u8>,
+    pub sequence_number: u64,
+}
+
+/// A Data Transfer Object representation of a [super::Range].
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Range {
+    pub lower: Option<Vec<u8>>,
+    pub upper: Option<Vec<u8>>,
+    pub summary: Summary,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub enum Summary {
+    Fingerprint { fingerprint: Vec<u8>, count: u64 },
+    Heads(Vec<LogHead>),
+    Differing(Vec<LogHead>),
+}
+
+#[derive(Snafu, Debug, Deserialize, Serialize)]
+pub enum Error {
+    BoundWasIncorrectLength,
+    FingerprintWasIncorrectLength,
+    RootWasIncorrectLength,
+    HeadWasIncorrectLength,
+}
+
+impl<D: Digest> From<super::LogHead<D>> for LogHead {
+    fn from(head: super::LogHead<D>) -> Self {
+        LogHead {
+            root: head.root.to_vec(),
+            head: head.head.to_vec(),
+            sequence_number: head.sequence_number,
+        }
+    }
+}
+
+impl<D: Digest> TryFrom<LogHead> for super::LogHead<D> {
+    type Error = Error;
+
+    fn try_from(head: LogHead) -> Result<Self, Self::Error> {
+        ensure!(head.root.len() == D::output_size(), RootWasIncorrectLength);
+        ensure!(head.head.len() == D::output_size(), HeadWasIncorrectLength);
+        Ok(super::LogHead {
+            root: <&Output<D>>::from(head.root.as_slice()).clone(),
+            head: <&Output<D>>::from(head.head.as_slice()).clone(),
+            sequence_number: head.sequence_number,
+        })
+    }
+}
+
+impl<D: Digest> From<super::Range<D>> for Range {
+    fn from(range: super::Range<D>) -> Self {
+        let heads = |heads: Vec<super::LogHead<D>>| heads.into_iter().map(Into::into).collect();
+        Range {
+            lower: range.lower.map(|lower| lower.to_vec()),
+            upper: range.upper.map(|upper| upper.to_vec()),
+            summary: match range.summary {
+                super::Summary::Fingerprint { fingerprint, count } => Summary::Fingerprint {
+                    fingerprint: fingerprint.to_vec(),
+                    count,
+                },
+                super::Summary::Heads(theirs) => Summary::Heads(heads(theirs)),
+                super::Summary::Differing(theirs) => Summary::Differing(heads(theirs)),
+            },
+        }
+    }
+}
+
+impl<D: Digest> TryFrom<Range> for super::Range<D> {
+    type Error = Error;
+
+    fn try_from(range: Range) -> Result<Self, Self::Error> {
+        let heads = |heads: Vec<LogHead>| {
+            heads
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()
+        };
+        Ok(super::Range {
+            lower: range.lower.map(bound::<D>).transpose()?,
+            upper: range.upper.map(bound::<D>).transpose()?,
+            summary: match range.summary {
+                Summary::Fingerprint { fingerprint, count } => {
+                    ensure!(
+                        fingerprint.len() == D::output_size(),
+                        FingerprintWasIncorrectLength
+                    );
+                    super::Summary::Fingerprint {
+                        fingerprint: <&Output<D>>::from(fingerprint.as_slice()).clone(),
+                        count,
+                    }
+                }
+                Summary::Heads(theirs) => super::Summary::Heads(heads(theirs)?),
+                Summary::Differing(theirs) => super::Summary::Differing(heads(theirs)?),
+            },
+        })
+    }
+}
+
+fn bound<D: Digest>(bound: Vec<u8>) -> Result<Output<D>, Error> {
+    ensure!(bound.len() == D::output_size(), BoundWasIncorrectLength);
+    Ok(<&Output<D>>::from(bound.as_slice()).clone())
+}
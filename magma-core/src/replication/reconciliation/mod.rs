@@ -0,0 +1,499 @@
+//! Finding the logs whose heads differ between two peers, without a [Request] per log.
+//!
+//! This is range-based set reconciliation over the heads of the logs, ordered by their root. A
+//! peer summarizes a [Range] of roots by the XOR of the digests of its `(root, head)` pairs. When
+//! the summaries match the range is done, otherwise the peer receiving it splits it, or sends its
+//! heads once the range holds few enough. Each round trip narrows down the ranges that differ, so
+//! peers that mostly agree exchange little more than the heads that differ.
+//!
+//! Once no range is left, [Reconciliation::requests] builds the [Request]s for the heads the peer
+//! has and we don't.
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::iter::FromIterator;
+use core::ops::Bound;
+use digest::{Digest, Output};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, Snafu};
+
+use crate::replication::request::Request;
+use crate::replication::subscription::ResponseOptions;
+use crate::store::Head;
+
+pub mod dto;
+
+/// Ranges with at most this many heads are sent as heads rather than split further.
+pub const DEFAULT_THRESHOLD: usize = 16;
+
+#[derive(Snafu, Debug, Deserialize, Serialize)]
+pub enum Error {
+    #[snafu(display("The lower bound of the range is not below its upper bound"))]
+    InvalidRange,
+}
+
+/// The head of a log, identified by the digest of its root event.
+#[derive(Debug)]
+pub struct LogHead<D: Digest> {
+    pub root: Output<D>,
+    pub head: Output<D>,
+    pub sequence_number: u64,
+}
+
+// Not derived, which would require `D: Clone`.
+impl<D: Digest> Clone for LogHead<D> {
+    fn clone(&self) -> Self {
+        LogHead {
+            root: self.root.clone(),
+            head: self.head.clone(),
+            sequence_number: self.sequence_number,
+        }
+    }
+}
+
+impl<D: Digest> From<Head<D>> for LogHead<D> {
+    fn from(head: Head<D>) -> Self {
+        LogHead {
+            root: head.root,
+            head: head.head,
+            sequence_number: head.sequence_number,
+        }
+    }
+}
+
+/// The roots from `lower` up to but excluding `upper`, where `None` is unbounded.
+#[derive(Debug)]
+pub struct Range<D: Digest> {
+    pub lower: Option<Output<D>>,
+    pub upper: Option<Output<D>>,
+    pub summary: Summary<D>,
+}
+
+#[derive(Debug)]
+pub enum Summary<D: Digest> {
+    /// The XOR of the digests of every `(root, head)` pair in the range, and their number.
+    Fingerprint { fingerprint: Output<D>, count: u64 },
+    /// Every head in the range. The peer answers with its heads that differ.
+    Heads(Vec<LogHead<D>>),
+    /// The heads in the range that differ from the [Summary::Heads] the peer sent.
+    Differing(Vec<LogHead<D>>),
+}
+
+#[derive(Debug)]
+struct Item<D: Digest> {
+    head: Output<D>,
+    sequence_number: u64,
+    /// The digest of the root followed by the head.
+    digest: Output<D>,
+}
+
+/// The heads of the logs a peer has, ordered by root.
+#[derive(Debug)]
+pub struct HeadSet<D: Digest> {
+    items: BTreeMap<Output<D>, Item<D>>,
+}
+
+impl<D: Digest> Default for HeadSet<D> {
+    fn default() -> Self {
+        HeadSet {
+            items: BTreeMap::new(),
+        }
+    }
+}
+
+impl<D: Digest> HeadSet<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a head, replacing any earlier head of the same log.
+    pub fn insert(&mut self, head: LogHead<D>) {
+        let digest = D::new().chain(&head.root).chain(&head.head).finalize();
+        let item = Item {
+            head: head.head,
+            sequence_number: head.sequence_number,
+            digest,
+        };
+        self.items.insert(head.root, item);
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The head of the log with the root event `root`.
+    pub fn get(&self, root: &Output<D>) -> Option<LogHead<D>> {
+        self.items.get(root).map(|item| log_head(root, item))
+    }
+
+    fn range<'a>(
+        &'a self,
+        lower: &'a Option<Output<D>>,
+        upper: &'a Option<Output<D>>,
+    ) -> impl Iterator<Item = (&'a Output<D>, &'a Item<D>)> {
+        let lower = lower.as_ref().map_or(Bound::Unbounded, Bound::Included);
+        let upper = upper.as_ref().map_or(Bound::Unbounded, Bound::Excluded);
+        self.items.range::<Output<D>, _>((lower, upper))
+    }
+
+    /// The XOR of the digests of the heads in the range, and their number.
+    fn fingerprint(
+        &self,
+        lower: &Option<Output<D>>,
+        upper: &Option<Output<D>>,
+    ) -> (Output<D>, u64) {
+        let mut fingerprint = Output::<D>::default();
+        let mut count = 0;
+        for (_, item) in self.range(lower, upper) {
+            for (byte, other) in fingerprint.iter_mut().zip(item.digest.iter()) {
+                *byte ^= other;
+            }
+            count += 1;
+        }
+        (fingerprint, count)
+    }
+
+    fn summarize(&self, lower: Option<Output<D>>, upper: Option<Output<D>>) -> Range<D> {
+        let (fingerprint, count) = self.fingerprint(&lower, &upper);
+        Range {
+            lower,
+            upper,
+            summary: Summary::Fingerprint { fingerprint, count },
+        }
+    }
+
+    fn heads(&self, lower: &Option<Output<D>>, upper: &Option<Output<D>>) -> Vec<LogHead<D>> {
+        self.range(lower, upper)
+            .map(|(root, item)| log_head(root, item))
+            .collect()
+    }
+}
+
+impl<D: Digest> FromIterator<LogHead<D>> for HeadSet<D> {
+    fn from_iter<I: IntoIterator<Item = LogHead<D>>>(iter: I) -> Self {
+        let mut set = HeadSet::new();
+        for head in iter {
+            set.insert(head);
+        }
+        set
+    }
+}
+
+impl<D: Digest> FromIterator<Head<D>> for HeadSet<D> {
+    fn from_iter<I: IntoIterator<Item = Head<D>>>(iter: I) -> Self {
+        iter.into_iter().map(LogHead::from).collect()
+    }
+}
+
+fn log_head<D: Digest>(root: &Output<D>, item: &Item<D>) -> LogHead<D> {
+    LogHead {
+        root: root.clone(),
+        head: item.head.clone(),
+        sequence_number: item.sequence_number,
+    }
+}
+
+/// One side of a reconciliation with a peer.
+///
+/// The initiator sends the message from [initiate](Reconciliation::initiate), then both sides
+/// [respond](Reconciliation::respond) to each other until a message is empty. The responder can
+/// use a new `Reconciliation` for every message, only the initiator has to keep it.
+#[derive(Debug)]
+pub struct Reconciliation<'a, D: Digest> {
+    heads: &'a HeadSet<D>,
+    threshold: usize,
+    /// The heads the peer has of logs we don't have, or further along than ours.
+    wanted: BTreeMap<Output<D>, LogHead<D>>,
+}
+
+impl<'a, D: Digest> Reconciliation<'a, D> {
+    pub fn new(heads: &'a HeadSet<D>) -> Self {
+        Self::with_threshold(heads, DEFAULT_THRESHOLD)
+    }
+
+    /// Sends the heads of ranges with at most `threshold` heads, which must be at least one.
+    pub fn with_threshold(heads: &'a HeadSet<D>, threshold: usize) -> Self {
+        Reconciliation {
+            heads,
+            threshold: threshold.max(1),
+            wanted: BTreeMap::new(),
+        }
+    }
+
+    /// The first message, covering every root.
+    pub fn initiate(&self) -> Vec<Range<D>> {
+        let range = if self.heads.len() <= self.threshold {
+            Range {
+                lower: None,
+                upper: None,
+                summary: Summary::Heads(self.heads.heads(&None, &None)),
+            }
+        } else {
+            self.heads.summarize(None, None)
+        };
+        vec![range]
+    }
+
+    /// Answers a message from the peer. Reconciliation is done when the answer is empty.
+    pub fn respond(&mut self, message: &[Range<D>]) -> Result<Vec<Range<D>>, Error> {
+        let mut answer = Vec::new();
+        for range in message {
+            if let (Some(lower), Some(upper)) = (&range.lower, &range.upper) {
+                ensure!(lower < upper, InvalidRange);
+            }
+            let (lower, upper) = (range.lower.clone(), range.upper.clone());
+            match &range.summary {
+                Summary::Fingerprint { fingerprint, count } => {
+                    let (ours, our_count) = self.heads.fingerprint(&lower, &upper);
+                    if ours == *fingerprint && our_count == *count {
+                        continue;
+                    }
+                    if our_count as usize <= self.threshold {
+                        let heads = self.heads.heads(&lower, &upper);
+                        answer.push(Range {
+                            lower,
+                            upper,
+                            summary: Summary::Heads(heads),
+                        });
+                    } else {
+                        self.split(lower, upper, our_count as usize, &mut answer);
+                    }
+                }
+                Summary::Heads(theirs) => {
+                    self.want(theirs);
+                    let differing: Vec<_> = self
+                        .heads
+                        .heads(&lower, &upper)
+                        .into_iter()
+                        .filter(|ours| {
+                            !theirs
+                                .iter()
+                                .any(|theirs| theirs.root == ours.root && theirs.head == ours.head)
+                        })
+                        .collect();
+                    if !differing.is_empty() {
+                        answer.push(Range {
+                            lower,
+                            upper,
+                            summary: Summary::Differing(differing),
+                        });
+                    }
+                }
+                Summary::Differing(theirs) => self.want(theirs),
+            }
+        }
+        Ok(answer)
+    }
+
+    /// Splits a range with `count` of our heads in two at its middle root.
+    fn split(
+        &self,
+        lower: Option<Output<D>>,
+        upper: Option<Output<D>>,
+        count: usize,
+        answer: &mut Vec<Range<D>>,
+    ) {
+        let (middle, _) = self
+            .heads
+            .range(&lower, &upper)
+            .nth(count / 2)
+            .expect("The range holds `count` heads");
+        let middle = Some(middle.clone());
+        answer.push(self.heads.summarize(lower, middle.clone()));
+        answer.push(self.heads.summarize(middle, upper));
+    }
+
+    fn want(&mut self, theirs: &[LogHead<D>]) {
+        for head in theirs {
+            let behind = match self.heads.items.get(&head.root) {
+                Some(ours) => ours.sequence_number < head.sequence_number,
+                None => true,
+            };
+            if behind {
+                self.wanted.insert(head.root.clone(), head.clone());
+            }
+        }
+    }
+
+    /// The heads the peer has of logs we don't have, or further along than ours.
+    pub fn wanted(&self) -> impl Iterator<Item = &LogHead<D>> {
+        self.wanted.values()
+    }
+
+    /// The requests for the events we're missing, one per log the peer is ahead on.
+    pub fn requests(&self, options: &ResponseOptions) -> Vec<Request<D>> {
+        self.wanted()
+            .map(|wanted| Request {
+                new: wanted.head.clone(),
+                old: self
+                    .heads
+                    .items
+                    .get(&wanted.root)
+                    .map(|ours| ours.head.clone()),
+                ordering: options.ordering,
+                path_length: options.path_length,
+                include_values: options.include_values,
+                limits: options.limits,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::tests::Bytes;
+    use crate::replication::request::{Limits, Ordering, PathLength};
+    use crate::replication::response::UnvalidatedResponse;
+    use crate::store::Store;
+    use blake2::Blake2b;
+    use proptest::prelude::*;
+
+    type MyStore = Store<Blake2b, Bytes>;
+
+    const OPTIONS: ResponseOptions = ResponseOptions {
+        ordering: Ordering::Ascending,
+        path_length: PathLength::LongestPath,
+        include_values: true,
+        limits: Limits {
+            max_events: None,
+            max_payload_bytes: None,
+        },
+    };
+
+    /// Runs a reconciliation, returning the requests of the initiator and the number of rounds.
+    fn reconcile(
+        ours: &HeadSet<Blake2b>,
+        theirs: &HeadSet<Blake2b>,
+        threshold: usize,
+    ) -> (Vec<Request<Blake2b>>, usize) {
+        let mut initiator = Reconciliation::with_threshold(ours, threshold);
+        let mut message = initiator.initiate();
+        let mut rounds = 0;
+        while !message.is_empty() {
+            let answer = Reconciliation::with_threshold(theirs, threshold)
+                .respond(&message)
+                .unwrap();
+            message = initiator.respond(&answer).unwrap();
+            rounds += 1;
+        }
+        (initiator.requests(&OPTIONS), rounds)
+    }
+
+    /// Copies the events a request is answered with into `store`.
+    fn fetch(store: &mut MyStore, from: &MyStore, request: Request<Blake2b>) {
+        let response: UnvalidatedResponse<_, _> = from.respond(&request).into();
+        let valid = response.try_into_valid_response(request).unwrap();
+        for (event, payload) in valid.events.iter().zip(valid.values.iter()) {
+            let mut encoded = vec![0; event.encoding_length()];
+            event.encode(&mut encoded).unwrap();
+            store.push(&encoded, payload.clone().unwrap()).unwrap();
+        }
+    }
+
+    /// A store with a log per length, with that many events.
+    fn logs(lengths: &[u8]) -> MyStore {
+        let mut store = MyStore::new();
+        for (i, length) in lengths.iter().enumerate() {
+            let root = store.create(Bytes(vec![i as u8])).unwrap().digest;
+            for payload in 1..*length {
+                store.append(&root, Bytes(vec![payload])).unwrap();
+            }
+        }
+        store
+    }
+
+    #[test]
+    fn equal_sets_agree_after_one_round() {
+        let store = logs(&[3; 100]);
+        let heads: HeadSet<_> = store.heads().collect();
+
+        let (requests, rounds) = reconcile(&heads, &heads, DEFAULT_THRESHOLD);
+
+        assert!(requests.is_empty());
+        assert_eq!(rounds, 1);
+    }
+
+    #[test]
+    fn requests_only_the_logs_that_are_behind() {
+        let behind = logs(&[3; 100]);
+        let mut ahead = behind.clone();
+        let roots: Vec<_> = ahead.heads().map(|head| head.root).collect();
+        ahead.append(&roots[17], Bytes(vec![9])).unwrap();
+        ahead.append(&roots[80], Bytes(vec![9])).unwrap();
+        ahead.create(Bytes(vec![200])).unwrap();
+
+        let ours: HeadSet<_> = behind.heads().collect();
+        let theirs: HeadSet<_> = ahead.heads().collect();
+        let (requests, _) = reconcile(&ours, &theirs, 4);
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|request| request.old.is_none())
+                .count(),
+            1
+        );
+
+        // The peer that is ahead wants nothing.
+        let (requests, _) = reconcile(&theirs, &ours, 4);
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn unknown_logs_are_fetched_from_the_root() {
+        let ahead = logs(&[2, 3]);
+        let mut behind = MyStore::new();
+
+        let (requests, _) = reconcile(&HeadSet::new(), &ahead.heads().collect(), 1);
+        for request in requests {
+            assert!(request.old.is_none());
+            fetch(&mut behind, &ahead, request);
+        }
+
+        let caught_up: HeadSet<_> = behind.heads().collect();
+        let (requests, _) = reconcile(&caught_up, &ahead.heads().collect(), 1);
+        assert_eq!(caught_up.len(), 2);
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn rejects_empty_ranges() {
+        let heads: HeadSet<Blake2b> = logs(&[1, 2]).heads().collect();
+        let mut range = heads.summarize(None, None);
+        range.lower = Some(Blake2b::digest(b"b"));
+        range.upper = range.lower;
+
+        let res = Reconciliation::new(&heads).respond(&[range]);
+
+        assert!(matches!(res, Err(Error::InvalidRange)));
+    }
+
+    proptest! {
+        #[test]
+        fn fetching_the_requests_catches_up(
+            lengths in prop::collection::vec((1u8..4, 0u8..3), 0..60),
+            threshold in 1usize..8,
+        ) {
+            let ours: Vec<_> = lengths.iter().map(|(length, _)| *length).collect();
+            let theirs: Vec<_> = lengths.iter().map(|(length, extra)| length + extra).collect();
+            let mut behind = logs(&ours);
+            let ahead = logs(&theirs);
+
+            let heads: HeadSet<_> = behind.heads().collect();
+            let (requests, _) = reconcile(&heads, &ahead.heads().collect(), threshold);
+            let expected = lengths.iter().filter(|(_, extra)| *extra > 0).count();
+            prop_assert_eq!(requests.len(), expected);
+
+            for request in requests {
+                fetch(&mut behind, &ahead, request);
+            }
+            let caught_up: HeadSet<_> = behind.heads().collect();
+            let (requests, rounds) = reconcile(&caught_up, &ahead.heads().collect(), threshold);
+            prop_assert!(requests.is_empty());
+            prop_assert!(rounds <= 1);
+        }
+    }
+}
@@ -1,5 +1,7 @@
-use super::{Ordering, PathLength};
+use super::{Limits, Ordering, PathLength};
+use crate::replication::response::dto::Compression;
 use core::convert::TryFrom;
+use core::num::NonZeroU64;
 use digest::{Digest, Output};
 use serde::{Deserialize, Serialize};
 use snafu::{ensure, Snafu};
@@ -14,18 +16,65 @@ pub struct Request {
     pub ordering: Ordering,
     pub path_length: PathLength,
     pub include_values: bool,
+    #[serde(default)]
+    pub limits: Limits,
+    /// The compressions the client accepts for payloads, in order of preference.
+    #[serde(default)]
+    pub accept_compression: Vec<Compression>,
 }
 
 impl Request {
     pub fn from_request<D: Digest>(request: &super::Request<D>) -> Self {
-        let new = request.new.as_slice().into();
-        let old = request.old.as_ref().map(|old| old.as_slice().into());
+        let new = request.new.to_vec();
+        let old = request.old.as_ref().map(|old| old.to_vec());
         Request {
             new,
             old,
             ordering: request.ordering,
             path_length: request.path_length,
             include_values: request.include_values,
+            limits: request.limits,
+            accept_compression: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct EventAtRequest {
+    pub new: Vec<u8>,
+    pub sequence_number: NonZeroU64,
+    pub include_value: bool,
+    /// The compressions the client accepts for payloads, in order of preference.
+    #[serde(default)]
+    pub accept_compression: Vec<Compression>,
+}
+
+impl EventAtRequest {
+    pub fn from_request<D: Digest>(request: &super::EventAtRequest<D>) -> Self {
+        EventAtRequest {
+            new: request.new.to_vec(),
+            sequence_number: request.sequence_number,
+            include_value: request.include_value,
+            accept_compression: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CheckpointRequest {
+    pub new: Vec<u8>,
+    pub path_length: PathLength,
+    /// The compressions the client accepts for payloads, in order of preference.
+    #[serde(default)]
+    pub accept_compression: Vec<Compression>,
+}
+
+impl CheckpointRequest {
+    pub fn from_request<D: Digest>(request: &super::CheckpointRequest<D>) -> Self {
+        CheckpointRequest {
+            new: request.new.to_vec(),
+            path_length: request.path_length,
+            accept_compression: Vec::new(),
         }
     }
 }
@@ -36,7 +85,7 @@ pub enum Error {
     OldWasIncorrectLength,
 }
 
-impl<'a, D> TryFrom<Request> for super::Request<D>
+impl<D> TryFrom<Request> for super::Request<D>
 where
     D: Digest,
 {
@@ -44,13 +93,15 @@ where
 
     fn try_from(value: Request) -> Result<Self, Self::Error> {
         ensure!(value.new.len() == D::output_size(), NewWasIncorrectLength);
-        let new = Output::<D>::clone_from_slice(value.new.as_slice());
+        let new = <&Output<D>>::from(value.new.as_slice()).clone();
 
         // Refactor this later, yikes
         if let Some(old) = value.old.as_ref() {
             ensure!(old.len() == D::output_size(), OldWasIncorrectLength)
         }
-        let old = value.old.map(|old| Output::<D>::clone_from_slice(&old));
+        let old = value
+            .old
+            .map(|old| <&Output<D>>::from(old.as_slice()).clone());
 
         let result = Self {
             new,
@@ -58,6 +109,7 @@ where
             ordering: value.ordering,
             path_length: value.path_length,
             include_values: value.include_values,
+            limits: value.limits,
         };
         Ok(result)
     }
@@ -68,6 +120,55 @@ where
     D: Digest,
 {
     fn from(value: super::Request<D>) -> Self {
-        todo!()
+        Self::from_request(&value)
+    }
+}
+
+impl<D> TryFrom<EventAtRequest> for super::EventAtRequest<D>
+where
+    D: Digest,
+{
+    type Error = Error;
+
+    fn try_from(value: EventAtRequest) -> Result<Self, Self::Error> {
+        ensure!(value.new.len() == D::output_size(), NewWasIncorrectLength);
+        Ok(Self {
+            new: <&Output<D>>::from(value.new.as_slice()).clone(),
+            sequence_number: value.sequence_number,
+            include_value: value.include_value,
+        })
+    }
+}
+
+impl<D> From<super::EventAtRequest<D>> for EventAtRequest
+where
+    D: Digest,
+{
+    fn from(value: super::EventAtRequest<D>) -> Self {
+        Self::from_request(&value)
+    }
+}
+
+impl<D> TryFrom<CheckpointRequest> for super::CheckpointRequest<D>
+where
+    D: Digest,
+{
+    type Error = Error;
+
+    fn try_from(value: CheckpointRequest) -> Result<Self, Self::Error> {
+        ensure!(value.new.len() == D::output_size(), NewWasIncorrectLength);
+        Ok(Self {
+            new: <&Output<D>>::from(value.new.as_slice()).clone(),
+            path_length: value.path_length,
+        })
+    }
+}
+
+impl<D> From<super::CheckpointRequest<D>> for CheckpointRequest
+where
+    D: Digest,
+{
+    fn from(value: super::CheckpointRequest<D>) -> Self {
+        Self::from_request(&value)
     }
 }
@@ -1,6 +1,8 @@
+use core::num::NonZeroU64;
 use digest::{Digest, Output};
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "alloc")]
 pub mod dto;
 
 #[derive(Deserialize, Serialize, Debug, Copy, Clone)]
@@ -20,7 +22,35 @@ pub enum PathLength {
     LongestPath,
 }
 
+/// Caps on how much of a path the server sends in one response.
+///
+/// A server that hits a limit answers with a [crate::replication::response::Response::Partial]
+/// prefix of the path, which the client continues with another request.
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// The most events to send. The server always sends at least one, or two when sending in
+    /// order of descending depth.
+    pub max_events: Option<u64>,
+    /// The most payload bytes to send. The first event is sent even if its payload is larger.
+    pub max_payload_bytes: Option<u64>,
+}
+
+/// What a server reads at most to answer one [Request], whatever the [Limits] of the request.
+///
+/// A response that runs out of budget is cut short like one that hits the limits, so a client
+/// can't make the server walk a whole log or read every value in one go. The events a response
+/// always has are read even if they go over the budget.
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Budget {
+    /// The most events to walk along the path.
+    pub max_events_walked: Option<u64>,
+    /// The most bytes of events and values to read. The event that goes over it is read but not
+    /// sent.
+    pub max_bytes_read: Option<u64>,
+}
+
 /// Describes which data the client wants from the server.
+#[derive(Debug)]
 pub struct Request<D: Digest> {
     /// The hash of the magma event for which the client wants to obtain the accumulated value.
     pub new: Output<D>,
@@ -33,4 +63,45 @@ pub struct Request<D: Digest> {
     pub path_length: PathLength,
     /// Should the response include the values
     pub include_values: bool,
+    /// How much the server may send at once.
+    pub limits: Limits,
+}
+
+// Not derived, which would require `D: Clone` even though only `Output<D>` is cloned.
+impl<D: Digest> Clone for Request<D> {
+    fn clone(&self) -> Self {
+        Request {
+            new: self.new.clone(),
+            old: self.old.clone(),
+            ordering: self.ordering,
+            path_length: self.path_length,
+            include_values: self.include_values,
+            limits: self.limits,
+        }
+    }
+}
+
+/// Asks for the event at `sequence_number` of the log whose latest event is `new`.
+///
+/// The server answers with the shortest path from `new` down to the event, in order of descending
+/// depth, which proves the event is on the chain to `new`.
+pub struct EventAtRequest<D: Digest> {
+    /// The hash of the magma event the path starts from.
+    pub new: Output<D>,
+    /// The sequence number of the event the client wants.
+    pub sequence_number: NonZeroU64,
+    /// Should the response include the delta of the requested event
+    pub include_value: bool,
+}
+
+/// Asks for the accumulated value at `new` by way of the latest checkpoint below it.
+///
+/// The server answers with the checkpoint, if it has one, and the path from it up to `new` in
+/// order of ascending depth with values, so the client can fold the values onto the checkpoint.
+/// Without a checkpoint the path starts at the root.
+pub struct CheckpointRequest<D: Digest> {
+    /// The hash of the magma event for which the client wants to obtain the accumulated value.
+    pub new: Output<D>,
+    /// Specifies whether to return the shortest path or the longest path from the checkpoint.
+    pub path_length: PathLength,
 }
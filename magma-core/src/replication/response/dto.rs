@@ -1,25 +1,132 @@
-use core::convert::TryFrom;
-use digest::Digest;
+use core::convert::{Infallible, TryFrom};
+use digest::{Digest, Output};
 use frunk::Semigroup;
 use serde::{Deserialize, Serialize};
-use snafu::{AsErrorSource, ResultExt, Snafu};
+use snafu::{ensure, AsErrorSource, OptionExt, ResultExt, Snafu};
 
+use super::encode_event;
 use crate::{CanonicalEncoding, Event};
 
 #[cfg(feature = "alloc")]
-use alloc::vec::Vec;
+use alloc::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    vec,
+    vec::Vec,
+};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct EventPayloadPair {
     pub event: Vec<u8>,
-    pub payload: Option<Vec<u8>>,
+    pub payload: Option<Payload>,
+}
+
+/// A payload of a response, sent once however many events have it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    /// The encoded payload.
+    Value(Vec<u8>),
+    /// The encoded payload, compressed. It decompresses to the size its event declares.
+    Compressed {
+        compression: Compression,
+        value: Vec<u8>,
+    },
+    /// The digest of the encoding of a payload sent as a [Payload::Value] or
+    /// [Payload::Compressed] elsewhere in the response, or in the batch.
+    Reference(Vec<u8>),
 }
 
+/// A way to compress payloads on the wire.
+///
+/// A client lists the ones it accepts in its request, and the server compresses a payload with
+/// the first it supports if that makes the payload smaller. Digests are always over the
+/// uncompressed encoding.
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    /// The LZ4 block format, with the `lz4` feature.
+    Lz4,
+}
+
+/// LZ4 can't shrink a block to less than 1/255 of its size.
+#[cfg(feature = "lz4")]
+const MAX_LZ4_RATIO: usize = 255;
+
+impl Compression {
+    /// Whether this build can compress and decompress with it.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::Lz4 => cfg!(feature = "lz4"),
+        }
+    }
+
+    /// The first of the compressions a client accepts that this build supports.
+    pub fn negotiate(accepted: &[Compression]) -> Option<Compression> {
+        accepted
+            .iter()
+            .copied()
+            .find(|compression| compression.is_supported())
+    }
+
+    #[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+    fn compress(self, encoded: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(lz4_flex::block::compress(encoded)),
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => None,
+        }
+    }
+
+    /// Decompresses a payload of at most `max_size` bytes, without allocating more than a payload
+    /// of the compressed length can decompress to.
+    #[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+    fn decompress<E>(self, value: &[u8], max_size: u64) -> Result<Vec<u8>, Error<E>>
+    where
+        E: AsErrorSource + core::fmt::Debug + core::fmt::Display,
+    {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let size = usize::try_from(max_size)
+                    .unwrap_or(usize::MAX)
+                    .min(value.len().saturating_mul(MAX_LZ4_RATIO));
+                let mut decompressed = vec![0; size];
+                let decompressed_size = lz4_flex::block::decompress_into(value, &mut decompressed)
+                    .ok()
+                    .context(InvalidCompressedPayload)?;
+                decompressed.truncate(decompressed_size);
+                Ok(decompressed)
+            }
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => UnsupportedCompression.fail(),
+        }
+    }
+}
+
+/// The largest payload a client decodes by default, see [Response::decode_within].
+pub const DEFAULT_MAX_PAYLOAD_SIZE: u64 = 16 << 20;
+
 /// A Data Transfer Object representation of a [super::Response].
+///
+/// In a [BatchResponse] the pairs are indices into the pairs of the batch.
 #[derive(Deserialize, Serialize, Debug)]
-pub enum Response {
+pub enum Response<P = EventPayloadPair> {
     UnknownEvent,
-    Data(Vec<EventPayloadPair>),
+    OldNotAncestorOfNew { old: Vec<u8>, path: Vec<Vec<u8>> },
+    PayloadsUnavailable,
+    RateLimited,
+    TooLarge,
+    Data(Vec<P>),
+    Partial(Vec<P>),
+}
+
+/// The responses to a batch of requests, with the pairs that are on more than one of the paths
+/// sent once.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BatchResponse {
+    pub pairs: Vec<EventPayloadPair>,
+    /// The response to every request of the batch in order, with the index of each pair.
+    pub responses: Vec<Response<u64>>,
 }
 
 #[derive(Snafu, Debug)]
@@ -30,6 +137,169 @@ pub enum Error<E: AsErrorSource + core::fmt::Display> {
     DecodePayload {
         source: E,
     },
+    /// A reference is neither the `delta_digest` nor the `skip_delta_digest` of its event.
+    MismatchedReference,
+    /// No payload with the digest of a reference was sent.
+    MissingPayload,
+    /// A payload is compressed in a way this build doesn't support.
+    UnsupportedCompression,
+    /// A compressed payload is invalid, or decompresses to more than its event declares.
+    InvalidCompressedPayload,
+    /// A payload is larger than the client decodes.
+    PayloadTooLarge,
+    /// A payload is neither the `delta_size` nor the `skip_delta_size` of its event.
+    PayloadSizeMismatch,
+}
+
+#[derive(Snafu, Debug)]
+pub enum BatchError<E: AsErrorSource + core::fmt::Debug + core::fmt::Display + 'static> {
+    /// A response refers to a pair the batch doesn't have.
+    UnknownPair { index: u64 },
+    /// The pair at `index` of the batch is invalid.
+    Pair { index: u64, source: Error<E> },
+    /// The proof that old isn't an ancestor of new in the response at `index` is invalid.
+    Proof { index: u64, source: Error<E> },
+}
+
+impl<P> Response<P> {
+    /// Maps the pairs of a [Response::Data] or [Response::Partial] all at once.
+    fn try_map_pair_vec<Q, E>(
+        self,
+        pairs: impl FnOnce(Vec<P>) -> Result<Vec<Q>, E>,
+    ) -> Result<Response<Q>, E> {
+        Ok(match self {
+            Response::UnknownEvent => Response::UnknownEvent,
+            Response::OldNotAncestorOfNew { old, path } => {
+                Response::OldNotAncestorOfNew { old, path }
+            }
+            Response::PayloadsUnavailable => Response::PayloadsUnavailable,
+            Response::RateLimited => Response::RateLimited,
+            Response::TooLarge => Response::TooLarge,
+            Response::Data(data) => Response::Data(pairs(data)?),
+            Response::Partial(data) => Response::Partial(pairs(data)?),
+        })
+    }
+
+    fn try_map_pairs<Q, E>(self, mut f: impl FnMut(P) -> Result<Q, E>) -> Result<Response<Q>, E> {
+        self.try_map_pair_vec(|pairs| pairs.into_iter().map(&mut f).collect())
+    }
+
+    fn map_pairs<Q>(self, mut f: impl FnMut(P) -> Q) -> Response<Q> {
+        self.try_map_pairs(|pair| Ok::<_, Infallible>(f(pair)))
+            .unwrap_or_else(|never| match never {})
+    }
+}
+
+impl<D, S> From<super::Response<D, S>> for Response<super::EventPayloadPair<D, S>>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding,
+{
+    /// Encodes everything but the pairs.
+    fn from(response: super::Response<D, S>) -> Self {
+        match response {
+            super::Response::UnknownEvent => Self::UnknownEvent,
+            super::Response::OldNotAncestorOfNew { old, path } => Self::OldNotAncestorOfNew {
+                old: encode_event(&old),
+                path: path.iter().map(encode_event).collect(),
+            },
+            super::Response::PayloadsUnavailable => Self::PayloadsUnavailable,
+            super::Response::RateLimited => Self::RateLimited,
+            super::Response::TooLarge => Self::TooLarge,
+            super::Response::Data(pairs) => Self::Data(pairs),
+            super::Response::Partial(pairs) => Self::Partial(pairs),
+        }
+    }
+}
+
+impl BatchResponse {
+    /// Encodes the responses to a batch, compressing the payloads with `compression` where that
+    /// makes them smaller.
+    pub fn from_responses<D, S>(
+        responses: Vec<super::Response<D, S>>,
+        compression: Option<Compression>,
+    ) -> Self
+    where
+        D: Digest,
+        S: Semigroup + CanonicalEncoding,
+    {
+        let mut pairs = Vec::new();
+        let mut indices = BTreeMap::new();
+        let responses = responses
+            .into_iter()
+            .map(|response| {
+                Response::from(response).map_pairs(|pair: super::EventPayloadPair<D, S>| {
+                    // The same event comes with its delta or its skip delta depending on the
+                    // path, so pairs are only shared if their payloads are the same too.
+                    let key = (
+                        encode_event(&pair.event),
+                        pair.payload.as_ref().map(encode_payload),
+                    );
+                    *indices.entry(key.clone()).or_insert_with(|| {
+                        pairs.push(key);
+                        pairs.len() as u64 - 1
+                    })
+                })
+            })
+            .collect();
+        let mut sent = BTreeSet::new();
+        let pairs = pairs
+            .into_iter()
+            .map(|(event, payload)| EventPayloadPair {
+                event,
+                payload: payload.map(|payload| send_payload::<D>(payload, &mut sent, compression)),
+            })
+            .collect();
+        BatchResponse { pairs, responses }
+    }
+
+    /// Decodes the response to every request of the batch, to be validated on its own.
+    pub fn into_responses<D, S>(
+        self,
+    ) -> Result<Vec<super::UnvalidatedResponse<D, S>>, BatchError<S::Error>>
+    where
+        D: Digest,
+        S: Semigroup + CanonicalEncoding + Clone,
+        <S as CanonicalEncoding>::Error: AsErrorSource + core::fmt::Display + 'static,
+    {
+        self.into_responses_within(DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+
+    /// Like [BatchResponse::into_responses], with payloads of at most `max_payload_size` bytes.
+    pub fn into_responses_within<D, S>(
+        self,
+        max_payload_size: u64,
+    ) -> Result<Vec<super::UnvalidatedResponse<D, S>>, BatchError<S::Error>>
+    where
+        D: Digest,
+        S: Semigroup + CanonicalEncoding + Clone,
+        <S as CanonicalEncoding>::Error: AsErrorSource + core::fmt::Display + 'static,
+    {
+        let BatchResponse { pairs, responses } = self;
+        // Every pair is decoded once, and copied into each response that has it.
+        let pairs = decode_pairs::<D, S>(&pairs, max_payload_size).map_err(|(index, source)| {
+            BatchError::Pair {
+                index: index as u64,
+                source,
+            }
+        })?;
+        responses
+            .into_iter()
+            .enumerate()
+            .map(|(response_index, response)| {
+                let response = response.try_map_pairs(|index| {
+                    usize::try_from(index)
+                        .ok()
+                        .and_then(|index| pairs.get(index))
+                        .cloned()
+                        .context(UnknownPair { index })
+                })?;
+                decode_response(response).context(Proof {
+                    index: response_index as u64,
+                })
+            })
+            .collect()
+    }
 }
 
 impl<D, S> TryFrom<Response> for super::UnvalidatedResponse<D, S>
@@ -42,64 +312,242 @@ where
 
     // Decode from a dto to an UnvalidatedResponse
     fn try_from(response: Response) -> Result<Self, Self::Error> {
-        match response {
-            Response::UnknownEvent => Ok(Self::UnknownEvent),
-            Response::Data(pairs) => {
-                let new_pairs = pairs
-                    .iter()
-                    .map(|pair| {
-                        let event = Event::decode(&pair.event).context(DecodeEvent)?;
-
-                        let payload = pair
-                            .payload
-                            .as_ref()
-                            .map(|payload| {
-                                let (res, _) = S::decode(&payload).context(DecodePayload)?;
-                                Ok(res)
-                            })
-                            .transpose()?;
-
-                        Ok(super::EventPayloadPair { event, payload })
-                    })
-                    .collect::<Result<Vec<_>, Self::Error>>()?;
+        response.decode_within(DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+}
+
+impl<D, S> From<super::Response<D, S>> for Response
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding,
+{
+    fn from(response: super::Response<D, S>) -> Self {
+        Self::with_compression(response, None)
+    }
+}
+
+impl Response {
+    /// Encodes a response, compressing the payloads with `compression` where that makes them
+    /// smaller.
+    pub fn with_compression<D, S>(
+        response: super::Response<D, S>,
+        compression: Option<Compression>,
+    ) -> Self
+    where
+        D: Digest,
+        S: Semigroup + CanonicalEncoding,
+    {
+        let mut sent = BTreeSet::new();
+        Response::<super::EventPayloadPair<D, S>>::from(response)
+            .map_pairs(|pair| encode_pair(&pair, &mut sent, compression))
+    }
+
+    /// Decodes a response, rejecting payloads of more than `max_payload_size` bytes whatever
+    /// their events declare.
+    pub fn decode_within<D, S>(
+        self,
+        max_payload_size: u64,
+    ) -> Result<super::UnvalidatedResponse<D, S>, Error<S::Error>>
+    where
+        D: Digest,
+        S: Semigroup + CanonicalEncoding,
+        <S as CanonicalEncoding>::Error: AsErrorSource + core::fmt::Display,
+    {
+        let response = self.try_map_pair_vec(|pairs| {
+            decode_pairs(&pairs, max_payload_size).map_err(|(_, error)| error)
+        })?;
+        decode_response(response)
+    }
+}
+
+/// The encoding of the payload of `event`, checked against the sizes the event declares before
+/// it's decoded. References are looked up with `payloads`.
+///
+/// The payload is the delta of the event, or its skip delta if the path follows the skip link to
+/// it, which only validating the response tells apart.
+fn encoded_payload<'a, D, E>(
+    event: &Event<D>,
+    payload: &'a Payload,
+    payloads: impl Fn(&Output<D>) -> Option<&'a [u8]>,
+    max_payload_size: u64,
+) -> Result<Cow<'a, [u8]>, Error<E>>
+where
+    D: Digest,
+    E: AsErrorSource + core::fmt::Debug + core::fmt::Display,
+{
+    let declared = [(event.delta_digest(), event.size()), event.skip_delta()];
+    let largest = declared
+        .iter()
+        .map(|(_, size)| *size)
+        .filter(|size| *size <= max_payload_size)
+        .max()
+        .context(PayloadTooLarge)?;
+    let encoded = match payload {
+        Payload::Value(value) => Cow::Borrowed(value.as_slice()),
+        Payload::Compressed { compression, value } => {
+            Cow::Owned(compression.decompress(value, largest)?)
+        }
+        Payload::Reference(reference) => {
+            let (digest, _) = declared
+                .iter()
+                .find(|(digest, _)| digest[..] == reference[..])
+                .context(MismatchedReference)?;
+            Cow::Borrowed(payloads(digest).context(MissingPayload)?)
+        }
+    };
+    let size = encoded.len() as u64;
+    ensure!(
+        declared.iter().any(|(_, declared)| size == *declared),
+        PayloadSizeMismatch
+    );
+    ensure!(size <= max_payload_size, PayloadTooLarge);
+    Ok(encoded)
+}
 
-                Ok(Self::Data(new_pairs))
+/// Decodes the rest of a response whose pairs are decoded.
+fn decode_response<D, S>(
+    response: Response<super::EventPayloadPair<D, S>>,
+) -> Result<super::UnvalidatedResponse<D, S>, Error<S::Error>>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding,
+    <S as CanonicalEncoding>::Error: AsErrorSource + core::fmt::Display,
+{
+    use super::UnvalidatedResponse;
+    Ok(match response {
+        Response::UnknownEvent => UnvalidatedResponse::UnknownEvent,
+        Response::OldNotAncestorOfNew { old, path } => UnvalidatedResponse::OldNotAncestorOfNew {
+            old: Event::decode(&old).context(DecodeEvent)?,
+            path: path
+                .iter()
+                .map(|event| Event::decode(event).context(DecodeEvent))
+                .collect::<Result<_, _>>()?,
+        },
+        Response::PayloadsUnavailable => UnvalidatedResponse::PayloadsUnavailable,
+        Response::RateLimited => UnvalidatedResponse::RateLimited,
+        Response::TooLarge => UnvalidatedResponse::TooLarge,
+        Response::Data(pairs) => UnvalidatedResponse::Data(pairs),
+        Response::Partial(pairs) => UnvalidatedResponse::Partial(pairs),
+    })
+}
+
+/// An error decoding a pair, with the index of the pair.
+type PairError<E> = (usize, Error<E>);
+
+/// Decodes every pair once, and every payload sent as a value. References are looked up among
+/// those payloads by the digest of their encoding.
+fn decode_pairs<D, S>(
+    pairs: &[EventPayloadPair],
+    max_payload_size: u64,
+) -> Result<Vec<super::EventPayloadPair<D, S>>, PairError<S::Error>>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding,
+    <S as CanonicalEncoding>::Error: AsErrorSource + core::fmt::Display,
+{
+    let mut events = Vec::with_capacity(pairs.len());
+    let mut values = Vec::with_capacity(pairs.len());
+    for (index, pair) in pairs.iter().enumerate() {
+        let at = |error| (index, error);
+        let event: Event<D> = Event::decode(&pair.event)
+            .context(DecodeEvent)
+            .map_err(at)?;
+        let value = match &pair.payload {
+            Some(Payload::Reference(_)) | None => None,
+            Some(payload) => {
+                Some(encoded_payload(&event, payload, |_| None, max_payload_size).map_err(at)?)
             }
+        };
+        events.push(event);
+        values.push(value);
+    }
+
+    let referenced = pairs
+        .iter()
+        .any(|pair| matches!(pair.payload, Some(Payload::Reference(_))));
+    let mut payloads = BTreeMap::new();
+    if referenced {
+        for value in values.iter().flatten() {
+            payloads.insert(D::digest(value), value.as_ref());
         }
     }
+
+    pairs
+        .iter()
+        .zip(events)
+        .zip(&values)
+        .enumerate()
+        .map(|(index, ((pair, event), value))| {
+            let at = |error| (index, error);
+            let payload = match (&pair.payload, value) {
+                (None, _) => None,
+                (Some(payload), value) => {
+                    let encoded = match value {
+                        Some(value) => Cow::Borrowed(value.as_ref()),
+                        None => encoded_payload(
+                            &event,
+                            payload,
+                            |digest| payloads.get(digest).copied(),
+                            max_payload_size,
+                        )
+                        .map_err(at)?,
+                    };
+                    let (payload, _) = S::decode(&encoded).context(DecodePayload).map_err(at)?;
+                    Some(payload)
+                }
+            };
+            Ok(super::EventPayloadPair { event, payload })
+        })
+        .collect()
 }
 
-impl<'a, D, S> From<super::Response<D, S>> for Response
+/// Encodes a pair, with a reference instead of its payload if a payload with the same digest was
+/// already sent.
+fn encode_pair<D, S>(
+    pair: &super::EventPayloadPair<D, S>,
+    sent: &mut BTreeSet<Output<D>>,
+    compression: Option<Compression>,
+) -> EventPayloadPair
 where
     D: Digest,
     S: Semigroup + CanonicalEncoding,
 {
-    fn from(response: super::Response<D, S>) -> Self {
-        match response {
-            super::Response::UnknownEvent => Self::UnknownEvent,
-            super::Response::Data(pairs) => {
-                let new_pairs = pairs.iter().map(|pair|{
-                    let mut event = Vec::with_capacity(pair.event.encoding_length());
-
-                    pair.event.encode(&mut event).expect("Encoding event failed unexpectedly");
-
-                    let payload = pair.payload.as_ref().map(|payload|{
-                        let mut vec = Vec::with_capacity(payload.encoding_length());
-                        vec.resize(payload.encoding_length(), 0);
-
-                        // This shouldn't fail unless the payload.encoding_length is buggy
-                        payload.encode(&mut vec).expect("Encoding Semigroup value failed unexpectedly. Is payload.encoding_length buggy?");
-                        vec
-                    });
-                    EventPayloadPair{
-                        event,
-                        payload
-                    }
-                })
-                .collect();
+    EventPayloadPair {
+        event: encode_event(&pair.event),
+        payload: pair
+            .payload
+            .as_ref()
+            .map(|payload| send_payload::<D>(encode_payload(payload), sent, compression)),
+    }
+}
 
-                Self::Data(new_pairs)
-            }
+fn encode_payload<S: CanonicalEncoding>(payload: &S) -> Vec<u8> {
+    let mut vec = vec![0; payload.encoding_length()];
+    // This shouldn't fail unless the payload.encoding_length is buggy
+    payload
+        .encode(&mut vec)
+        .expect("Encoding Semigroup value failed unexpectedly. Is payload.encoding_length buggy?");
+    vec
+}
+
+/// An encoded payload as sent, or a reference to it if it was already sent.
+fn send_payload<D: Digest>(
+    encoded: Vec<u8>,
+    sent: &mut BTreeSet<Output<D>>,
+    compression: Option<Compression>,
+) -> Payload {
+    // The payload may be the delta or the skip delta of the event, so it's told apart by the
+    // digest of what is sent.
+    let digest = D::digest(&encoded);
+    if sent.contains(&digest) {
+        return Payload::Reference(digest.to_vec());
+    }
+    sent.insert(digest);
+
+    match compression.and_then(|compression| Some((compression, compression.compress(&encoded)?))) {
+        Some((compression, value)) if value.len() < encoded.len() => {
+            Payload::Compressed { compression, value }
         }
+        _ => Payload::Value(encoded),
     }
 }
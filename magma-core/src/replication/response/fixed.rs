@@ -0,0 +1,97 @@
+//! Responses that fit in a fixed capacity buffer, for devices without an allocator.
+use digest::Digest;
+use heapless::Vec;
+use snafu::ensure;
+
+use super::validator::Validator;
+use super::{ResponseValidationError, TooManyEvents};
+use crate::event::dto::EventRef;
+use crate::replication::request::{EventAtRequest, Request};
+
+/// An encoded event and its encoded payload, borrowed from wherever the response was received.
+#[derive(Debug, Clone, Copy)]
+pub struct EventPayloadPair<'a> {
+    pub event: &'a [u8],
+    pub payload: Option<&'a [u8]>,
+}
+
+/// A response holding at most `N` events.
+#[derive(Debug)]
+pub enum Response<'a, const N: usize> {
+    UnknownEvent,
+    /// See [super::Validator::check_not_ancestor].
+    OldNotAncestorOfNew {
+        old: &'a [u8],
+        path: Vec<&'a [u8], N>,
+    },
+    PayloadsUnavailable,
+    RateLimited,
+    TooLarge,
+    Data(Vec<EventPayloadPair<'a>, N>),
+}
+
+impl<'a, const N: usize> Response<'a, N> {
+    /// Adds the next pair to a `Data` response, failing once the response holds `N` events.
+    pub fn push(&mut self, pair: EventPayloadPair<'a>) -> Result<(), ResponseValidationError> {
+        if !matches!(self, Self::Data(_)) {
+            *self = Self::Data(Vec::new());
+        }
+        if let Self::Data(pairs) = self {
+            ensure!(pairs.push(pair).is_ok(), TooManyEvents);
+        }
+        Ok(())
+    }
+
+    pub fn try_into_valid_response<D: Digest>(
+        self,
+        request: Request<D>,
+    ) -> Result<ValidResponse<'a, N>, ResponseValidationError> {
+        self.validate(Validator::new(&request))
+    }
+
+    /// Validates a response to an [EventAtRequest], the requested event is the last one.
+    pub fn try_into_valid_event_at<D: Digest>(
+        self,
+        request: EventAtRequest<D>,
+    ) -> Result<ValidResponse<'a, N>, ResponseValidationError> {
+        self.validate(Validator::for_event_at(&request))
+    }
+
+    fn validate<D: Digest>(
+        self,
+        mut validator: Validator<D>,
+    ) -> Result<ValidResponse<'a, N>, ResponseValidationError> {
+        match self {
+            Self::UnknownEvent => Err(ResponseValidationError::UnknownEvent),
+            Self::OldNotAncestorOfNew { old, path } => {
+                validator.check_not_ancestor(old, path)?;
+                Err(ResponseValidationError::OldNotAncestorOfNew)
+            }
+            Self::PayloadsUnavailable => Err(ResponseValidationError::PayloadsUnavailable),
+            Self::RateLimited => Err(ResponseValidationError::RateLimited),
+            Self::TooLarge => Err(ResponseValidationError::TooLarge),
+            Self::Data(pairs) => {
+                let mut events = Vec::new();
+                let mut values = Vec::new();
+
+                for pair in pairs {
+                    let event = validator.push(pair.event, pair.payload)?;
+                    // Both have the same capacity as `pairs`, so these can't overflow.
+                    let _ = events.push(event);
+                    let _ = values.push(pair.payload);
+                }
+                validator.finish()?;
+
+                Ok(ValidResponse { events, values })
+            }
+        }
+    }
+}
+
+/// A valid response created by calling [Response::try_into_valid_response].
+#[readonly::make]
+#[derive(Debug)]
+pub struct ValidResponse<'a, const N: usize> {
+    pub events: Vec<EventRef<'a>, N>,
+    pub values: Vec<Option<&'a [u8]>, N>,
+}
@@ -1,78 +1,277 @@
-use core::convert::TryFrom;
-use snafu::{ensure, AsErrorSource, OptionExt, ResultExt, Snafu};
-
-use digest::Digest;
-use frunk::{semigroup::combine_all_option, Semigroup};
-
-use crate::replication::request::Request;
-use crate::{CanonicalEncoding, Event};
+use snafu::Snafu;
 
 #[cfg(feature = "alloc")]
-use alloc::vec::Vec;
+use {
+    crate::replication::request::{EventAtRequest, Ordering, Request},
+    crate::{CanonicalEncoding, Event},
+    alloc::{vec, vec::Vec},
+    digest::{Digest, Output},
+    frunk::Semigroup,
+    snafu::{ensure, OptionExt},
+};
 
+#[cfg(feature = "alloc")]
 pub mod dto;
+pub mod fixed;
+pub mod validator;
 
+pub use validator::Validator;
+
+#[cfg(feature = "alloc")]
 #[derive(Debug)]
 pub struct EventPayloadPair<D: Digest, S: Semigroup> {
     pub event: Event<D>,
     pub payload: Option<S>,
 }
 
+// Not derived, which would require `D: Clone` even though only `Output<D>` is cloned.
+#[cfg(feature = "alloc")]
+impl<D: Digest, S: Semigroup + Clone> Clone for EventPayloadPair<D, S> {
+    fn clone(&self) -> Self {
+        EventPayloadPair {
+            event: self.event.clone(),
+            payload: self.payload.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
 #[derive(Debug)]
 pub enum Response<D: Digest, S: Semigroup + CanonicalEncoding> {
     UnknownEvent,
+    /// `old` is not below `new` in the same log. The server proves it with the `old` event and the
+    /// shortest path from `new` down to the event at the same sequence number, or just `new` if
+    /// `old` is not lower. See [Validator::check_not_ancestor].
+    OldNotAncestorOfNew {
+        old: Event<D>,
+        path: Vec<Event<D>>,
+    },
+    /// The server has the events but no longer has the values the client asked for.
+    PayloadsUnavailable,
+    /// The server won't answer right now, the client should try again later.
+    RateLimited,
+    /// The response would be larger than the server is willing to send, even in parts.
+    TooLarge,
     Data(Vec<EventPayloadPair<D, S>>),
+    /// A prefix of the path, cut short by the [crate::replication::request::Limits] of the
+    /// request. The client asks for the rest with [ValidResponse::continuation].
+    Partial(Vec<EventPayloadPair<D, S>>),
 }
 
+#[cfg(feature = "alloc")]
 #[derive(Debug)]
 pub enum UnvalidatedResponse<D: Digest, S: Semigroup + CanonicalEncoding> {
     UnknownEvent,
+    OldNotAncestorOfNew { old: Event<D>, path: Vec<Event<D>> },
+    PayloadsUnavailable,
+    RateLimited,
+    TooLarge,
     Data(Vec<EventPayloadPair<D, S>>),
+    Partial(Vec<EventPayloadPair<D, S>>),
 }
 
+/// Treats a response built in the same process as untrusted, e.g. to test a server.
+#[cfg(feature = "alloc")]
+impl<D: Digest, S: Semigroup + CanonicalEncoding> From<Response<D, S>>
+    for UnvalidatedResponse<D, S>
+{
+    fn from(response: Response<D, S>) -> Self {
+        match response {
+            Response::UnknownEvent => Self::UnknownEvent,
+            Response::OldNotAncestorOfNew { old, path } => Self::OldNotAncestorOfNew { old, path },
+            Response::PayloadsUnavailable => Self::PayloadsUnavailable,
+            Response::RateLimited => Self::RateLimited,
+            Response::TooLarge => Self::TooLarge,
+            Response::Data(pairs) => Self::Data(pairs),
+            Response::Partial(pairs) => Self::Partial(pairs),
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
 impl<D: Digest, S: Semigroup + CanonicalEncoding> UnvalidatedResponse<D, S> {
     pub fn try_into_valid_response(
         self,
         request: Request<D>,
     ) -> Result<ValidResponse<D, S>, ResponseValidationError> {
-        match self {
-            Self::UnknownEvent => Err(ResponseValidationError::UnknownEvent),
-            Self::Data(pairs) => {
-                // TODO !!!! ALL THE LOGIC
-                //
-                // Regardless of the specifics of the communication protocol, a server sending a `Data` response first transmits the `Events` in order of descending depth. The client hashes the first received magma event and verifies that the resulting digest matches the one it requested. For all further magma events, the client verifies that the depth has the correct value (the correct position in the shortest path in the evolution).
-                //
-                // Next, the server transmits the `additional_events`, again in order of descending depth. The client again verifies that the depth of each magma event is correct.
-                //
-                // Furthermore, whenever the client receives a magma event, it verifies that all incoming and outgoing links are consistent. The client computes the hash of the received event and verifies that it matches with the `predecessor_event_link`  or `skip_event_link` value of all known (to the client) magma events that correspond to in-neighbors in the evolution graph. The client does the same for the out-neighbors as well.
-                //
-                // The server next communicates the value of `is_complete_payload`.
-                //
-                // Then, the semigroup `values` of the response are transmitted sorted by the order of the depths of the associated magma events. Whether they are sorted in ascending or descending order is specified by the `Mode` of the request. When the client receives a semigroup value, it verifies that its hash and length exactly match the ones given in the corresponding magma event.
-
-                let (events, values) = pairs
-                    .into_iter()
-                    .map(|pair| (pair.event, pair.payload))
-                    .unzip();
-
-                Ok(ValidResponse { events, values })
+        let (mut valid, resume_from) = self.validate(Validator::new(&request))?;
+        valid.continuation = resume_from.map(|last| match request.ordering {
+            // The last event is sent again, this time with its payload.
+            Ordering::Descending => Request {
+                new: last,
+                ..request
+            },
+            Ordering::Ascending => Request {
+                old: Some(last),
+                ..request
+            },
+        });
+        Ok(valid)
+    }
+
+    /// Validates a response to an [EventAtRequest], the requested event is the last one.
+    pub fn try_into_valid_event_at(
+        self,
+        request: EventAtRequest<D>,
+    ) -> Result<ValidResponse<D, S>, ResponseValidationError> {
+        let (valid, resume_from) = self.validate(Validator::for_event_at(&request))?;
+        ensure!(resume_from.is_none(), UnexpectedPartialResponse);
+        Ok(valid)
+    }
+
+    /// Validates the pairs and returns where to resume from if the response was partial.
+    fn validate(
+        self,
+        mut validator: Validator<D>,
+    ) -> Result<ValidatedAndResumeFrom<D, S>, ResponseValidationError> {
+        let (pairs, partial) = match self {
+            Self::UnknownEvent => return Err(ResponseValidationError::UnknownEvent),
+            Self::OldNotAncestorOfNew { old, path } => {
+                let path: Vec<_> = path.iter().map(encode_event).collect();
+                validator
+                    .check_not_ancestor(&encode_event(&old), path.iter().map(Vec::as_slice))?;
+                return Err(ResponseValidationError::OldNotAncestorOfNew);
             }
+            Self::PayloadsUnavailable => return Err(ResponseValidationError::PayloadsUnavailable),
+            Self::RateLimited => return Err(ResponseValidationError::RateLimited),
+            Self::TooLarge => return Err(ResponseValidationError::TooLarge),
+            Self::Data(pairs) => (pairs, false),
+            Self::Partial(pairs) => (pairs, true),
+        };
+        // Regardless of the specifics of the communication protocol, a server sending a `Data` response first transmits the `Events` in order of descending depth. The client hashes the first received magma event and verifies that the resulting digest matches the one it requested. For all further magma events, the client verifies that the depth has the correct value (the correct position in the shortest path in the evolution).
+        //
+        // Furthermore, whenever the client receives a magma event, it verifies that all incoming and outgoing links are consistent. The client computes the hash of the received event and verifies that it matches with the `predecessor_event_link`  or `skip_event_link` value of all known (to the client) magma events that correspond to in-neighbors in the evolution graph. The client does the same for the out-neighbors as well.
+        //
+        // When the client receives a semigroup value, it verifies that its hash and length exactly match the ones given in the corresponding magma event.
+        for pair in &pairs {
+            let event = encode_event(&pair.event);
+
+            let payload = pair.payload.as_ref().map(encode_value);
+
+            validator.push(&event, payload.as_deref())?;
         }
+        let resume_from = if partial {
+            validator.finish_partial()?
+        } else {
+            validator.finish()?;
+            None
+        };
+
+        let (events, values) = pairs
+            .into_iter()
+            .map(|pair| (pair.event, pair.payload))
+            .unzip();
+
+        Ok((
+            ValidResponse {
+                events,
+                values,
+                continuation: None,
+            },
+            resume_from,
+        ))
     }
 }
 
-#[cfg(any(feature = "alloc", feature = "std"))]
+#[cfg(feature = "alloc")]
+pub(crate) fn encode_event<D: Digest>(event: &Event<D>) -> Vec<u8> {
+    let mut encoded = vec![0; event.encoding_length()];
+    event
+        .encode(&mut encoded)
+        .expect("Encoding event failed unexpectedly");
+    encoded
+}
+
+#[cfg(feature = "alloc")]
+fn encode_value<S: CanonicalEncoding>(value: &S) -> Vec<u8> {
+    let mut encoded = vec![0; value.encoding_length()];
+    value
+        .encode(&mut encoded)
+        .expect("Encoding Semigroup value failed unexpectedly. Is payload.encoding_length buggy?");
+    encoded
+}
+
+/// A validated response and the digest of the event to resume from, if it was partial.
+#[cfg(feature = "alloc")]
+type ValidatedAndResumeFrom<D, S> = (ValidResponse<D, S>, Option<Output<D>>);
+
+#[cfg(feature = "alloc")]
 #[readonly::make]
 #[derive(Debug)]
 /// A Valid response created by calling [Response.try_into_valid_request]
 pub struct ValidResponse<D: Digest, S: Semigroup> {
     pub events: Vec<Event<D>>,
     pub values: Vec<Option<S>>,
+    /// The request for the rest of the path, if the server sent a [Response::Partial].
+    pub continuation: Option<Request<D>>,
+}
+
+#[cfg(feature = "alloc")]
+impl<D: Digest, S: Semigroup + CanonicalEncoding + Clone> ValidResponse<D, S> {
+    /// Folds the values in order of ascending depth onto `base`, the accumulated value at the event
+    /// below the lowest one, or `None` if the lowest event is the root.
+    ///
+    /// Checks the folded value at every event that commits to its accumulated value, and returns
+    /// the value at the highest event.
+    pub fn fold(&self, base: Option<S>) -> Result<S, ResponseValidationError> {
+        let mut pairs: Vec<_> = self.events.iter().zip(&self.values).collect();
+        if pairs.first().map(|(event, _)| event.sequence_number())
+            > pairs.last().map(|(event, _)| event.sequence_number())
+        {
+            pairs.reverse();
+        }
+
+        let mut accumulated = base;
+        for (event, value) in pairs {
+            let value = value.as_ref().context(MissingValue)?;
+            let next = match accumulated {
+                Some(accumulated) => accumulated.combine(value),
+                None => {
+                    ensure!(matches!(event, Event::Root { .. }), EventWasNotRoot);
+                    value.clone()
+                }
+            };
+            if let Some((digest, size)) = event.accumulated() {
+                let encoded = encode_value(&next);
+                ensure!(
+                    (&D::digest(&encoded), encoded.len() as u64) == (digest, size),
+                    AccumulatedValueDidNotMatch
+                );
+            }
+            accumulated = Some(next);
+        }
+        accumulated.context(MissingValue)
+    }
 }
 
 #[derive(Debug, Snafu)]
 pub enum ResponseValidationError {
     UnknownEvent,
+    /// The server proved that `old` is not an ancestor of `new`.
+    OldNotAncestorOfNew,
+    OldEventHashDidNotMatchHashOfRequestOld,
+    OldWasAncestorOfNew,
+    UnexpectedOldNotAncestorOfNew,
+    PayloadsUnavailable,
+    RateLimited,
+    TooLarge,
     ExpectedAtLeastOneEventInEvents,
     FirstEventHashDidNotMatchHashOfRequestNew,
+    LastEventHashDidNotMatchHashOfRequestNew,
+    DecodeEvent {
+        source: crate::event::decode::error::Error,
+    },
+    EventWasNotLinkedFromPrevious,
+    SequenceNumberDidNotMatchLink,
+    EventDidNotLinkToOld,
+    EventWasNotRoot,
+    EventWasNotAtSequenceNumber,
+    PayloadDidNotMatchDelta,
+    UnverifiablePayload,
+    UnexpectedPartialResponse,
+    TooManyEvents,
+    /// A value to fold was left out of the response.
+    MissingValue,
+    /// A folded value did not match the accumulated value an event commits to.
+    AccumulatedValueDidNotMatch,
 }
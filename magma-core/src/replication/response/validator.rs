@@ -0,0 +1,324 @@
+use digest::{Digest, Output};
+use snafu::{ensure, OptionExt, ResultExt};
+
+use super::ResponseValidationError as Error;
+use super::{
+    DecodeEvent, EventDidNotLinkToOld, EventWasNotAtSequenceNumber, EventWasNotLinkedFromPrevious,
+    EventWasNotRoot, ExpectedAtLeastOneEventInEvents, FirstEventHashDidNotMatchHashOfRequestNew,
+    LastEventHashDidNotMatchHashOfRequestNew, OldEventHashDidNotMatchHashOfRequestOld,
+    OldWasAncestorOfNew, PayloadDidNotMatchDelta, SequenceNumberDidNotMatchLink, TooManyEvents,
+    UnexpectedOldNotAncestorOfNew, UnverifiablePayload,
+};
+use crate::event::dto::EventRef;
+use crate::multihash::{DigestFormat, MultihashDigest};
+use crate::replication::path::skip_link_target;
+use crate::replication::request::{EventAtRequest, Ordering, Request};
+
+/// Validates the events and payloads of a response one pair at a time.
+///
+/// The validator only keeps the digests of the last event it saw, so it works on borrowed,
+/// encoded events and never needs to allocate. Feed it every pair in the order the server sent
+/// them with [Validator::push] and call [Validator::finish] once the response is exhausted.
+#[derive(Debug)]
+pub struct Validator<D: Digest> {
+    new: Output<D>,
+    lowest: Lowest<D>,
+    ordering: Ordering,
+    format: DigestFormat,
+    previous: Option<Linked<D>>,
+}
+
+/// Where the lowest event of the path has to be.
+#[derive(Debug)]
+enum Lowest<D: Digest> {
+    /// Linked to `old`, or the root if the client knows nothing.
+    LinkedTo(Option<Output<D>>),
+    /// The event with this sequence number.
+    At(u64),
+}
+
+/// Which link of the upper event on the path points at the lower one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Link {
+    Predecessor,
+    Skip,
+}
+
+/// Everything needed about a validated event to check its neighbours on the path.
+#[derive(Debug)]
+struct Linked<D: Digest> {
+    digest: Output<D>,
+    sequence_number: u64,
+    links: Option<(Output<D>, Output<D>)>,
+    delta: (Output<D>, u64),
+    skip_delta: (Output<D>, u64),
+    payload: Option<(Output<D>, u64)>,
+}
+
+impl<D: Digest> Linked<D> {
+    fn new(digest: Output<D>, event: &EventRef, payload: Option<&[u8]>) -> Self {
+        let output = |bytes: &[u8]| <&Output<D>>::from(bytes).clone();
+        let (links, delta, skip_delta) = match *event {
+            EventRef::Root {
+                delta_digest,
+                delta_size,
+            } => (
+                None,
+                (output(delta_digest), delta_size),
+                (output(delta_digest), delta_size),
+            ),
+            EventRef::Child {
+                predecessor_event_link,
+                delta_digest,
+                delta_size,
+                skip_event_link,
+                skip_delta_digest,
+                skip_delta_size,
+                ..
+            } => (
+                Some((output(predecessor_event_link), output(skip_event_link))),
+                (output(delta_digest), delta_size),
+                (output(skip_delta_digest), skip_delta_size),
+            ),
+        };
+
+        Linked {
+            digest,
+            sequence_number: event.sequence_number().get(),
+            links,
+            delta,
+            skip_delta,
+            payload: payload.map(|payload| (D::digest(payload), payload.len() as u64)),
+        }
+    }
+
+    /// Finds the link from this event to the event with `digest`.
+    fn link_to(&self, digest: &Output<D>) -> Option<Link> {
+        let (predecessor, skip) = self.links.as_ref()?;
+        if predecessor == digest {
+            Some(Link::Predecessor)
+        } else if skip == digest {
+            Some(Link::Skip)
+        } else {
+            None
+        }
+    }
+
+    /// Checks the payload sent with this event against the delta for the link that was followed
+    /// down the path. The root has no links, its payload is its delta.
+    fn check_payload(&self, link: Option<Link>) -> Result<(), Error> {
+        let expected = match link {
+            Some(Link::Skip) => &self.skip_delta,
+            _ => &self.delta,
+        };
+        if let Some(payload) = &self.payload {
+            ensure!(payload == expected, PayloadDidNotMatchDelta);
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `lower` is linked from `upper` and returns the link.
+fn link_between<D: Digest>(upper: &Linked<D>, lower: &Linked<D>) -> Result<Link, Error> {
+    let link = upper
+        .link_to(&lower.digest)
+        .context(EventWasNotLinkedFromPrevious)?;
+    let sequence_numbers_match = match link {
+        Link::Predecessor => lower.sequence_number + 1 == upper.sequence_number,
+        Link::Skip => lower.sequence_number == skip_link_target(upper.sequence_number),
+    };
+    ensure!(sequence_numbers_match, SequenceNumberDidNotMatchLink);
+    Ok(link)
+}
+
+impl<D: Digest> Validator<D> {
+    pub fn new(request: &Request<D>) -> Self {
+        Validator {
+            new: request.new.clone(),
+            lowest: Lowest::LinkedTo(request.old.clone()),
+            ordering: request.ordering,
+            format: DigestFormat::Raw,
+            previous: None,
+        }
+    }
+
+    /// Validates the path down to the event an [EventAtRequest] asks for, which is the last event
+    /// of the response.
+    pub fn for_event_at(request: &EventAtRequest<D>) -> Self {
+        Validator {
+            new: request.new.clone(),
+            lowest: Lowest::At(request.sequence_number.get()),
+            ordering: Ordering::Descending,
+            format: DigestFormat::Raw,
+            previous: None,
+        }
+    }
+
+    /// Expects events encoded with [crate::Event::encode_multihash], rejecting any event with a
+    /// digest from another algorithm.
+    pub fn with_multihash(mut self) -> Self
+    where
+        D: MultihashDigest,
+    {
+        self.format = DigestFormat::multihash::<D>();
+        self
+    }
+
+    /// Validates the next encoded event and its encoded payload, if the server sent one.
+    pub fn push<'a>(
+        &mut self,
+        event: &'a [u8],
+        payload: Option<&[u8]>,
+    ) -> Result<EventRef<'a>, Error> {
+        let decoded = EventRef::decode_with_format::<D>(event, self.format).context(DecodeEvent)?;
+        let current = Linked::new(D::digest(event), &decoded, payload);
+
+        match (self.ordering, self.previous.take()) {
+            (Ordering::Descending, None) => {
+                ensure!(
+                    current.digest == self.new,
+                    FirstEventHashDidNotMatchHashOfRequestNew
+                );
+            }
+            (Ordering::Descending, Some(previous)) => {
+                let link = link_between(&previous, &current)?;
+                previous.check_payload(Some(link))?;
+            }
+            (Ordering::Ascending, None) => {
+                let link = self.link_to_old(&current)?;
+                current.check_payload(link)?;
+            }
+            (Ordering::Ascending, Some(previous)) => {
+                let link = link_between(&current, &previous)?;
+                current.check_payload(Some(link))?;
+            }
+        }
+
+        self.previous = Some(current);
+        Ok(decoded)
+    }
+
+    /// Checks that the events pushed so far form a complete path between `new` and `old`.
+    ///
+    /// The path from an event to itself is empty.
+    pub fn finish(self) -> Result<(), Error> {
+        let last = match (&self.previous, &self.lowest) {
+            (None, Lowest::LinkedTo(Some(old))) if *old == self.new => return Ok(()),
+            (previous, _) => previous.as_ref().context(ExpectedAtLeastOneEventInEvents)?,
+        };
+
+        match self.ordering {
+            Ordering::Descending => {
+                let link = self.link_to_old(last)?;
+                last.check_payload(link)
+            }
+            Ordering::Ascending => {
+                ensure!(
+                    last.digest == self.new,
+                    LastEventHashDidNotMatchHashOfRequestNew
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks the events pushed so far form a prefix of the path, for a response the server cut
+    /// short.
+    ///
+    /// Returns the digest of the last event, where the rest of the path continues from, or `None`
+    /// if the events turn out to be the complete path. In order of descending depth the payload
+    /// of the last event can't be checked until the next event is known, so the server must leave
+    /// it out and send it again at the start of the next response.
+    pub fn finish_partial(self) -> Result<Option<Output<D>>, Error> {
+        let last = self
+            .previous
+            .as_ref()
+            .context(ExpectedAtLeastOneEventInEvents)?;
+
+        let complete = match self.ordering {
+            Ordering::Descending => self.link_to_old(last).is_ok(),
+            Ordering::Ascending => last.digest == self.new,
+        };
+        if complete {
+            self.finish()?;
+            return Ok(None);
+        }
+        if let Ordering::Descending = self.ordering {
+            ensure!(last.payload.is_none(), UnverifiablePayload);
+        }
+        Ok(Some(last.digest.clone()))
+    }
+
+    /// Checks a proof that `old` is not an ancestor of `new`, consuming the validator.
+    ///
+    /// The proof is the encoded `old` event and the shortest path from `new` down to the event at
+    /// the same sequence number, which has to be a different event, e.g. on another fork. If `old`
+    /// is not below `new` the path is just `new`, which has to be a different event if it's at the
+    /// same sequence number.
+    pub fn check_not_ancestor<'a>(
+        self,
+        old_event: &[u8],
+        path: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<(), Error> {
+        let old = match &self.lowest {
+            Lowest::LinkedTo(Some(old)) => old,
+            _ => return UnexpectedOldNotAncestorOfNew.fail(),
+        };
+        ensure!(
+            &D::digest(old_event) == old,
+            OldEventHashDidNotMatchHashOfRequestOld
+        );
+        let old_sequence_number = EventRef::decode_with_format::<D>(old_event, self.format)
+            .context(DecodeEvent)?
+            .sequence_number()
+            .get();
+
+        let mut validator = Validator::<D> {
+            new: self.new.clone(),
+            lowest: Lowest::At(old_sequence_number),
+            ordering: Ordering::Descending,
+            format: self.format,
+            previous: None,
+        };
+        let mut path = path.into_iter();
+        let first = path.next().context(ExpectedAtLeastOneEventInEvents)?;
+        let new_sequence_number = validator.push(first, None)?.sequence_number().get();
+        if old_sequence_number >= new_sequence_number {
+            ensure!(path.next().is_none(), TooManyEvents);
+            ensure!(&self.new != old, OldWasAncestorOfNew);
+            return Ok(());
+        }
+
+        for event in path {
+            validator.push(event, None)?;
+        }
+        let lowest = validator
+            .previous
+            .as_ref()
+            .map(|lowest| lowest.digest.clone());
+        validator.finish()?;
+        ensure!(lowest.as_ref() != Some(old), OldWasAncestorOfNew);
+        Ok(())
+    }
+
+    /// Checks where the path ends and returns the link the lowest event was reached through, if
+    /// it is linked to `old`.
+    fn link_to_old(&self, lowest: &Linked<D>) -> Result<Option<Link>, Error> {
+        match &self.lowest {
+            Lowest::LinkedTo(Some(old)) => {
+                lowest.link_to(old).map(Some).context(EventDidNotLinkToOld)
+            }
+            Lowest::LinkedTo(None) => {
+                ensure!(lowest.links.is_none(), EventWasNotRoot);
+                Ok(None)
+            }
+            Lowest::At(sequence_number) => {
+                ensure!(
+                    lowest.sequence_number == *sequence_number,
+                    EventWasNotAtSequenceNumber
+                );
+                Ok(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use digest::{Digest, Output};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, AsErrorSource, Snafu};
+
+use super::ResponseOptions;
+use crate::replication::response::dto::{Error as ResponseDtoError, Response};
+use crate::{CanonicalEncoding, Semigroup};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Subscription {
+    pub root: Vec<u8>,
+    pub known: Option<Vec<u8>>,
+    pub responses: Option<ResponseOptions>,
+}
+
+impl Subscription {
+    pub fn from_subscription<D: Digest>(subscription: &super::Subscription<D>) -> Self {
+        Subscription {
+            root: subscription.root.to_vec(),
+            known: subscription.known.as_ref().map(|known| known.to_vec()),
+            responses: subscription.responses,
+        }
+    }
+}
+
+/// A Data Transfer Object representation of a [super::HeadUpdate].
+#[derive(Deserialize, Serialize, Debug)]
+pub struct HeadUpdate {
+    pub head: Vec<u8>,
+    pub sequence_number: u64,
+    pub response: Option<Response>,
+}
+
+#[derive(Snafu, Debug, Deserialize, Serialize)]
+pub enum SubscriptionError {
+    RootWasIncorrectLength,
+    KnownWasIncorrectLength,
+}
+
+#[derive(Snafu, Debug)]
+pub enum Error<E: AsErrorSource + core::fmt::Display> {
+    HeadWasIncorrectLength,
+    DecodeEvent {
+        source: crate::event::decode::error::Error,
+    },
+    DecodePayload {
+        source: E,
+    },
+    MismatchedReference,
+    MissingPayload,
+    UnsupportedCompression,
+    InvalidCompressedPayload,
+    PayloadTooLarge,
+    PayloadSizeMismatch,
+}
+
+impl<E: AsErrorSource + core::fmt::Display> From<ResponseDtoError<E>> for Error<E> {
+    fn from(error: ResponseDtoError<E>) -> Self {
+        match error {
+            ResponseDtoError::DecodeEvent { source } => Error::DecodeEvent { source },
+            ResponseDtoError::DecodePayload { source } => Error::DecodePayload { source },
+            ResponseDtoError::MismatchedReference => Error::MismatchedReference,
+            ResponseDtoError::MissingPayload => Error::MissingPayload,
+            ResponseDtoError::UnsupportedCompression => Error::UnsupportedCompression,
+            ResponseDtoError::InvalidCompressedPayload => Error::InvalidCompressedPayload,
+            ResponseDtoError::PayloadTooLarge => Error::PayloadTooLarge,
+            ResponseDtoError::PayloadSizeMismatch => Error::PayloadSizeMismatch,
+        }
+    }
+}
+
+impl<D: Digest> TryFrom<Subscription> for super::Subscription<D> {
+    type Error = SubscriptionError;
+
+    fn try_from(subscription: Subscription) -> Result<Self, Self::Error> {
+        ensure!(
+            subscription.root.len() == D::output_size(),
+            RootWasIncorrectLength
+        );
+        if let Some(known) = &subscription.known {
+            ensure!(known.len() == D::output_size(), KnownWasIncorrectLength);
+        }
+        Ok(super::Subscription {
+            root: <&Output<D>>::from(subscription.root.as_slice()).clone(),
+            known: subscription
+                .known
+                .map(|known| <&Output<D>>::from(known.as_slice()).clone()),
+            responses: subscription.responses,
+        })
+    }
+}
+
+impl<D, S> TryFrom<HeadUpdate> for super::UnvalidatedHeadUpdate<D, S>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding,
+    <S as CanonicalEncoding>::Error: AsErrorSource + core::fmt::Display,
+{
+    type Error = Error<S::Error>;
+
+    fn try_from(update: HeadUpdate) -> Result<Self, Self::Error> {
+        ensure!(
+            update.head.len() == D::output_size(),
+            HeadWasIncorrectLength
+        );
+        Ok(Self {
+            head: <&Output<D>>::from(update.head.as_slice()).clone(),
+            sequence_number: update.sequence_number,
+            response: update.response.map(TryFrom::try_from).transpose()?,
+        })
+    }
+}
+
+impl<D, S> From<super::HeadUpdate<D, S>> for HeadUpdate
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding,
+{
+    fn from(update: super::HeadUpdate<D, S>) -> Self {
+        HeadUpdate {
+            head: update.head.to_vec(),
+            sequence_number: update.sequence_number,
+            response: update.response.map(Into::into),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Following a log as its author appends to it, instead of polling with [Request]s.
+//!
+//! A client sends a [Subscription] for a log and the server sends a [HeadUpdate] whenever the log
+//! has a head the client doesn't know yet. With [ResponseOptions] each update also carries the
+//! response to the request from the previous head, so the client catches up without asking.
+use digest::{Digest, Output};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+
+use crate::replication::request::{Limits, Ordering, PathLength, Request};
+use crate::replication::response::{
+    encode_event, Response, ResponseValidationError, UnvalidatedResponse, ValidResponse,
+};
+use crate::{CanonicalEncoding, Semigroup};
+
+pub mod dto;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("The update has no response although the subscription asked for one"))]
+    MissingResponse,
+    #[snafu(display("The update has a response although the subscription didn't ask for one"))]
+    UnexpectedResponse,
+    #[snafu(display("The response in the update is invalid: {}", source))]
+    InvalidResponse { source: ResponseValidationError },
+    #[snafu(display("The head in the response is not at the sequence number of the update"))]
+    SequenceNumberDidNotMatchHead,
+    #[snafu(display("The path in the response doesn't start at the root of the subscription"))]
+    PathDidNotStartAtRoot,
+    #[snafu(display("The response in the update is partial"))]
+    PartialResponse,
+}
+
+/// How to build the [Request] whose response is sent with every [HeadUpdate].
+#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
+pub struct ResponseOptions {
+    pub ordering: Ordering,
+    pub path_length: PathLength,
+    pub include_values: bool,
+    /// An update whose response is cut short by these or the server's budget is rejected with
+    /// [Error::PartialResponse], see [Subscription].
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+/// Asks for the new heads of the log with the root event `root`.
+///
+/// Both sides keep a copy and [advance](Subscription::advance) it with every update whose response
+/// has the whole path from `known`. Otherwise neither copy advances and the next update is from
+/// the same head again. A client whose updates keep being cut short catches up with [Request]s
+/// instead, following their [continuation](ValidResponse::continuation)s, and subscribes again from
+/// the head it reached.
+#[derive(Debug)]
+pub struct Subscription<D: Digest> {
+    pub root: Output<D>,
+    /// The latest head the client knows, or `None` to get the path from the root.
+    pub known: Option<Output<D>>,
+    /// `None` for just the heads.
+    pub responses: Option<ResponseOptions>,
+}
+
+// Not derived, which would require `D: Clone`.
+impl<D: Digest> Clone for Subscription<D> {
+    fn clone(&self) -> Self {
+        Subscription {
+            root: self.root.clone(),
+            known: self.known.clone(),
+            responses: self.responses,
+        }
+    }
+}
+
+impl<D: Digest> Subscription<D> {
+    /// Counts `head` as known, and returns the request the update for it answers if the
+    /// subscription asks for responses.
+    pub fn advance(&mut self, head: Output<D>) -> Option<Request<D>> {
+        let old = self.known.replace(head.clone());
+        self.responses.map(|options| Request {
+            new: head,
+            old,
+            ordering: options.ordering,
+            path_length: options.path_length,
+            include_values: options.include_values,
+            limits: options.limits,
+        })
+    }
+}
+
+/// Tells a subscriber about a new head, see [crate::store::Store::update].
+#[derive(Debug)]
+pub struct HeadUpdate<D: Digest, S: Semigroup + CanonicalEncoding> {
+    pub head: Output<D>,
+    pub sequence_number: u64,
+    pub response: Option<Response<D, S>>,
+}
+
+#[derive(Debug)]
+pub struct UnvalidatedHeadUpdate<D: Digest, S: Semigroup + CanonicalEncoding> {
+    pub head: Output<D>,
+    pub sequence_number: u64,
+    pub response: Option<UnvalidatedResponse<D, S>>,
+}
+
+/// Treats an update built in the same process as untrusted, e.g. to test a server.
+impl<D: Digest, S: Semigroup + CanonicalEncoding> From<HeadUpdate<D, S>>
+    for UnvalidatedHeadUpdate<D, S>
+{
+    fn from(update: HeadUpdate<D, S>) -> Self {
+        UnvalidatedHeadUpdate {
+            head: update.head,
+            sequence_number: update.sequence_number,
+            response: update.response.map(Into::into),
+        }
+    }
+}
+
+impl<D: Digest, S: Semigroup + CanonicalEncoding> UnvalidatedHeadUpdate<D, S> {
+    /// Validates the response against the client's copy of the subscription, and advances it if
+    /// the update is valid.
+    ///
+    /// The head and its sequence number can only be checked if the update has a response.
+    pub fn try_into_valid_update(
+        self,
+        subscription: &mut Subscription<D>,
+    ) -> Result<ValidHeadUpdate<D, S>, Error> {
+        let mut advanced = subscription.clone();
+        let response = match (advanced.advance(self.head.clone()), self.response) {
+            (Some(request), Some(response)) => Some(
+                response
+                    .try_into_valid_response(request)
+                    .context(InvalidResponse)?,
+            ),
+            (None, None) => None,
+            (Some(_), None) => return MissingResponse.fail(),
+            (None, Some(_)) => return UnexpectedResponse.fail(),
+        };
+        if let Some(response) = &response {
+            // The head and the root are only both in a response with the whole path.
+            ensure!(response.continuation.is_none(), PartialResponse);
+            let highest = response
+                .events
+                .iter()
+                .map(|event| event.sequence_number().get())
+                .max();
+            ensure!(
+                highest == Some(self.sequence_number),
+                SequenceNumberDidNotMatchHead
+            );
+            if subscription.known.is_none() {
+                let lowest = response
+                    .events
+                    .iter()
+                    .min_by_key(|event| event.sequence_number());
+                ensure!(
+                    lowest.map(|event| D::digest(&encode_event(event)))
+                        == Some(subscription.root.clone()),
+                    PathDidNotStartAtRoot
+                );
+            }
+        }
+        *subscription = advanced;
+        Ok(ValidHeadUpdate {
+            head: self.head,
+            sequence_number: self.sequence_number,
+            response,
+        })
+    }
+}
+
+#[readonly::make]
+#[derive(Debug)]
+pub struct ValidHeadUpdate<D: Digest, S: Semigroup> {
+    pub head: Output<D>,
+    pub sequence_number: u64,
+    pub response: Option<ValidResponse<D, S>>,
+}
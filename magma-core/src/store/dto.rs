@@ -0,0 +1,48 @@
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use digest::{Digest, Output};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, Snafu};
+
+use crate::log::LogStats;
+
+/// A Data Transfer Object representation of a [super::Head].
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Head {
+    pub root: Vec<u8>,
+    pub head: Vec<u8>,
+    pub sequence_number: u64,
+    pub stats: LogStats,
+}
+
+#[derive(Snafu, Debug, Deserialize, Serialize)]
+pub enum Error {
+    RootWasIncorrectLength,
+    HeadWasIncorrectLength,
+}
+
+impl<D: Digest> From<&super::Head<D>> for Head {
+    fn from(head: &super::Head<D>) -> Self {
+        Head {
+            root: head.root.to_vec(),
+            head: head.head.to_vec(),
+            sequence_number: head.sequence_number,
+            stats: head.stats,
+        }
+    }
+}
+
+impl<D: Digest> TryFrom<Head> for super::Head<D> {
+    type Error = Error;
+
+    fn try_from(head: Head) -> Result<Self, Self::Error> {
+        ensure!(head.root.len() == D::output_size(), RootWasIncorrectLength);
+        ensure!(head.head.len() == D::output_size(), HeadWasIncorrectLength);
+        Ok(super::Head {
+            root: <&Output<D>>::from(&head.root[..]).clone(),
+            head: <&Output<D>>::from(&head.head[..]).clone(),
+            sequence_number: head.sequence_number,
+            stats: head.stats,
+        })
+    }
+}
@@ -0,0 +1,587 @@
+//! Many logs in one store, e.g. for a server hosting the logs of many authors.
+//!
+//! A log is identified by the digest of its root event. Requests only name events by their
+//! digest, so the store indexes the events of all its logs and answers every request from the log
+//! that holds `new`.
+use alloc::{collections::BTreeMap, vec::Vec};
+use digest::{Digest, Output};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+use crate::event::decode::error::Error as DecodeError;
+use crate::log::{self, Entry, Log, LogStats, Pruned, RetentionPolicy};
+use crate::replication::checkpoint::{Checkpoint, CheckpointResponse};
+use crate::replication::request::{Budget, CheckpointRequest, EventAtRequest, Request};
+use crate::replication::response::Response;
+use crate::replication::subscription::{HeadUpdate, Subscription};
+use crate::{CanonicalEncoding, Event, Semigroup};
+
+pub mod dto;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("Failed to decode event: {}", source))]
+    DecodeEvent { source: DecodeError },
+    #[snafu(display("The store already has a log with this root"))]
+    LogAlreadyExists,
+    #[snafu(display("An empty log can't be added to a store"))]
+    EmptyLog,
+    #[snafu(display("The store has no log with this root"))]
+    UnknownLog,
+    #[snafu(display("The event links to an event that is not in the store"))]
+    UnknownPredecessor,
+    #[snafu(display("The event links to an event that is not the head of its log"))]
+    PredecessorWasNotHead,
+    #[snafu(display("The checkpoint is for an event that is not in the store"))]
+    UnknownCheckpointEvent,
+    #[snafu(display("{}", source))]
+    LogRejected { source: log::Error },
+}
+
+/// The latest event of a log in a [Store].
+#[derive(Debug)]
+pub struct Head<D: Digest> {
+    /// The digest of the root event, which identifies the log.
+    pub root: Output<D>,
+    pub head: Output<D>,
+    pub sequence_number: u64,
+    pub stats: LogStats,
+}
+
+/// Logs, each identified by the digest of its root event, and an index of all their events.
+#[derive(Debug, Clone)]
+pub struct Store<D: Digest, S> {
+    logs: Vec<Log<D, S>>,
+    /// The index of the log each event is in, and its sequence number there.
+    events: BTreeMap<Output<D>, (usize, u64)>,
+    /// Whether new logs are created with [Log::with_accumulated_digests].
+    commits_accumulated: bool,
+}
+
+impl<D: Digest, S> Default for Store<D, S> {
+    fn default() -> Self {
+        Store {
+            logs: Vec::new(),
+            events: BTreeMap::new(),
+            commits_accumulated: false,
+        }
+    }
+}
+
+impl<D, S> Store<D, S>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store whose new logs commit to their accumulated values, see
+    /// [Log::with_accumulated_digests].
+    pub fn with_accumulated_digests() -> Self {
+        Store {
+            commits_accumulated: true,
+            ..Self::default()
+        }
+    }
+
+    /// The number of logs.
+    pub fn len(&self) -> usize {
+        self.logs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.logs.is_empty()
+    }
+
+    pub fn logs(&self) -> impl Iterator<Item = &Log<D, S>> {
+        self.logs.iter()
+    }
+
+    /// The log whose root event has the digest `root`.
+    pub fn log(&self, root: &Output<D>) -> Option<&Log<D, S>> {
+        self.log_index(root).map(|index| &self.logs[index])
+    }
+
+    /// Finds the event with `digest` in any of the logs.
+    pub fn find(&self, digest: &Output<D>) -> Option<&Entry<D, S>> {
+        let (index, sequence_number) = self.events.get(digest)?;
+        self.logs[*index].get(*sequence_number)
+    }
+
+    /// The head of every log, in the order they were added.
+    pub fn heads(&self) -> impl Iterator<Item = Head<D>> + '_ {
+        self.logs.iter().map(|log| Head {
+            root: root(log).digest.clone(),
+            head: log
+                .head()
+                .expect("Logs in a store are never empty")
+                .digest
+                .clone(),
+            sequence_number: log.len(),
+            stats: log.stats(),
+        })
+    }
+
+    pub fn stats(&self, root: &Output<D>) -> Option<LogStats> {
+        self.log(root).map(Log::stats)
+    }
+
+    /// Adds a log that was built elsewhere, e.g. loaded from a file.
+    pub fn insert(&mut self, log: Log<D, S>) -> Result<(), Error> {
+        let root = log.get(1).context(EmptyLog)?;
+        ensure!(!self.events.contains_key(&root.digest), LogAlreadyExists);
+
+        let index = self.logs.len();
+        for entry in log.entries() {
+            self.events
+                .insert(entry.digest.clone(), (index, entry.sequence_number()));
+        }
+        self.logs.push(log);
+        Ok(())
+    }
+
+    /// Starts a new log with a root event for `payload`.
+    pub fn create(&mut self, payload: S) -> Result<&Entry<D, S>, Error> {
+        let mut log = self.new_log();
+        log.append(payload);
+        self.insert(log)?;
+        Ok(self.head_of(self.logs.len() - 1))
+    }
+
+    /// Writes a new event for `payload` to the end of the log with the root `root`.
+    pub fn append(&mut self, root: &Output<D>, payload: S) -> Result<&Entry<D, S>, Error> {
+        let index = self.log_index(root).context(UnknownLog)?;
+        let entry = self.logs[index].append(payload);
+        self.events
+            .insert(entry.digest.clone(), (index, entry.sequence_number()));
+        Ok(entry)
+    }
+
+    /// Adds an event received from elsewhere. A root event starts a new log, any other event
+    /// must link to the head of a log in the store.
+    pub fn push(&mut self, encoded: &[u8], payload: S) -> Result<&Entry<D, S>, Error> {
+        match Event::<D>::decode(encoded).context(DecodeEvent)? {
+            Event::Root { .. } => {
+                let mut log = self.new_log();
+                log.push(encoded, payload).context(LogRejected)?;
+                self.insert(log)?;
+                Ok(self.head_of(self.logs.len() - 1))
+            }
+            Event::Child {
+                predecessor_event_link,
+                ..
+            } => {
+                let (index, sequence_number) = *self
+                    .events
+                    .get(&predecessor_event_link)
+                    .context(UnknownPredecessor)?;
+                ensure!(
+                    sequence_number == self.logs[index].len(),
+                    PredecessorWasNotHead
+                );
+                let entry = self.logs[index]
+                    .push(encoded, payload)
+                    .context(LogRejected)?;
+                self.events
+                    .insert(entry.digest.clone(), (index, entry.sequence_number()));
+                Ok(entry)
+            }
+        }
+    }
+
+    /// Publishes a checkpoint in the log of its event, see [Log::add_checkpoint].
+    pub fn add_checkpoint(&mut self, checkpoint: Checkpoint<D, S>) -> Result<(), Error> {
+        let (index, _) = *self
+            .events
+            .get(&checkpoint.event)
+            .context(UnknownCheckpointEvent)?;
+        self.logs[index]
+            .add_checkpoint(checkpoint)
+            .context(LogRejected)
+    }
+
+    /// Prunes every log with the same policy, and returns what was pruned from which log.
+    pub fn prune(&mut self, policy: &RetentionPolicy) -> Vec<(Output<D>, Pruned)> {
+        self.logs
+            .iter_mut()
+            .map(|log| (root(log).digest.clone(), log.prune(policy)))
+            .filter(|(_, pruned)| !pruned.sequence_numbers.is_empty())
+            .collect()
+    }
+
+    /// Answers a [Request] from the log that holds `new`.
+    pub fn respond(&self, request: &Request<D>) -> Response<D, S> {
+        self.respond_within(request, &Budget::default())
+    }
+
+    /// Answers a [Request] like [Store::respond], within `budget`, see [Log::respond_within].
+    ///
+    /// If `old` is in another log, the answer proves that it isn't an ancestor of `new`.
+    pub fn respond_within(&self, request: &Request<D>, budget: &Budget) -> Response<D, S> {
+        let (index, new) = match self.events.get(&request.new) {
+            Some(found) => *found,
+            None => return Response::UnknownEvent,
+        };
+        let old = match &request.old {
+            Some(old) => match self.events.get(old) {
+                Some((old_index, old)) if *old_index != index => {
+                    let old = &self.entry(*old_index, *old).event;
+                    return self.logs[index].not_ancestor(new, old);
+                }
+                Some((_, old)) => Some(*old),
+                None => return Response::UnknownEvent,
+            },
+            None => None,
+        };
+        self.logs[index].respond_from(new, old, request, budget)
+    }
+
+    /// Answers an [EventAtRequest] from the log that holds `new`.
+    pub fn respond_event_at(&self, request: &EventAtRequest<D>) -> Response<D, S> {
+        match self.events.get(&request.new) {
+            Some((index, new)) => self.logs[*index].respond_event_at_from(*new, request),
+            None => Response::UnknownEvent,
+        }
+    }
+
+    /// Answers a [CheckpointRequest] from the log that holds `new`.
+    pub fn respond_checkpoint(&self, request: &CheckpointRequest<D>) -> CheckpointResponse<D, S> {
+        self.respond_checkpoint_within(request, &Budget::default())
+    }
+
+    /// Answers a [CheckpointRequest] like [Store::respond_checkpoint], within `budget`, see
+    /// [Log::respond_checkpoint_within].
+    pub fn respond_checkpoint_within(
+        &self,
+        request: &CheckpointRequest<D>,
+        budget: &Budget,
+    ) -> CheckpointResponse<D, S> {
+        match self.events.get(&request.new) {
+            Some((index, new)) => self.logs[*index].respond_checkpoint_from(*new, request, budget),
+            None => CheckpointResponse {
+                checkpoint: None,
+                path: Response::UnknownEvent,
+            },
+        }
+    }
+
+    /// The update for `subscription` if its log has a head the client doesn't know yet, which
+    /// then counts as known.
+    pub fn update(&self, subscription: &mut Subscription<D>) -> Option<HeadUpdate<D, S>> {
+        self.update_within(subscription, &Budget::default())
+    }
+
+    /// The update for `subscription` like [Store::update], with the response within `budget`.
+    ///
+    /// The head only counts as known if the response has the whole path, as the client only
+    /// advances its copy on such an update.
+    pub fn update_within(
+        &self,
+        subscription: &mut Subscription<D>,
+        budget: &Budget,
+    ) -> Option<HeadUpdate<D, S>> {
+        let head = self.log(&subscription.root)?.head()?;
+        if subscription.known.as_ref() == Some(&head.digest) {
+            return None;
+        }
+        let mut advanced = subscription.clone();
+        let request = advanced.advance(head.digest.clone());
+        let response = request.map(|request| self.respond_within(&request, budget));
+        if matches!(response, None | Some(Response::Data(_))) {
+            *subscription = advanced;
+        }
+        Some(HeadUpdate {
+            head: head.digest.clone(),
+            sequence_number: head.sequence_number(),
+            response,
+        })
+    }
+
+    fn new_log(&self) -> Log<D, S> {
+        if self.commits_accumulated {
+            Log::with_accumulated_digests()
+        } else {
+            Log::new()
+        }
+    }
+
+    fn log_index(&self, root: &Output<D>) -> Option<usize> {
+        match self.events.get(root) {
+            Some((index, 1)) => Some(*index),
+            _ => None,
+        }
+    }
+
+    fn entry(&self, index: usize, sequence_number: u64) -> &Entry<D, S> {
+        self.logs[index]
+            .get(sequence_number)
+            .expect("Indexed events are in their log")
+    }
+
+    fn head_of(&self, index: usize) -> &Entry<D, S> {
+        self.logs[index]
+            .head()
+            .expect("Logs in a store are never empty")
+    }
+}
+
+fn root<D: Digest, S: Semigroup + CanonicalEncoding + Clone>(log: &Log<D, S>) -> &Entry<D, S> {
+    log.get(1).expect("Logs in a store are never empty")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::tests::Bytes;
+    use crate::replication::request::{Limits, Ordering, PathLength};
+    use crate::replication::response::{ResponseValidationError, UnvalidatedResponse};
+    use crate::replication::subscription::{self, ResponseOptions, UnvalidatedHeadUpdate};
+    use blake2::Blake2b;
+
+    type MyStore = Store<Blake2b, Bytes>;
+
+    fn bytes(payload: &[u8]) -> Bytes {
+        Bytes(payload.to_vec())
+    }
+
+    /// Two logs of four events each.
+    fn store() -> (MyStore, Output<Blake2b>, Output<Blake2b>) {
+        let mut store = MyStore::new();
+        let alice = store.create(bytes(b"alice")).unwrap().digest;
+        let bob = store.create(bytes(b"bob")).unwrap().digest;
+        for payload in [b"a", b"b", b"c"].iter() {
+            store.append(&alice, bytes(*payload)).unwrap();
+            store.append(&bob, bytes(*payload)).unwrap();
+        }
+        (store, alice, bob)
+    }
+
+    fn request(new: Output<Blake2b>, old: Option<Output<Blake2b>>) -> Request<Blake2b> {
+        Request {
+            new,
+            old,
+            ordering: Ordering::Descending,
+            path_length: PathLength::LongestPath,
+            include_values: true,
+            limits: Limits::default(),
+        }
+    }
+
+    #[test]
+    fn answers_requests_for_every_log() {
+        let (store, alice, bob) = store();
+
+        for root in [alice, bob].iter() {
+            let head = store.log(root).unwrap().head().unwrap().digest;
+            let request = request(head, Some(*root));
+            let response: UnvalidatedResponse<_, _> = store.respond(&request).into();
+            let valid = response.try_into_valid_response(request).unwrap();
+            assert_eq!(valid.events.len(), 3);
+        }
+    }
+
+    #[test]
+    fn old_in_another_log_is_not_an_ancestor() {
+        let (mut store, alice, bob) = store();
+        store.append(&alice, bytes(b"d")).unwrap();
+        let head = store.log(&alice).unwrap().head().unwrap().digest;
+        let bob_head = store.log(&bob).unwrap().head().unwrap().digest;
+
+        for old in [bob, bob_head].iter() {
+            let request = request(head, Some(*old));
+            let response: UnvalidatedResponse<_, _> = store.respond(&request).into();
+
+            assert!(matches!(
+                response.try_into_valid_response(request),
+                Err(ResponseValidationError::OldNotAncestorOfNew)
+            ));
+        }
+    }
+
+    #[test]
+    fn heads_report_every_log() {
+        let (mut store, alice, bob) = store();
+        store.append(&bob, bytes(b"d")).unwrap();
+
+        let heads: Vec<_> = store.heads().collect();
+
+        assert_eq!(heads.len(), 2);
+        assert_eq!((heads[0].root, heads[0].sequence_number), (alice, 4));
+        assert_eq!((heads[1].root, heads[1].sequence_number), (bob, 5));
+        assert_eq!(heads[1].head, store.find(&heads[1].head).unwrap().digest);
+        assert_eq!(
+            heads[1].stats,
+            LogStats {
+                events: 5,
+                payloads: 5,
+                payload_bytes: 7,
+                checkpoints: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn push_copies_interleaved_logs() {
+        let (written, alice, bob) = store();
+        let mut store = MyStore::new();
+
+        for sequence_number in 1..=4 {
+            for root in [alice, bob].iter() {
+                let entry = written.log(root).unwrap().get(sequence_number).unwrap();
+                store
+                    .push(&entry.encoded, entry.payload.clone().unwrap())
+                    .unwrap();
+            }
+        }
+
+        let heads = store.heads().map(|head| head.head);
+        assert!(heads.eq(written.heads().map(|head| head.head)));
+    }
+
+    #[test]
+    fn push_rejects_events_that_do_not_extend_a_head() {
+        let (written, alice, _) = store();
+        let log = written.log(&alice).unwrap();
+        let mut store = MyStore::new();
+
+        let res = store.push(&log.get(2).unwrap().encoded, bytes(b"a"));
+        assert!(matches!(res, Err(Error::UnknownPredecessor)));
+
+        for entry in log.entries().take(3) {
+            store
+                .push(&entry.encoded, entry.payload.clone().unwrap())
+                .unwrap();
+        }
+        let res = store.push(&log.get(3).unwrap().encoded, bytes(b"b"));
+        assert!(matches!(res, Err(Error::PredecessorWasNotHead)));
+    }
+
+    #[test]
+    fn subscribers_catch_up_with_every_update() {
+        let (mut store, alice, _) = store();
+        let responses = Some(ResponseOptions {
+            ordering: Ordering::Ascending,
+            path_length: PathLength::ShortestPath,
+            include_values: true,
+            limits: Limits::default(),
+        });
+        let mut server = Subscription {
+            root: alice,
+            known: None,
+            responses,
+        };
+        let mut client = server.clone();
+
+        for payload in [b"d", b"e"].iter() {
+            let update: UnvalidatedHeadUpdate<_, _> = store.update(&mut server).unwrap().into();
+            let valid = update.try_into_valid_update(&mut client).unwrap();
+            assert_eq!(
+                valid.head,
+                store.log(&alice).unwrap().head().unwrap().digest
+            );
+            assert!(store.update(&mut server).is_none());
+
+            store.append(&alice, bytes(*payload)).unwrap();
+        }
+        let update: UnvalidatedHeadUpdate<_, _> = store.update(&mut server).unwrap().into();
+        let valid = update.try_into_valid_update(&mut client).unwrap();
+
+        assert_eq!(valid.sequence_number, 6);
+        assert_eq!(valid.response.as_ref().unwrap().events.len(), 1);
+    }
+
+    #[test]
+    fn rejects_an_update_for_another_head() {
+        let (store, alice, bob) = store();
+        let mut server = Subscription {
+            root: bob,
+            known: None,
+            responses: Some(ResponseOptions {
+                ordering: Ordering::Descending,
+                path_length: PathLength::ShortestPath,
+                include_values: false,
+                limits: Limits::default(),
+            }),
+        };
+        let mut client = server.clone();
+
+        let mut update: UnvalidatedHeadUpdate<_, _> = store.update(&mut server).unwrap().into();
+        update.head = store.log(&alice).unwrap().head().unwrap().digest;
+        let res = update.try_into_valid_update(&mut client);
+
+        assert!(matches!(
+            res,
+            Err(subscription::Error::InvalidResponse { .. })
+        ));
+        assert_eq!(client.known, None);
+    }
+
+    #[test]
+    fn rejects_an_update_from_another_log() {
+        let (store, alice, bob) = store();
+        let mut server = subscription(alice, Limits::default());
+        let mut client = Subscription {
+            root: bob,
+            ..server.clone()
+        };
+
+        let update: UnvalidatedHeadUpdate<_, _> = store.update(&mut server).unwrap().into();
+        let res = update.try_into_valid_update(&mut client);
+
+        assert!(matches!(
+            res,
+            Err(subscription::Error::PathDidNotStartAtRoot)
+        ));
+        assert_eq!(client.known, None);
+    }
+
+    #[test]
+    fn rejects_partial_updates_and_validates_the_next_one() {
+        let (store, alice, _) = store();
+        let budget = Budget {
+            max_events_walked: Some(2),
+            max_bytes_read: None,
+        };
+        let mut server = subscription(alice, Limits::default());
+        let mut client = server.clone();
+
+        let update: UnvalidatedHeadUpdate<_, _> =
+            store.update_within(&mut server, &budget).unwrap().into();
+        let res = update.try_into_valid_update(&mut client);
+
+        assert!(matches!(res, Err(subscription::Error::PartialResponse)));
+        assert_eq!(client.known, None);
+        assert_eq!(server.known, None);
+
+        let update: UnvalidatedHeadUpdate<_, _> = store.update(&mut server).unwrap().into();
+        let valid = update.try_into_valid_update(&mut client).unwrap();
+
+        assert_eq!(valid.sequence_number, 4);
+        assert_eq!(client.known, server.known);
+    }
+
+    /// Subscribes to the whole path from the root, in order of ascending depth.
+    fn subscription(root: Output<Blake2b>, limits: Limits) -> Subscription<Blake2b> {
+        Subscription {
+            root,
+            known: None,
+            responses: Some(ResponseOptions {
+                ordering: Ordering::Ascending,
+                path_length: PathLength::LongestPath,
+                include_values: true,
+                limits,
+            }),
+        }
+    }
+
+    #[test]
+    fn rejects_a_second_log_with_the_same_root() {
+        let (mut store, _, _) = store();
+
+        let res = store.create(bytes(b"alice"));
+
+        assert!(matches!(res, Err(Error::LogAlreadyExists)));
+        assert_eq!(store.len(), 2);
+    }
+}
@@ -0,0 +1,588 @@
+//! Exercises the data transfer objects, which need `alloc` but not `std`. CI runs this against a
+//! build with `--no-default-features --features alloc`.
+#![cfg(feature = "alloc")]
+
+use magma_core::*;
+use snafu::Snafu;
+
+/// Concatenates byte strings, encoded as the bytes themselves.
+#[derive(Debug, Clone, PartialEq)]
+struct Bytes(Vec<u8>);
+
+#[derive(Snafu, Debug)]
+enum BytesError {
+    BufferTooSmall,
+}
+
+impl Semigroup for Bytes {
+    fn combine(&self, other: &Self) -> Self {
+        Bytes([self.0.as_slice(), other.0.as_slice()].concat())
+    }
+}
+
+impl CanonicalEncoding for Bytes {
+    type Error = BytesError;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        snafu::ensure!(buffer.len() >= self.0.len(), BufferTooSmall);
+        buffer[..self.0.len()].copy_from_slice(&self.0);
+        Ok(self.0.len())
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), Self::Error> {
+        Ok((Bytes(buffer.to_vec()), &[]))
+    }
+
+    fn encoding_length(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Runs the suite once per digest, so nothing assumes a particular `D::output_size()`.
+macro_rules! digest_suite {
+    ($($name:ident => $digest:ty),* $(,)?) => {$(
+        mod $name {
+            use super::Bytes;
+            use magma_core::event::dto::{
+                Accumulated as AccumulatedDto, Error as EventDtoError, Event as EventDto,
+            };
+            use magma_core::replication::reconciliation::dto::Range as RangeDto;
+            use magma_core::replication::reconciliation::{HeadSet, Range, Reconciliation, Summary};
+            use magma_core::replication::request::dto::{
+                EventAtRequest as EventAtRequestDto, Request as RequestDto,
+            };
+            use magma_core::replication::request::{EventAtRequest, Limits, Ordering, PathLength, Request};
+            use magma_core::replication::response::dto::{
+                BatchError, BatchResponse, Compression, Error as ResponseDtoError,
+                EventPayloadPair as PairDto, Payload as PayloadDto, Response as ResponseDto,
+            };
+            use magma_core::replication::response::{EventPayloadPair, Response, UnvalidatedResponse};
+            use magma_core::store::dto::Head as HeadDto;
+            use magma_core::store::Store;
+            use magma_core::*;
+            use proptest::prelude::*;
+            use std::convert::{TryFrom, TryInto};
+
+            type MyDigest = $digest;
+            type MyEvent = Event<MyDigest>;
+
+            fn digest(event: &MyEvent) -> Output<MyDigest> {
+                let mut buffer = vec![0; event.encoding_length()];
+                event.encode(&mut buffer).unwrap();
+                MyDigest::digest(&buffer)
+            }
+
+            /// A log without skip links, one event per payload.
+            fn linear_log(payloads: &[Vec<u8>]) -> Vec<MyEvent> {
+                let mut events: Vec<MyEvent> = Vec::new();
+                for payload in payloads {
+                    let delta_digest = MyDigest::digest(payload);
+                    let delta_size = payload.len() as u64;
+                    let event = match events.last() {
+                        None => Event::Root {
+                            delta_digest,
+                            delta_size,
+                        },
+                        Some(previous) => Event::Child {
+                            sequence_number: NonZeroU64::new(events.len() as u64 + 1).unwrap(),
+                            predecessor_event_link: digest(previous),
+                            delta_digest: delta_digest.clone(),
+                            delta_size,
+                            skip_event_link: digest(previous),
+                            skip_delta_digest: delta_digest,
+                            skip_delta_size: delta_size,
+                            accumulated: None,
+                        },
+                    };
+                    events.push(event);
+                }
+                events
+            }
+
+            #[test]
+            fn event_dto_checks_digest_lengths() {
+                let size = MyDigest::output_size();
+                let event = EventDto::Child {
+                    sequence_number: NonZeroU64::new(2).unwrap(),
+                    predecessor_event_link: vec![0; size],
+                    delta_digest: vec![0; size],
+                    delta_size: 0,
+                    skip_event_link: vec![0; size],
+                    skip_delta_digest: vec![0; size / 2],
+                    skip_delta_size: 0,
+                    accumulated: None,
+                };
+
+                let res = MyEvent::try_from(event);
+
+                assert!(matches!(
+                    res,
+                    Err(EventDtoError::InvalidDigestLength {
+                        expected_length,
+                        actual_length
+                    }) if expected_length == size && actual_length == size / 2
+                ));
+            }
+
+            #[test]
+            fn event_dto_checks_accumulated_digest_length() {
+                let size = MyDigest::output_size();
+                let event = EventDto::Child {
+                    sequence_number: NonZeroU64::new(2).unwrap(),
+                    predecessor_event_link: vec![0; size],
+                    delta_digest: vec![0; size],
+                    delta_size: 0,
+                    skip_event_link: vec![0; size],
+                    skip_delta_digest: vec![0; size],
+                    skip_delta_size: 0,
+                    accumulated: Some(AccumulatedDto {
+                        digest: vec![0; size + 1],
+                        size: 0,
+                    }),
+                };
+
+                let res = MyEvent::try_from(event);
+
+                assert!(matches!(
+                    res,
+                    Err(EventDtoError::InvalidDigestLength {
+                        expected_length,
+                        actual_length
+                    }) if expected_length == size && actual_length == size + 1
+                ));
+            }
+
+            #[test]
+            fn request_dto_round_trip() {
+                let request = Request::<MyDigest> {
+                    new: MyDigest::digest(b"new"),
+                    old: Some(MyDigest::digest(b"old")),
+                    ordering: Ordering::Descending,
+                    path_length: PathLength::LongestPath,
+                    include_values: false,
+                    limits: Limits::default(),
+                };
+
+                let dto: RequestDto = RequestDto::from_request(&request);
+                let decoded: Request<MyDigest> = dto.try_into().unwrap();
+
+                assert_eq!(decoded.new, request.new);
+                assert_eq!(decoded.old, request.old);
+                assert!(!decoded.include_values);
+            }
+
+            #[test]
+            fn head_dto_round_trip() {
+                let mut store = Store::<MyDigest, Bytes>::new();
+                let root = store.create(Bytes(b"a".to_vec())).unwrap().digest.clone();
+                store.append(&root, Bytes(b"bc".to_vec())).unwrap();
+                let head = store.heads().next().unwrap();
+
+                let json = serde_json::to_string(&HeadDto::from(&head)).unwrap();
+                let dto: HeadDto = serde_json::from_str(&json).unwrap();
+                let decoded: store::Head<MyDigest> = dto.try_into().unwrap();
+
+                assert_eq!(decoded.root, root);
+                assert_eq!(decoded.head, head.head);
+                assert_eq!(decoded.sequence_number, 2);
+                assert_eq!(decoded.stats, head.stats);
+            }
+
+            #[test]
+            fn range_dto_round_trip() {
+                let mut store = Store::<MyDigest, Bytes>::new();
+                store.create(Bytes(b"a".to_vec())).unwrap();
+                let heads: HeadSet<MyDigest> = store.heads().collect();
+                let message = Reconciliation::new(&heads).initiate();
+
+                let json = serde_json::to_string(&RangeDto::from(message.into_iter().next().unwrap())).unwrap();
+                let dto: RangeDto = serde_json::from_str(&json).unwrap();
+                let decoded: Range<MyDigest> = dto.try_into().unwrap();
+
+                assert!(decoded.lower.is_none() && decoded.upper.is_none());
+                assert!(matches!(
+                    decoded.summary,
+                    Summary::Heads(heads) if heads.len() == 1 && heads[0].sequence_number == 1
+                ));
+            }
+
+            #[test]
+            fn request_dto_without_limits_has_no_limits() {
+                let request = Request::<MyDigest> {
+                    new: MyDigest::digest(b"new"),
+                    old: None,
+                    ordering: Ordering::Ascending,
+                    path_length: PathLength::ShortestPath,
+                    include_values: true,
+                    limits: Limits {
+                        max_events: Some(3),
+                        max_payload_bytes: None,
+                    },
+                };
+                let mut json = serde_json::to_value(RequestDto::from_request(&request)).unwrap();
+                assert_eq!(json["limits"]["max_events"], 3);
+
+                json.as_object_mut().unwrap().remove("limits");
+                let dto: RequestDto = serde_json::from_value(json).unwrap();
+                assert_eq!(dto.limits, Limits::default());
+            }
+
+            #[test]
+            fn event_at_request_dto_round_trip() {
+                let request = EventAtRequest::<MyDigest> {
+                    new: MyDigest::digest(b"new"),
+                    sequence_number: NonZeroU64::new(7).unwrap(),
+                    include_value: true,
+                };
+
+                let dto = EventAtRequestDto::from_request(&request);
+                let decoded: EventAtRequest<MyDigest> = dto.try_into().unwrap();
+
+                assert_eq!(decoded.new, request.new);
+                assert_eq!(decoded.sequence_number.get(), 7);
+            }
+
+            proptest! {
+                #[test]
+                fn response_dto_round_trip_validates(
+                    payloads in prop::collection::vec(any::<Vec<u8>>(), 1..8),
+                    descending in any::<bool>(),
+                ) {
+                    let events = linear_log(&payloads);
+                    let (ordering, pairs): (_, Vec<_>) = if descending {
+                        (Ordering::Descending, events.iter().zip(&payloads).rev().collect())
+                    } else {
+                        (Ordering::Ascending, events.iter().zip(&payloads).collect())
+                    };
+                    let request = Request::<MyDigest> {
+                        new: digest(events.last().unwrap()),
+                        old: None,
+                        ordering,
+                        path_length: PathLength::LongestPath,
+                        include_values: true,
+                        limits: Limits::default(),
+                    };
+
+                    let response = Response::Data(
+                        pairs
+                            .into_iter()
+                            .map(|(event, payload)| EventPayloadPair {
+                                event: event.clone(),
+                                payload: Some(Bytes(payload.clone())),
+                            })
+                            .collect(),
+                    );
+                    let dto: ResponseDto = response.into();
+                    let unvalidated: UnvalidatedResponse<MyDigest, Bytes> = dto.try_into().unwrap();
+                    let valid = unvalidated.try_into_valid_response(request).unwrap();
+
+                    prop_assert_eq!(valid.events.len(), payloads.len());
+                }
+
+                #[test]
+                fn response_with_a_missing_event_is_rejected(
+                    payloads in prop::collection::vec(any::<Vec<u8>>(), 3..8),
+                    missing in any::<prop::sample::Index>(),
+                ) {
+                    let events = linear_log(&payloads);
+                    let missing = 1 + missing.index(events.len() - 2);
+                    let request = Request::<MyDigest> {
+                        new: digest(events.last().unwrap()),
+                        old: None,
+                        ordering: Ordering::Ascending,
+                        path_length: PathLength::LongestPath,
+                        include_values: false,
+                        limits: Limits::default(),
+                    };
+
+                    let response = UnvalidatedResponse::<MyDigest, Bytes>::Data(
+                        events
+                            .into_iter()
+                            .enumerate()
+                            .filter(|(i, _)| *i != missing)
+                            .map(|(_, event)| EventPayloadPair {
+                                event,
+                                payload: None,
+                            })
+                            .collect(),
+                    );
+
+                    prop_assert!(response.try_into_valid_response(request).is_err());
+                }
+
+                #[test]
+                fn batch_response_dto_shares_pairs(
+                    payloads in prop::collection::vec(any::<Vec<u8>>(), 2..8),
+                    known in any::<prop::sample::Index>(),
+                ) {
+                    let events = linear_log(&payloads);
+                    let known = known.index(events.len() - 1);
+                    let request = |old: Option<&MyEvent>| Request::<MyDigest> {
+                        new: digest(events.last().unwrap()),
+                        old: old.map(digest),
+                        ordering: Ordering::Ascending,
+                        path_length: PathLength::LongestPath,
+                        include_values: true,
+                        limits: Limits::default(),
+                    };
+                    let response = |skip: usize| Response::<MyDigest, Bytes>::Data(
+                        events
+                            .iter()
+                            .zip(&payloads)
+                            .skip(skip)
+                            .map(|(event, payload)| EventPayloadPair {
+                                event: event.clone(),
+                                payload: Some(Bytes(payload.clone())),
+                            })
+                            .collect(),
+                    );
+                    let requests = vec![request(None), request(Some(&events[known]))];
+
+                    let dto =
+                        BatchResponse::from_responses(vec![response(0), response(known + 1)], None);
+                    prop_assert_eq!(dto.pairs.len(), payloads.len());
+                    let responses = dto.into_responses::<MyDigest, Bytes>().unwrap();
+
+                    let lengths: Vec<_> = responses
+                        .into_iter()
+                        .zip(requests)
+                        .map(|(response, request)| {
+                            response.try_into_valid_response(request).unwrap().events.len()
+                        })
+                        .collect();
+                    prop_assert_eq!(lengths, vec![payloads.len(), payloads.len() - known - 1]);
+                }
+            }
+
+            #[test]
+            fn batch_response_dto_keeps_deltas_and_skip_deltas_apart() {
+                let mut store = Store::<MyDigest, Bytes>::new();
+                let root = store.create(Bytes(b"a".to_vec())).unwrap().digest.clone();
+                for payload in [b"b", b"c", b"d", b"e", b"f", b"g", b"h"] {
+                    store.append(&root, Bytes(payload.to_vec())).unwrap();
+                }
+                let head = store.heads().next().unwrap().head;
+                let requests: Vec<_> = [PathLength::LongestPath, PathLength::ShortestPath]
+                    .iter()
+                    .map(|path_length| Request::<MyDigest> {
+                        new: head.clone(),
+                        old: None,
+                        ordering: Ordering::Ascending,
+                        path_length: path_length.clone(),
+                        include_values: true,
+                        limits: Limits::default(),
+                    })
+                    .collect();
+                let responses = requests.iter().map(|request| store.respond(request)).collect();
+
+                let dto = BatchResponse::from_responses(responses, None);
+                let responses = dto.into_responses::<MyDigest, Bytes>().unwrap();
+
+                let values: Vec<_> = responses
+                    .into_iter()
+                    .zip(requests)
+                    .map(|(response, request)| {
+                        let valid = response.try_into_valid_response(request).unwrap();
+                        assert!(valid.continuation.is_none());
+                        valid.fold(None).unwrap()
+                    })
+                    .collect();
+                assert_eq!(values, vec![Bytes(b"abcdefgh".to_vec()); 2]);
+            }
+
+            /// A log of `[a], [b], [a]`, encoded as a response with values.
+            fn repeated_payloads() -> (Vec<MyEvent>, Request<MyDigest>, ResponseDto) {
+                encoded_log(vec![b"a".to_vec(), b"b".to_vec(), b"a".to_vec()], None)
+            }
+
+            fn encoded_log(
+                payloads: Vec<Vec<u8>>,
+                compression: Option<Compression>,
+            ) -> (Vec<MyEvent>, Request<MyDigest>, ResponseDto) {
+                let events = linear_log(&payloads);
+                let request = Request::<MyDigest> {
+                    new: digest(events.last().unwrap()),
+                    old: None,
+                    ordering: Ordering::Ascending,
+                    path_length: PathLength::LongestPath,
+                    include_values: true,
+                    limits: Limits::default(),
+                };
+                let response = Response::<MyDigest, Bytes>::Data(
+                    events
+                        .iter()
+                        .zip(payloads)
+                        .map(|(event, payload)| EventPayloadPair {
+                            event: event.clone(),
+                            payload: Some(Bytes(payload)),
+                        })
+                        .collect(),
+                );
+                (events, request, ResponseDto::with_compression(response, compression))
+            }
+
+            fn pairs(dto: &mut ResponseDto) -> &mut Vec<PairDto> {
+                match dto {
+                    ResponseDto::Data(pairs) => pairs,
+                    dto => panic!("Expected data, got {:?}", dto),
+                }
+            }
+
+            #[test]
+            fn response_dto_sends_repeated_payloads_once() {
+                let (events, request, mut dto) = repeated_payloads();
+
+                let last = pairs(&mut dto)[2].payload.clone();
+                assert_eq!(
+                    last,
+                    Some(PayloadDto::Reference(events[2].delta_digest().to_vec()))
+                );
+                let unvalidated: UnvalidatedResponse<MyDigest, Bytes> = dto.try_into().unwrap();
+                let valid = unvalidated.try_into_valid_response(request).unwrap();
+                assert_eq!(valid.events.len(), 3);
+            }
+
+            #[test]
+            fn response_dto_rejects_references_to_missing_payloads() {
+                let (_, _, mut dto) = repeated_payloads();
+                pairs(&mut dto)[0].payload = None;
+
+                let res = UnvalidatedResponse::<MyDigest, Bytes>::try_from(dto);
+
+                assert!(matches!(res, Err(ResponseDtoError::MissingPayload)));
+            }
+
+            #[test]
+            fn response_dto_rejects_references_to_other_payloads() {
+                let (events, _, mut dto) = repeated_payloads();
+                let other = events[1].delta_digest().to_vec();
+                pairs(&mut dto)[2].payload = Some(PayloadDto::Reference(other));
+
+                let res = UnvalidatedResponse::<MyDigest, Bytes>::try_from(dto);
+
+                assert!(matches!(res, Err(ResponseDtoError::MismatchedReference)));
+            }
+
+            #[test]
+            fn response_dto_rejects_payloads_of_another_size_than_declared() {
+                let (_, _, mut dto) = repeated_payloads();
+                pairs(&mut dto)[1].payload = Some(PayloadDto::Value(b"bb".to_vec()));
+
+                let res = UnvalidatedResponse::<MyDigest, Bytes>::try_from(dto);
+
+                assert!(matches!(res, Err(ResponseDtoError::PayloadSizeMismatch)));
+            }
+
+            #[test]
+            fn response_dto_rejects_payloads_past_the_max_size() {
+                let (_, request, dto) = encoded_log(vec![vec![1; 100], vec![2; 10]], None);
+
+                let res = dto.decode_within::<MyDigest, Bytes>(99);
+                assert!(matches!(res, Err(ResponseDtoError::PayloadTooLarge)));
+
+                let (_, _, dto) = encoded_log(vec![vec![1; 100], vec![2; 10]], None);
+                let unvalidated = dto.decode_within::<MyDigest, Bytes>(100).unwrap();
+                assert!(unvalidated.try_into_valid_response(request).is_ok());
+            }
+
+            #[cfg(feature = "lz4")]
+            #[test]
+            fn response_dto_compresses_payloads_that_get_smaller() {
+                let payloads = vec![vec![1; 1000], b"b".to_vec(), vec![1; 1000]];
+                let (_, request, mut dto) = encoded_log(payloads, Some(Compression::Lz4));
+
+                let kinds: Vec<_> = pairs(&mut dto)
+                    .iter()
+                    .map(|pair| match pair.payload.as_ref().unwrap() {
+                        PayloadDto::Value(_) => "value",
+                        PayloadDto::Compressed { .. } => "compressed",
+                        PayloadDto::Reference(_) => "reference",
+                    })
+                    .collect();
+                assert_eq!(kinds, vec!["compressed", "value", "reference"]);
+                let unvalidated: UnvalidatedResponse<MyDigest, Bytes> = dto.try_into().unwrap();
+                let valid = unvalidated.try_into_valid_response(request).unwrap();
+                assert_eq!(valid.values[2].as_ref().unwrap().0, vec![1; 1000]);
+            }
+
+            /// The payload of the first pair, compressed from `payload`.
+            #[cfg(feature = "lz4")]
+            fn compressed(payload: Vec<u8>) -> PayloadDto {
+                let (_, _, mut dto) = encoded_log(vec![payload], Some(Compression::Lz4));
+                pairs(&mut dto)[0].payload.take().unwrap()
+            }
+
+            #[cfg(feature = "lz4")]
+            #[test]
+            fn response_dto_rejects_payloads_that_decompress_past_their_delta_size() {
+                let (_, _, mut dto) = encoded_log(vec![vec![1; 1000]], Some(Compression::Lz4));
+                pairs(&mut dto)[0].payload = Some(compressed(vec![1; 100_000]));
+
+                let res = UnvalidatedResponse::<MyDigest, Bytes>::try_from(dto);
+
+                assert!(matches!(res, Err(ResponseDtoError::InvalidCompressedPayload)));
+            }
+
+            #[cfg(feature = "lz4")]
+            #[test]
+            fn response_dto_rejects_delta_sizes_no_compressed_payload_reaches() {
+                let (_, _, mut dto) = encoded_log(vec![vec![1; 1 << 20]], None);
+                pairs(&mut dto)[0].payload = Some(compressed(vec![1; 1000]));
+
+                let res = UnvalidatedResponse::<MyDigest, Bytes>::try_from(dto);
+
+                assert!(matches!(res, Err(ResponseDtoError::PayloadSizeMismatch)));
+            }
+
+            #[test]
+            fn batch_response_dto_rejects_unknown_pairs() {
+                let dto = BatchResponse {
+                    pairs: Vec::new(),
+                    responses: vec![ResponseDto::Data(vec![0])],
+                };
+
+                let res = dto.into_responses::<MyDigest, Bytes>();
+
+                assert!(matches!(res, Err(BatchError::UnknownPair { index: 0 })));
+            }
+
+            #[test]
+            fn batch_response_dto_names_the_invalid_pair() {
+                let payloads = vec![b"a".to_vec(), b"b".to_vec()];
+                let events = linear_log(&payloads);
+                let response = Response::<MyDigest, Bytes>::Data(
+                    events
+                        .iter()
+                        .zip(&payloads)
+                        .map(|(event, payload)| EventPayloadPair {
+                            event: event.clone(),
+                            payload: Some(Bytes(payload.clone())),
+                        })
+                        .collect(),
+                );
+                let mut dto = BatchResponse::from_responses(vec![response], None);
+                // A reference to its own payload, which isn't sent anywhere else.
+                dto.pairs[1].payload = Some(PayloadDto::Reference(
+                    events[1].delta_digest().to_vec(),
+                ));
+
+                let res = dto.into_responses::<MyDigest, Bytes>();
+
+                assert!(matches!(
+                    res,
+                    Err(BatchError::Pair {
+                        index: 1,
+                        source: ResponseDtoError::MissingPayload
+                    })
+                ));
+            }
+        }
+    )*};
+}
+
+digest_suite! {
+    blake2b => blake2::Blake2b,
+    blake2s => blake2::Blake2s,
+    blake3 => blake3::Hasher,
+    sha256 => sha2::Sha256,
+}
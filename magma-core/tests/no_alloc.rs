@@ -0,0 +1,293 @@
+//! Exercises the parts of the crate that work without an allocator. CI runs this against a build
+//! with `--no-default-features`, so nothing here may use `Vec` or other heap types.
+
+/// Runs the suite once per digest, so nothing assumes a particular `D::output_size()`.
+macro_rules! digest_suite {
+    ($($name:ident => $digest:ty),* $(,)?) => {$(
+        mod $name {
+            use magma_core::event::dto::EventRef;
+            use magma_core::replication::request::{EventAtRequest, Limits, Ordering, PathLength, Request};
+            use magma_core::replication::response::fixed::{EventPayloadPair, Response};
+            use magma_core::replication::response::ResponseValidationError;
+            use magma_core::*;
+
+            type MyDigest = $digest;
+            type MyEvent = Event<MyDigest>;
+
+            const BUFFER_SIZE: usize = 512;
+
+            struct Encoded {
+                bytes: [u8; BUFFER_SIZE],
+                length: usize,
+            }
+
+            impl Encoded {
+                fn new(event: &MyEvent) -> Self {
+                    let mut bytes = [0; BUFFER_SIZE];
+                    let length = event.encode(&mut bytes).unwrap();
+                    Encoded { bytes, length }
+                }
+                fn as_slice(&self) -> &[u8] {
+                    &self.bytes[..self.length]
+                }
+                fn digest(&self) -> Output<MyDigest> {
+                    MyDigest::digest(self.as_slice())
+                }
+            }
+
+            fn child(
+                sequence_number: u64,
+                predecessor: &Encoded,
+                delta: &[u8],
+                skip: &Encoded,
+                skip_delta: &[u8],
+            ) -> MyEvent {
+                Event::Child {
+                    sequence_number: NonZeroU64::new(sequence_number).unwrap(),
+                    predecessor_event_link: predecessor.digest(),
+                    delta_digest: MyDigest::digest(delta),
+                    delta_size: delta.len() as u64,
+                    skip_event_link: skip.digest(),
+                    skip_delta_digest: MyDigest::digest(skip_delta),
+                    skip_delta_size: skip_delta.len() as u64,
+                    accumulated: None,
+                }
+            }
+
+            /// A log of four events where the fourth skips back to the root.
+            fn log() -> [Encoded; 4] {
+                let root = Encoded::new(&Event::Root {
+                    delta_digest: MyDigest::digest(b"a"),
+                    delta_size: 1,
+                });
+                let second = Encoded::new(&child(2, &root, b"b", &root, b"b"));
+                let third = Encoded::new(&child(3, &second, b"c", &second, b"c"));
+                let fourth = Encoded::new(&child(4, &third, b"d", &root, b"bcd"));
+                [root, second, third, fourth]
+            }
+
+            fn request(new: Output<MyDigest>, ordering: Ordering, path_length: PathLength) -> Request<MyDigest> {
+                Request {
+                    new,
+                    old: None,
+                    ordering,
+                    path_length,
+                    include_values: true,
+                    limits: Limits::default(),
+                }
+            }
+
+            fn pair<'a>(event: &'a Encoded, payload: &'a [u8]) -> EventPayloadPair<'a> {
+                EventPayloadPair {
+                    event: event.as_slice(),
+                    payload: Some(payload),
+                }
+            }
+
+            #[test]
+            fn encode_decode_without_alloc() {
+                let [root, _, _, fourth] = log();
+
+                let decoded = MyEvent::decode(fourth.as_slice()).unwrap();
+                assert_eq!(decoded.sequence_number().get(), 4);
+
+                match EventRef::decode::<MyDigest>(fourth.as_slice()).unwrap() {
+                    EventRef::Child {
+                        skip_event_link, ..
+                    } => assert_eq!(skip_event_link, &root.digest()[..]),
+                    EventRef::Root { .. } => panic!("expected a child event"),
+                }
+            }
+
+            #[test]
+            fn validates_shortest_path_descending() {
+                let [root, _, _, fourth] = log();
+                let mut response = Response::<4>::UnknownEvent;
+                response.push(pair(&fourth, b"bcd")).unwrap();
+                response.push(pair(&root, b"a")).unwrap();
+
+                let request = request(
+                    fourth.digest(),
+                    Ordering::Descending,
+                    PathLength::ShortestPath,
+                );
+                let valid = response.try_into_valid_response(request).unwrap();
+
+                assert_eq!(valid.events.len(), 2);
+                assert_eq!(valid.values[0], Some(&b"bcd"[..]));
+            }
+
+            #[test]
+            fn validates_longest_path_ascending() {
+                let [root, second, third, fourth] = log();
+                let mut response = Response::<4>::UnknownEvent;
+                response.push(pair(&root, b"a")).unwrap();
+                response.push(pair(&second, b"b")).unwrap();
+                response.push(pair(&third, b"c")).unwrap();
+                response.push(pair(&fourth, b"d")).unwrap();
+
+                let request = request(
+                    fourth.digest(),
+                    Ordering::Ascending,
+                    PathLength::LongestPath,
+                );
+                assert!(response.try_into_valid_response(request).is_ok());
+            }
+
+            #[test]
+            fn rejects_payload_for_the_wrong_delta() {
+                let [root, _, _, fourth] = log();
+                let mut response = Response::<4>::UnknownEvent;
+                response.push(pair(&fourth, b"d")).unwrap();
+                response.push(pair(&root, b"a")).unwrap();
+
+                let request = request(
+                    fourth.digest(),
+                    Ordering::Descending,
+                    PathLength::ShortestPath,
+                );
+                let res = response.try_into_valid_response(request);
+
+                assert!(matches!(
+                    res,
+                    Err(ResponseValidationError::PayloadDidNotMatchDelta)
+                ));
+            }
+
+            #[test]
+            fn rejects_a_path_that_skips_an_event() {
+                let [root, _, third, fourth] = log();
+                let mut response = Response::<4>::UnknownEvent;
+                response.push(pair(&fourth, b"d")).unwrap();
+                response.push(pair(&third, b"c")).unwrap();
+                response.push(pair(&root, b"a")).unwrap();
+
+                let request = request(
+                    fourth.digest(),
+                    Ordering::Descending,
+                    PathLength::LongestPath,
+                );
+                let res = response.try_into_valid_response(request);
+
+                assert!(matches!(
+                    res,
+                    Err(ResponseValidationError::EventWasNotLinkedFromPrevious)
+                ));
+            }
+
+            #[test]
+            fn rejects_a_skip_link_to_another_depth() {
+                let [root, second, _, _] = log();
+                // Event 3 skip links to its predecessor, not to the root.
+                let forged = Encoded::new(&child(3, &second, b"c", &root, b"bc"));
+                let mut response = Response::<4>::UnknownEvent;
+                response.push(pair(&forged, b"bc")).unwrap();
+                response.push(pair(&root, b"a")).unwrap();
+
+                let request = request(
+                    forged.digest(),
+                    Ordering::Descending,
+                    PathLength::ShortestPath,
+                );
+                let res = response.try_into_valid_response(request);
+
+                assert!(matches!(
+                    res,
+                    Err(ResponseValidationError::SequenceNumberDidNotMatchLink)
+                ));
+            }
+
+            #[test]
+            fn rejects_a_path_that_passes_old() {
+                let [root, second, _, fourth] = log();
+                let mut response = Response::<4>::UnknownEvent;
+                response.push(pair(&fourth, b"bcd")).unwrap();
+                response.push(pair(&root, b"a")).unwrap();
+
+                let request = Request {
+                    old: Some(second.digest()),
+                    ..request(
+                        fourth.digest(),
+                        Ordering::Descending,
+                        PathLength::ShortestPath,
+                    )
+                };
+                let res = response.try_into_valid_response(request);
+
+                assert!(matches!(
+                    res,
+                    Err(ResponseValidationError::EventDidNotLinkToOld)
+                ));
+            }
+
+            #[test]
+            fn checks_proof_that_old_is_not_an_ancestor() {
+                let [_, _, third, fourth] = log();
+                let mut path = heapless::Vec::new();
+                path.push(third.as_slice()).unwrap();
+                let response: Response<4> = Response::OldNotAncestorOfNew {
+                    old: fourth.as_slice(),
+                    path,
+                };
+
+                let request = Request {
+                    old: Some(fourth.digest()),
+                    ..request(
+                        third.digest(),
+                        Ordering::Descending,
+                        PathLength::ShortestPath,
+                    )
+                };
+                let res = response.try_into_valid_response(request);
+
+                assert!(matches!(
+                    res,
+                    Err(ResponseValidationError::OldNotAncestorOfNew)
+                ));
+            }
+
+            #[test]
+            fn validates_event_at_sequence_number() {
+                let [root, _, _, fourth] = log();
+                let mut response = Response::<4>::UnknownEvent;
+                response
+                    .push(EventPayloadPair {
+                        event: fourth.as_slice(),
+                        payload: None,
+                    })
+                    .unwrap();
+                response.push(pair(&root, b"a")).unwrap();
+
+                let request = EventAtRequest::<MyDigest> {
+                    new: fourth.digest(),
+                    sequence_number: NonZeroU64::new(1).unwrap(),
+                    include_value: true,
+                };
+                let valid = response.try_into_valid_event_at(request).unwrap();
+
+                assert_eq!(valid.events[1].sequence_number().get(), 1);
+                assert_eq!(valid.values[1], Some(&b"a"[..]));
+            }
+
+            #[test]
+            fn fixed_response_rejects_more_than_capacity() {
+                let [root, second, third, _] = log();
+                let mut response = Response::<2>::UnknownEvent;
+                response.push(pair(&third, b"c")).unwrap();
+                response.push(pair(&second, b"b")).unwrap();
+
+                assert!(matches!(
+                    response.push(pair(&root, b"a")),
+                    Err(ResponseValidationError::TooManyEvents)
+                ));
+            }
+        }
+    )*};
+}
+
+digest_suite! {
+    blake2b => blake2::Blake2b,
+    blake2s => blake2::Blake2s,
+    blake3 => blake3::Hasher,
+    sha256 => sha2::Sha256,
+}
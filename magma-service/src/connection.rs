@@ -0,0 +1,431 @@
+//! Bounding what one client can make a service do.
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture, Either, FutureExt, Shared};
+use futures::stream::{BoxStream, StreamExt};
+use magma_core::replication::checkpoint::CheckpointResponse;
+use magma_core::replication::reconciliation::Range;
+use magma_core::replication::request::{CheckpointRequest, EventAtRequest, Request};
+use magma_core::replication::response::Response;
+use magma_core::replication::subscription::{HeadUpdate, Subscription};
+use magma_core::store::Head;
+use magma_core::{CanonicalEncoding, Digest, Semigroup};
+
+use super::{Error, MagmaService};
+
+/// A service as one client sees it, e.g. over one network connection.
+///
+/// At most a fixed number of the client's requests run at once, the others wait their turn, so
+/// a client sending many requests gets them answered one batch after another instead of taking
+/// over the server. Closing or dropping the connection, e.g. when the client disconnects, cancels
+/// the requests still running or waiting with [Error::Cancelled] and ends the subscriptions.
+pub struct Connection<T> {
+    service: T,
+    permits: Arc<Permits>,
+    /// Dropped when the connection closes, which completes `closed`.
+    closing: Mutex<Option<oneshot::Sender<()>>>,
+    closed: Shared<oneshot::Receiver<()>>,
+}
+
+impl<T> Connection<T> {
+    /// Serves `service` to a client, running at most `max_concurrent` of its requests at once,
+    /// which must be at least one.
+    pub fn new(service: T, max_concurrent: usize) -> Self {
+        let (closing, closed) = oneshot::channel();
+        Connection {
+            service,
+            permits: Arc::new(Permits::new(max_concurrent.max(1))),
+            closing: Mutex::new(Some(closing)),
+            closed: closed.shared(),
+        }
+    }
+
+    /// Cancels every request and subscription of the connection, and the ones that come after.
+    pub fn close(&self) {
+        self.closing.lock().unwrap().take();
+    }
+
+    /// Runs `work` once it's the request's turn, unless the connection closes first.
+    fn limit<R: Send + 'static>(
+        &self,
+        work: BoxFuture<'static, Result<R, Error>>,
+    ) -> BoxFuture<'static, Result<R, Error>> {
+        let permits = self.permits.clone();
+        let run = async move {
+            let _permit = permits.acquire().await;
+            work.await
+        };
+        let closed = self.closed.clone();
+        async move {
+            // Checks the connection first, so nothing runs once it's closed.
+            match future::select(closed, Box::pin(run)).await {
+                Either::Left(_) => Err(Error::Cancelled),
+                Either::Right((result, _)) => result,
+            }
+        }
+        .boxed()
+    }
+}
+
+impl<D, S, T> MagmaService<D, S> for Connection<T>
+where
+    D: Digest + 'static,
+    S: Semigroup + CanonicalEncoding + Send + 'static,
+    T: MagmaService<D, S>,
+{
+    fn request(&self, request: Request<D>) -> BoxFuture<'static, Result<Response<D, S>, Error>> {
+        self.limit(self.service.request(request))
+    }
+
+    /// A batch takes one turn, however many requests it has.
+    fn request_batch(
+        &self,
+        requests: Vec<Request<D>>,
+    ) -> BoxFuture<'static, Result<Vec<Response<D, S>>, Error>> {
+        self.limit(self.service.request_batch(requests))
+    }
+
+    fn request_event_at(
+        &self,
+        request: EventAtRequest<D>,
+    ) -> BoxFuture<'static, Result<Response<D, S>, Error>> {
+        self.limit(self.service.request_event_at(request))
+    }
+
+    fn request_checkpoint(
+        &self,
+        request: CheckpointRequest<D>,
+    ) -> BoxFuture<'static, Result<CheckpointResponse<D, S>, Error>> {
+        self.limit(self.service.request_checkpoint(request))
+    }
+
+    fn list_heads(&self) -> BoxFuture<'static, Result<Vec<Head<D>>, Error>> {
+        self.limit(self.service.list_heads())
+    }
+
+    fn reconcile(
+        &self,
+        message: Vec<Range<D>>,
+    ) -> BoxFuture<'static, Result<Vec<Range<D>>, Error>> {
+        self.limit(self.service.reconcile(message))
+    }
+
+    /// Subscribing takes a turn like a request, but the subscription doesn't hold on to it.
+    fn subscribe_head(
+        &self,
+        subscription: Subscription<D>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, HeadUpdate<D, S>>, Error>> {
+        let closed = self.closed.clone();
+        self.limit(self.service.subscribe_head(subscription))
+            .map(|updates| Ok(updates?.take_until(closed).boxed()))
+            .boxed()
+    }
+}
+
+/// Hands out a fixed number of permits, in the order they're asked for.
+struct Permits {
+    state: Mutex<PermitsState>,
+}
+
+struct PermitsState {
+    available: usize,
+    next_waiter: u64,
+    /// The tasks waiting for a permit, first in line first.
+    waiters: BTreeMap<u64, Waker>,
+}
+
+impl Permits {
+    fn new(permits: usize) -> Self {
+        Permits {
+            state: Mutex::new(PermitsState {
+                available: permits,
+                next_waiter: 0,
+                waiters: BTreeMap::new(),
+            }),
+        }
+    }
+
+    fn acquire(self: &Arc<Self>) -> Acquire {
+        Acquire {
+            permits: self.clone(),
+            waiter: None,
+        }
+    }
+}
+
+impl PermitsState {
+    /// Lets the first in line know it may take a permit.
+    fn wake_first(&self) {
+        if self.available > 0 {
+            if let Some(waker) = self.waiters.values().next() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+struct Acquire {
+    permits: Arc<Permits>,
+    waiter: Option<u64>,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit> {
+        let permits = self.permits.clone();
+        let mut state = permits.state.lock().unwrap();
+        let first = state.waiters.keys().next().copied();
+        if state.available > 0 && first == self.waiter {
+            state.available -= 1;
+            if let Some(waiter) = self.waiter.take() {
+                state.waiters.remove(&waiter);
+            }
+            state.wake_first();
+            return Poll::Ready(Permit {
+                permits: self.permits.clone(),
+            });
+        }
+        let waiter = self.waiter.unwrap_or_else(|| {
+            state.next_waiter += 1;
+            state.next_waiter
+        });
+        state.waiters.insert(waiter, cx.waker().clone());
+        self.waiter = Some(waiter);
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter {
+            let mut state = self.permits.state.lock().unwrap();
+            state.waiters.remove(&waiter);
+            state.wake_first();
+        }
+    }
+}
+
+/// Gives its permit back when dropped.
+struct Permit {
+    permits: Arc<Permits>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.permits.state.lock().unwrap();
+        state.available += 1;
+        state.wake_first();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Sum;
+    use blake2::Blake2b;
+    use futures::executor::{block_on, LocalPool};
+    use futures::stream;
+    use futures::task::LocalSpawnExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Reads {
+        running: AtomicUsize,
+        most_running: AtomicUsize,
+        finished: AtomicUsize,
+        cancelled: AtomicUsize,
+    }
+
+    /// Counts a read as running until it's dropped, as cancelled unless it finished.
+    struct Reading<'a> {
+        reads: &'a Reads,
+        finished: bool,
+    }
+
+    impl<'a> Reading<'a> {
+        fn start(reads: &'a Reads) -> Self {
+            let running = reads.running.fetch_add(1, Ordering::SeqCst) + 1;
+            reads.most_running.fetch_max(running, Ordering::SeqCst);
+            Reading {
+                reads,
+                finished: false,
+            }
+        }
+    }
+
+    impl Drop for Reading<'_> {
+        fn drop(&mut self) {
+            self.reads.running.fetch_sub(1, Ordering::SeqCst);
+            let count = match self.finished {
+                true => &self.reads.finished,
+                false => &self.reads.cancelled,
+            };
+            count.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// A stand-in for a store on slow storage, whose reads take until the test lets them finish.
+    struct SlowStore {
+        reads: Arc<Reads>,
+        done: Shared<oneshot::Receiver<()>>,
+    }
+
+    impl SlowStore {
+        /// The store, and the sender that finishes every read when it's sent on or dropped.
+        fn new() -> (Self, oneshot::Sender<()>) {
+            let (finish, done) = oneshot::channel();
+            let store = SlowStore {
+                reads: Default::default(),
+                done: done.shared(),
+            };
+            (store, finish)
+        }
+
+        fn read<R: Send + 'static>(&self, value: R) -> BoxFuture<'static, Result<R, Error>> {
+            let (reads, done) = (self.reads.clone(), self.done.clone());
+            async move {
+                let mut reading = Reading::start(&reads);
+                let _ = done.await;
+                reading.finished = true;
+                Ok(value)
+            }
+            .boxed()
+        }
+    }
+
+    impl MagmaService<Blake2b, Sum> for SlowStore {
+        fn request(
+            &self,
+            _: Request<Blake2b>,
+        ) -> BoxFuture<'static, Result<Response<Blake2b, Sum>, Error>> {
+            self.read(Response::UnknownEvent)
+        }
+
+        fn request_batch(
+            &self,
+            _: Vec<Request<Blake2b>>,
+        ) -> BoxFuture<'static, Result<Vec<Response<Blake2b, Sum>>, Error>> {
+            self.read(Vec::new())
+        }
+
+        fn request_event_at(
+            &self,
+            _: EventAtRequest<Blake2b>,
+        ) -> BoxFuture<'static, Result<Response<Blake2b, Sum>, Error>> {
+            self.read(Response::UnknownEvent)
+        }
+
+        fn request_checkpoint(
+            &self,
+            _: CheckpointRequest<Blake2b>,
+        ) -> BoxFuture<'static, Result<CheckpointResponse<Blake2b, Sum>, Error>> {
+            self.read(CheckpointResponse {
+                checkpoint: None,
+                path: Response::UnknownEvent,
+            })
+        }
+
+        fn list_heads(&self) -> BoxFuture<'static, Result<Vec<Head<Blake2b>>, Error>> {
+            self.read(Vec::new())
+        }
+
+        fn reconcile(
+            &self,
+            _: Vec<Range<Blake2b>>,
+        ) -> BoxFuture<'static, Result<Vec<Range<Blake2b>>, Error>> {
+            self.read(Vec::new())
+        }
+
+        fn subscribe_head(
+            &self,
+            _: Subscription<Blake2b>,
+        ) -> BoxFuture<'static, Result<BoxStream<'static, HeadUpdate<Blake2b, Sum>>, Error>>
+        {
+            future::ok(stream::pending().boxed()).boxed()
+        }
+    }
+
+    type Listed = Arc<Mutex<Vec<Result<Vec<Head<Blake2b>>, Error>>>>;
+
+    /// Runs `count` requests to list the heads on `pool`, collecting what they return.
+    fn list_heads(connection: &Connection<SlowStore>, pool: &LocalPool, count: usize) -> Listed {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..count {
+            let results = results.clone();
+            let listed = connection.list_heads();
+            pool.spawner()
+                .spawn_local(async move {
+                    let heads = listed.await;
+                    results.lock().unwrap().push(heads);
+                })
+                .unwrap();
+        }
+        results
+    }
+
+    #[test]
+    fn runs_at_most_the_limit_of_requests_at_once() {
+        let (store, finish) = SlowStore::new();
+        let reads = store.reads.clone();
+        let connection = Connection::new(store, 2);
+        let mut pool = LocalPool::new();
+        let results = list_heads(&connection, &pool, 5);
+
+        pool.run_until_stalled();
+        assert_eq!(reads.running.load(Ordering::SeqCst), 2);
+
+        finish.send(()).unwrap();
+        pool.run();
+        assert_eq!(reads.finished.load(Ordering::SeqCst), 5);
+        assert_eq!(reads.most_running.load(Ordering::SeqCst), 2);
+        assert!(results.lock().unwrap().iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn disconnecting_cancels_running_and_waiting_requests() {
+        let (store, _finish) = SlowStore::new();
+        let reads = store.reads.clone();
+        let connection = Connection::new(store, 1);
+        let mut pool = LocalPool::new();
+        let results = list_heads(&connection, &pool, 3);
+        pool.run_until_stalled();
+
+        drop(connection);
+        pool.run();
+
+        assert_eq!(reads.running.load(Ordering::SeqCst), 0);
+        assert_eq!(reads.cancelled.load(Ordering::SeqCst), 1);
+        assert_eq!(reads.finished.load(Ordering::SeqCst), 0);
+        let results = results.lock().unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Err(Error::Cancelled))));
+    }
+
+    #[test]
+    fn closing_ends_subscriptions_and_later_requests() {
+        let (store, _finish) = SlowStore::new();
+        let connection = Connection::new(store, 1);
+        let subscription = Subscription {
+            root: Blake2b::digest(b"log"),
+            known: None,
+            responses: None,
+        };
+        let updates = block_on(connection.subscribe_head(subscription)).unwrap();
+
+        connection.close();
+
+        assert_eq!(block_on(updates.count()), 0);
+        assert!(matches!(
+            block_on(connection.list_heads()),
+            Err(Error::Cancelled)
+        ));
+    }
+}
@@ -0,0 +1,479 @@
+//! Serving Magma without tying it to a transport.
+//!
+//! [MagmaService] takes requests and returns responses, or a stream of updates for subscriptions,
+//! all as the types of `magma-core`. A transport decodes the request from its framing, calls the
+//! service and encodes what comes back, e.g. the JSON-RPC adapter of the `client-server` demo.
+//! [StoreService] implements it for a [Store] shared between the transports and the author, and
+//! a [Connection] bounds what one client can make it do at once.
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+
+use futures::channel::mpsc;
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{BoxStream, StreamExt};
+use magma_core::replication::checkpoint::CheckpointResponse;
+use magma_core::replication::reconciliation::{self, HeadSet, Range, Reconciliation};
+use magma_core::replication::request::{Budget, CheckpointRequest, EventAtRequest, Request};
+use magma_core::replication::response::Response;
+use magma_core::replication::subscription::{HeadUpdate, Subscription};
+use magma_core::store::{self, Head, Store};
+use magma_core::{CanonicalEncoding, Digest, Output, Semigroup};
+use snafu::{ResultExt, Snafu};
+
+mod connection;
+pub use connection::Connection;
+
+/// The most requests of a batch a [StoreService] answers. It answers the rest with
+/// [Response::RateLimited].
+pub const MAX_BATCH_REQUESTS: usize = 64;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("The service has no log with this root"))]
+    UnknownLog,
+    #[snafu(display("The reconciliation message is invalid: {}", source))]
+    InvalidMessage { source: reconciliation::Error },
+    #[snafu(display("The connection closed before the request was answered"))]
+    Cancelled,
+}
+
+/// The requests a Magma server answers.
+///
+/// The futures are boxed and own what they need, so the trait can be used as a trait object and
+/// transports can spawn the futures on any thread. The work happens when they're polled, so
+/// dropping one before then cancels the request.
+pub trait MagmaService<D: Digest, S: Semigroup + CanonicalEncoding> {
+    /// Answers a request for the path between two events of a log.
+    fn request(&self, request: Request<D>) -> BoxFuture<'static, Result<Response<D, S>, Error>>;
+
+    /// Answers several requests, possibly for different logs, at once. The responses are in the
+    /// order of the requests.
+    fn request_batch(
+        &self,
+        requests: Vec<Request<D>>,
+    ) -> BoxFuture<'static, Result<Vec<Response<D, S>>, Error>>;
+
+    /// Answers a request for the event at a sequence number.
+    fn request_event_at(
+        &self,
+        request: EventAtRequest<D>,
+    ) -> BoxFuture<'static, Result<Response<D, S>, Error>>;
+
+    /// Answers a request for the latest checkpoint below an event, with the path up from it.
+    fn request_checkpoint(
+        &self,
+        request: CheckpointRequest<D>,
+    ) -> BoxFuture<'static, Result<CheckpointResponse<D, S>, Error>>;
+
+    /// The head of every log the service has.
+    fn list_heads(&self) -> BoxFuture<'static, Result<Vec<Head<D>>, Error>>;
+
+    /// Answers a message of a reconciliation over the heads of every log, see [Reconciliation].
+    fn reconcile(&self, message: Vec<Range<D>>)
+        -> BoxFuture<'static, Result<Vec<Range<D>>, Error>>;
+
+    /// Streams an update whenever the log gets a new head, starting with the next one. Dropping
+    /// the stream ends the subscription.
+    fn subscribe_head(
+        &self,
+        subscription: Subscription<D>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, HeadUpdate<D, S>>, Error>>;
+}
+
+struct Subscriber<D: Digest, S: Semigroup + CanonicalEncoding> {
+    subscription: Subscription<D>,
+    sender: mpsc::UnboundedSender<HeadUpdate<D, S>>,
+}
+
+/// Serves a [Store], and pushes updates to subscribers as the author writes to it.
+///
+/// Clones share the store and the subscribers, so one can be handed to each transport.
+pub struct StoreService<D: Digest, S: Semigroup + CanonicalEncoding> {
+    store: Arc<RwLock<Store<D, S>>>,
+    subscribers: Arc<Mutex<Vec<Subscriber<D, S>>>>,
+    /// What answering one request may read, see [Store::respond_within].
+    budget: Budget,
+}
+
+// Not derived, which would require `D: Clone` and `S: Clone`.
+impl<D: Digest, S: Semigroup + CanonicalEncoding> Clone for StoreService<D, S> {
+    fn clone(&self) -> Self {
+        StoreService {
+            store: self.store.clone(),
+            subscribers: self.subscribers.clone(),
+            budget: self.budget,
+        }
+    }
+}
+
+impl<D, S> StoreService<D, S>
+where
+    D: Digest,
+    S: Semigroup + CanonicalEncoding + Clone,
+{
+    pub fn new(store: Store<D, S>) -> Self {
+        Self::with_budget(store, Budget::default())
+    }
+
+    /// Answers every request within `budget`, however much the client asks for.
+    pub fn with_budget(store: Store<D, S>, budget: Budget) -> Self {
+        StoreService {
+            store: Arc::new(RwLock::new(store)),
+            subscribers: Default::default(),
+            budget,
+        }
+    }
+
+    pub fn store(&self) -> RwLockReadGuard<'_, Store<D, S>> {
+        self.store.read().unwrap()
+    }
+
+    /// Starts a log as its author, see [Store::create].
+    pub fn create(&self, payload: S) -> Result<Output<D>, store::Error> {
+        let mut store = self.store.write().unwrap();
+        let root = store.create(payload)?.digest.clone();
+        self.notify(&store);
+        Ok(root)
+    }
+
+    /// Appends to a log as its author and notifies the subscribers following it.
+    pub fn append(&self, root: &Output<D>, payload: S) -> Result<(), store::Error> {
+        let mut store = self.store.write().unwrap();
+        store.append(root, payload)?;
+        self.notify(&store);
+        Ok(())
+    }
+
+    /// Sends the subscribers their updates, and forgets those whose stream was dropped.
+    ///
+    /// Each response is within the budget, as the store stays locked while they are built.
+    fn notify(&self, store: &Store<D, S>) {
+        self.subscribers.lock().unwrap().retain_mut(|subscriber| {
+            match store.update_within(&mut subscriber.subscription, &self.budget) {
+                Some(update) => subscriber.sender.unbounded_send(update).is_ok(),
+                None => !subscriber.sender.is_closed(),
+            }
+        });
+    }
+}
+
+impl<D, S> MagmaService<D, S> for StoreService<D, S>
+where
+    D: Digest + Send + Sync + 'static,
+    S: Semigroup + CanonicalEncoding + Clone + Send + Sync + 'static,
+{
+    fn request(&self, request: Request<D>) -> BoxFuture<'static, Result<Response<D, S>, Error>> {
+        let (store, budget) = (self.store.clone(), self.budget);
+        async move { Ok(store.read().unwrap().respond_within(&request, &budget)) }.boxed()
+    }
+
+    /// Every request of the batch is answered within the budget on its own.
+    fn request_batch(
+        &self,
+        requests: Vec<Request<D>>,
+    ) -> BoxFuture<'static, Result<Vec<Response<D, S>>, Error>> {
+        let (store, budget) = (self.store.clone(), self.budget);
+        async move {
+            let store = store.read().unwrap();
+            let responses = requests.iter().enumerate().map(|(i, request)| {
+                if i < MAX_BATCH_REQUESTS {
+                    store.respond_within(request, &budget)
+                } else {
+                    Response::RateLimited
+                }
+            });
+            Ok(responses.collect())
+        }
+        .boxed()
+    }
+
+    fn request_event_at(
+        &self,
+        request: EventAtRequest<D>,
+    ) -> BoxFuture<'static, Result<Response<D, S>, Error>> {
+        let store = self.store.clone();
+        async move { Ok(store.read().unwrap().respond_event_at(&request)) }.boxed()
+    }
+
+    fn request_checkpoint(
+        &self,
+        request: CheckpointRequest<D>,
+    ) -> BoxFuture<'static, Result<CheckpointResponse<D, S>, Error>> {
+        let (store, budget) = (self.store.clone(), self.budget);
+        async move {
+            let store = store.read().unwrap();
+            Ok(store.respond_checkpoint_within(&request, &budget))
+        }
+        .boxed()
+    }
+
+    fn list_heads(&self) -> BoxFuture<'static, Result<Vec<Head<D>>, Error>> {
+        let store = self.store.clone();
+        async move { Ok(store.read().unwrap().heads().collect()) }.boxed()
+    }
+
+    fn reconcile(
+        &self,
+        message: Vec<Range<D>>,
+    ) -> BoxFuture<'static, Result<Vec<Range<D>>, Error>> {
+        let store = self.store.clone();
+        async move {
+            let heads: HeadSet<D> = store.read().unwrap().heads().collect();
+            Reconciliation::new(&heads)
+                .respond(&message)
+                .context(InvalidMessage)
+        }
+        .boxed()
+    }
+
+    /// Registers the subscription right away, so it gets every head after the call even if the
+    /// future is polled later.
+    fn subscribe_head(
+        &self,
+        subscription: Subscription<D>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, HeadUpdate<D, S>>, Error>> {
+        let subscribed = if self.store().log(&subscription.root).is_some() {
+            let (sender, receiver) = mpsc::unbounded();
+            self.subscribers.lock().unwrap().push(Subscriber {
+                subscription,
+                sender,
+            });
+            Ok(receiver.boxed())
+        } else {
+            Err(Error::UnknownLog)
+        };
+        future::ready(subscribed).boxed()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use blake2::Blake2b;
+    use futures::executor::block_on;
+    use magma_core::replication::request::{Limits, Ordering, PathLength};
+    use magma_core::replication::response::UnvalidatedResponse;
+    use magma_core::replication::subscription::ResponseOptions;
+
+    /// Adds up bytes, encoded as a single byte.
+    #[derive(Debug, Clone, PartialEq)]
+    pub(crate) struct Sum(pub(crate) u8);
+
+    #[derive(Snafu, Debug)]
+    pub(crate) enum SumError {
+        BufferTooSmall,
+    }
+
+    impl Semigroup for Sum {
+        fn combine(&self, other: &Self) -> Self {
+            Sum(self.0.wrapping_add(other.0))
+        }
+    }
+
+    impl CanonicalEncoding for Sum {
+        type Error = SumError;
+
+        fn encode(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            snafu::ensure!(!buffer.is_empty(), BufferTooSmall);
+            buffer[0] = self.0;
+            Ok(1)
+        }
+
+        fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), Self::Error> {
+            snafu::ensure!(!buffer.is_empty(), BufferTooSmall);
+            Ok((Sum(buffer[0]), &buffer[1..]))
+        }
+
+        fn encoding_length(&self) -> usize {
+            1
+        }
+    }
+
+    type MyService = StoreService<Blake2b, Sum>;
+
+    fn service() -> (MyService, Output<Blake2b>) {
+        service_within(Budget::default())
+    }
+
+    fn service_within(budget: Budget) -> (MyService, Output<Blake2b>) {
+        let service = MyService::with_budget(Store::new(), budget);
+        let root = service.create(Sum(1)).unwrap();
+        for value in 2..=5 {
+            service.append(&root, Sum(value)).unwrap();
+        }
+        (service, root)
+    }
+
+    /// Takes any service, as a transport would.
+    fn head(service: &dyn MagmaService<Blake2b, Sum>) -> Head<Blake2b> {
+        block_on(service.list_heads()).unwrap().pop().unwrap()
+    }
+
+    #[test]
+    fn answers_requests_from_the_store() {
+        let (service, root) = service();
+        let request = Request {
+            new: head(&service).head,
+            old: Some(root),
+            ordering: Ordering::Ascending,
+            path_length: PathLength::LongestPath,
+            include_values: true,
+            limits: Limits::default(),
+        };
+
+        let response: UnvalidatedResponse<_, _> =
+            block_on(service.request(request.clone())).unwrap().into();
+        let valid = response.try_into_valid_response(request).unwrap();
+
+        assert_eq!(valid.events.len(), 4);
+    }
+
+    #[test]
+    fn answers_in_parts_within_the_budget() {
+        let budget = Budget {
+            max_events_walked: Some(2),
+            max_bytes_read: None,
+        };
+        let (service, _) = service_within(budget);
+        let mut request = Some(Request {
+            new: head(&service).head,
+            old: None,
+            ordering: Ordering::Ascending,
+            path_length: PathLength::LongestPath,
+            include_values: true,
+            limits: Limits::default(),
+        });
+
+        let mut parts = Vec::new();
+        while let Some(next) = request {
+            let response: UnvalidatedResponse<_, _> =
+                block_on(service.request(next.clone())).unwrap().into();
+            let valid = response.try_into_valid_response(next).unwrap();
+            parts.push(valid.events.len());
+            request = valid.continuation.clone();
+        }
+
+        assert_eq!(parts, vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn answers_every_request_of_a_batch() {
+        let (service, root) = service();
+        let head = head(&service).head;
+        let other = service.create(Sum(9)).unwrap();
+        let request = |new, old| Request {
+            new,
+            old,
+            ordering: Ordering::Ascending,
+            path_length: PathLength::LongestPath,
+            include_values: true,
+            limits: Limits::default(),
+        };
+        let mut requests = vec![
+            request(head, None),
+            request(head, Some(root)),
+            request(other, None),
+        ];
+        requests.resize(MAX_BATCH_REQUESTS + 1, request(root, None));
+
+        let responses = block_on(service.request_batch(requests.clone())).unwrap();
+
+        assert_eq!(responses.len(), MAX_BATCH_REQUESTS + 1);
+        let mut responses = responses.into_iter().zip(requests);
+        let lengths: Vec<_> = responses
+            .by_ref()
+            .take(3)
+            .map(|(response, request)| {
+                let response: UnvalidatedResponse<_, _> = response.into();
+                let valid = response.try_into_valid_response(request).unwrap();
+                valid.events.len()
+            })
+            .collect();
+        assert_eq!(lengths, vec![5, 4, 1]);
+        assert!(matches!(
+            responses.next_back(),
+            Some((Response::RateLimited, _))
+        ));
+    }
+
+    #[test]
+    fn subscribers_get_every_new_head() {
+        let (service, root) = service();
+        let subscription = Subscription {
+            root,
+            known: Some(head(&service).head),
+            responses: Some(ResponseOptions {
+                ordering: Ordering::Ascending,
+                path_length: PathLength::ShortestPath,
+                include_values: true,
+                limits: Limits::default(),
+            }),
+        };
+        let updates = block_on(service.subscribe_head(subscription)).unwrap();
+
+        service.append(&root, Sum(6)).unwrap();
+        service.append(&root, Sum(7)).unwrap();
+        drop(service);
+
+        let sequence_numbers: Vec<_> =
+            block_on(updates.map(|update| update.sequence_number).collect());
+        assert_eq!(sequence_numbers, vec![6, 7]);
+    }
+
+    #[test]
+    fn updates_are_answered_within_the_budget() {
+        let budget = Budget {
+            max_events_walked: Some(2),
+            max_bytes_read: None,
+        };
+        let (service, root) = service_within(budget);
+        let subscription = Subscription {
+            root,
+            known: Some(root),
+            responses: Some(ResponseOptions {
+                ordering: Ordering::Ascending,
+                path_length: PathLength::LongestPath,
+                include_values: true,
+                limits: Limits::default(),
+            }),
+        };
+        let updates = block_on(service.subscribe_head(subscription)).unwrap();
+
+        service.append(&root, Sum(6)).unwrap();
+        drop(service);
+
+        let responses: Vec<_> = block_on(updates.map(|update| update.response).collect());
+        assert!(matches!(
+            responses.as_slice(),
+            [Some(Response::Partial(pairs))] if pairs.len() == 2
+        ));
+    }
+
+    #[test]
+    fn dropped_subscriptions_are_forgotten() {
+        let (service, root) = service();
+        let subscription = Subscription {
+            root,
+            known: None,
+            responses: None,
+        };
+        drop(block_on(service.subscribe_head(subscription)).unwrap());
+
+        service.append(&root, Sum(6)).unwrap();
+
+        assert!(service.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_subscriptions_to_unknown_logs() {
+        let (service, _) = service();
+        let subscription = Subscription {
+            root: Blake2b::digest(b"unknown"),
+            known: None,
+            responses: None,
+        };
+
+        let res = block_on(service.subscribe_head(subscription));
+
+        assert!(matches!(res, Err(Error::UnknownLog)));
+    }
+}